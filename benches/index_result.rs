@@ -0,0 +1,110 @@
+//! Compares the latency of running the index result query once per search hit with running it
+//! once for all the hits of a page.
+//!
+//! Run with `cargo bench --bench index_result`.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use kos_kit_server::search::index_result_query_options;
+use kos_kit_server::sparql::with_iri_values;
+use oxigraph::io::GraphFormat;
+use oxigraph::model::GraphNameRef;
+use oxigraph::sparql::QueryResults;
+use oxigraph::store::Store;
+
+const CONCEPT_COUNT: usize = 10_000;
+const INDEX_RESULT_SPARQL: &str = include_str!("../src/index_result.sparql");
+const ITERATIONS: u32 = 20;
+
+fn concept_iri(i: usize) -> String {
+    format!("http://example.com/concept/{}", i)
+}
+
+fn load_concepts(store: &Store) {
+    let mut ntriples = String::new();
+    for i in 0..CONCEPT_COUNT {
+        let iri = concept_iri(i);
+        writeln!(
+            ntriples,
+            "<{iri}> <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <http://www.w3.org/2004/02/skos/core#Concept> ."
+        )
+        .unwrap();
+        writeln!(
+            ntriples,
+            "<{iri}> <http://www.w3.org/2004/02/skos/core#prefLabel> \"Concept {i}\"@en ."
+        )
+        .unwrap();
+        writeln!(
+            ntriples,
+            "<{iri}> <http://www.w3.org/2004/02/skos/core#prefLabel> \"Concept {i}\"@fr ."
+        )
+        .unwrap();
+    }
+    store
+        .load_graph(
+            ntriples.as_bytes(),
+            GraphFormat::NTriples,
+            GraphNameRef::DefaultGraph,
+            None,
+        )
+        .unwrap();
+}
+
+/// Runs the query and counts the triples it constructs.
+fn run_index_result_query(store: &Store, query: &str) -> usize {
+    match store
+        .query_opt(query, index_result_query_options(vec![String::from("en")]))
+        .unwrap()
+    {
+        QueryResults::Graph(triples) => triples.map(Result::unwrap).count(),
+        _ => panic!("index result query did not return a graph"),
+    }
+}
+
+/// Mean duration of `f`, after a warm-up run.
+fn time(f: impl Fn() -> usize) -> (Duration, usize) {
+    let triple_count = f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        assert_eq!(f(), triple_count);
+    }
+    (start.elapsed() / ITERATIONS, triple_count)
+}
+
+fn main() {
+    let store = Store::new().unwrap();
+    load_concepts(&store);
+
+    for limit in [10, 100] {
+        let iris = (0..limit)
+            .map(|i| concept_iri(i * (CONCEPT_COUNT / limit)))
+            .collect::<Vec<_>>();
+
+        let (per_hit_duration, per_hit_triple_count) = time(|| {
+            iris.iter()
+                .map(|iri| {
+                    run_index_result_query(
+                        &store,
+                        &with_iri_values(INDEX_RESULT_SPARQL, std::iter::once(iri.as_str())),
+                    )
+                })
+                .sum()
+        });
+        let (batched_duration, batched_triple_count) = time(|| {
+            run_index_result_query(
+                &store,
+                &with_iri_values(INDEX_RESULT_SPARQL, iris.iter().map(String::as_str)),
+            )
+        });
+        assert_eq!(per_hit_triple_count, batched_triple_count);
+
+        println!(
+            "limit={}: one query per hit {:?}, one query per page {:?} ({:.1}x faster)",
+            limit,
+            per_hit_duration,
+            batched_duration,
+            per_hit_duration.as_secs_f64() / batched_duration.as_secs_f64()
+        );
+    }
+}
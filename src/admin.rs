@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use oxhttp::model::{Request, Response, Status};
+use oxigraph::store::Store;
+
+use crate::auth::check_bearer_token;
+use crate::search_index::SearchIndexHandle;
+
+type HttpError = (Status, String);
+
+/// Starts rebuilding the search index from the store, with the index init query read again from
+/// its file. `/search` keeps using the current index until the new one is complete.
+pub fn handle_reindex_request(
+    request: &mut Request,
+    oxigraph_store: Store,
+    search_index_handle: &Arc<SearchIndexHandle>,
+    admin_token: Option<&str>,
+) -> Result<Response, HttpError> {
+    if request.method().as_ref() != "POST" {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    check_bearer_token(request, admin_token)?;
+
+    if search_index_handle.spawn_rebuild(oxigraph_store) {
+        Ok(Response::builder(Status::ACCEPTED).with_body("reindexing started"))
+    } else {
+        Err((
+            Status::CONFLICT,
+            String::from("the index is already being rebuilt"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use oxhttp::model::{HeaderName, Method};
+
+    use super::*;
+    use crate::testing;
+
+    fn post_reindex(
+        search_index_handle: &Arc<SearchIndexHandle>,
+        authorization: Option<&str>,
+    ) -> Result<Response, HttpError> {
+        let mut request = testing::request(Method::POST, "/admin/reindex");
+        if let Some(authorization) = authorization {
+            request
+                .append_header(HeaderName::AUTHORIZATION, authorization)
+                .unwrap();
+        }
+        handle_reindex_request(
+            &mut request,
+            testing::store(),
+            search_index_handle,
+            Some("secret"),
+        )
+    }
+
+    #[test]
+    fn rebuilds_the_index_with_the_admin_token() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let search_index = search_index_handle.current();
+
+        assert_eq!(
+            post_reindex(&search_index_handle, None).unwrap_err().0,
+            Status::UNAUTHORIZED
+        );
+        assert_eq!(
+            post_reindex(&search_index_handle, Some("Bearer guess"))
+                .unwrap_err()
+                .0,
+            Status::UNAUTHORIZED
+        );
+        assert_eq!(
+            post_reindex(&search_index_handle, Some("Bearer secret"))
+                .unwrap()
+                .status(),
+            Status::ACCEPTED
+        );
+
+        // The rebuilt index replaces the current one once complete
+        for _ in 0..500 {
+            if !Arc::ptr_eq(&search_index, &search_index_handle.current()) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!Arc::ptr_eq(&search_index, &search_index_handle.current()));
+        assert_eq!(
+            search_index_handle.current().reader.searcher().num_docs(),
+            search_index.reader.searcher().num_docs()
+        );
+    }
+
+    #[test]
+    fn only_accepts_posts() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let mut request = testing::request(Method::GET, "/admin/reindex");
+        assert_eq!(
+            handle_reindex_request(
+                &mut request,
+                oxigraph_store,
+                &search_index_handle,
+                Some("secret")
+            )
+            .unwrap_err()
+            .0,
+            Status::METHOD_NOT_ALLOWED
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use anyhow::bail;
+use icu_collator::{Collator, CollatorOptions};
+use icu_locid::Locale;
+use tantivy::tokenizer::{
+    AsciiFoldingFilter, Language, LowerCaser, RemoveLongFilter, SimpleTokenizer, Stemmer,
+    StopWordFilter, TextAnalyzer, Token, TokenFilter, TokenStream, Tokenizer,
+};
+use tantivy::Index;
+
+/// Name of the tokenizer for texts without a language tag or in a language without its own
+/// analyzer: lowercasing and ASCII folding, no stemming and no stop words.
+pub const DEFAULT_TOKENIZER_NAME: &str = "kos_default";
+
+/// Name of the tokenizer of the `suggest` field: the default tokenizer followed by edge n-grams.
+pub const SUGGEST_TOKENIZER_NAME: &str = "kos_suggest";
+
+/// Longest edge n-gram of the `suggest` field, in characters. Longer query terms can only match
+/// once truncated to this length.
+pub const SUGGEST_MAX_GRAM: usize = 20;
+
+/// Maps an ISO 639-1 language code to the language of Tantivy's stemmers and stop word lists.
+fn analyzer_language(language_code: &str) -> Option<Language> {
+    Some(match language_code {
+        "ar" => Language::Arabic,
+        "da" => Language::Danish,
+        "de" => Language::German,
+        "el" => Language::Greek,
+        "en" => Language::English,
+        "es" => Language::Spanish,
+        "fi" => Language::Finnish,
+        "fr" => Language::French,
+        "hu" => Language::Hungarian,
+        "it" => Language::Italian,
+        "nl" => Language::Dutch,
+        "no" => Language::Norwegian,
+        "pt" => Language::Portuguese,
+        "ro" => Language::Romanian,
+        "ru" => Language::Russian,
+        "sv" => Language::Swedish,
+        "ta" => Language::Tamil,
+        "tr" => Language::Turkish,
+        _ => return None,
+    })
+}
+
+/// The primary language subtag of a language tag (e.g., `en` for `en-GB`), lowercased.
+pub fn primary_language_subtag(language_tag: &str) -> String {
+    language_tag
+        .split('-')
+        .next()
+        .unwrap_or(language_tag)
+        .to_ascii_lowercase()
+}
+
+/// Name of the tokenizer used for texts in a language, or for texts without a language.
+pub fn tokenizer_name(language_code: Option<&str>) -> String {
+    match language_code {
+        Some(language_code) => format!("kos_{}", language_code),
+        None => String::from(DEFAULT_TOKENIZER_NAME),
+    }
+}
+
+/// Name of the index field holding the texts of a text field in a language that has its own
+/// analyzer. Texts in other languages are held by the text field itself.
+pub fn language_field_name(text_field_name: &str, language_code: &str) -> String {
+    format!("{}_{}", text_field_name, language_code)
+}
+
+/// Names of all the index fields holding the texts of a text field: the text field itself followed
+/// by one field per language code.
+pub fn language_field_names(text_field_name: &str, language_codes: &[String]) -> Vec<String> {
+    std::iter::once(String::from(text_field_name))
+        .chain(
+            language_codes
+                .iter()
+                .map(|language_code| language_field_name(text_field_name, language_code)),
+        )
+        .collect()
+}
+
+/// Collator ordering labels by the conventions of a language (e.g., `ö` after `z` in Swedish but
+/// next to `o` in German), or by the root collation when no language is given or the language has
+/// no collation of its own.
+pub fn collator(language_tag: Option<&str>) -> Collator {
+    let locale = language_tag
+        .and_then(|language_tag| language_tag.parse::<Locale>().ok())
+        .unwrap_or(Locale::UND);
+    Collator::try_new(&(&locale).into(), CollatorOptions::new())
+        .or_else(|_| Collator::try_new(&Locale::UND.into(), CollatorOptions::new()))
+        .expect("the root collation is compiled in")
+}
+
+/// Registers the default tokenizer and one tokenizer per language code on the index.
+///
+/// Tokenizers are not persisted with the index, so this must be done every time an index is
+/// created or opened, before writing to or querying it.
+pub fn register_tokenizers(index: &Index, language_codes: &[String]) -> anyhow::Result<()> {
+    index.tokenizers().register(
+        DEFAULT_TOKENIZER_NAME,
+        TextAnalyzer::builder(SimpleTokenizer::default())
+            .filter(RemoveLongFilter::limit(40))
+            .filter(LowerCaser)
+            .filter(AsciiFoldingFilter)
+            .build(),
+    );
+
+    index.tokenizers().register(
+        SUGGEST_TOKENIZER_NAME,
+        TextAnalyzer::builder(SimpleTokenizer::default())
+            .filter(RemoveLongFilter::limit(40))
+            .filter(LowerCaser)
+            .filter(AsciiFoldingFilter)
+            .filter(EdgeNgramFilter {
+                min_gram: 1,
+                max_gram: SUGGEST_MAX_GRAM,
+            })
+            .build(),
+    );
+
+    for language_code in language_codes {
+        let language = match analyzer_language(language_code) {
+            Some(language) => language,
+            None => bail!("no analyzer for language {}", language_code),
+        };
+        let mut text_analyzer_builder = TextAnalyzer::builder(SimpleTokenizer::default())
+            .filter(RemoveLongFilter::limit(40))
+            .filter(LowerCaser)
+            .dynamic();
+        // Stop words and stemmers expect the accented forms, so fold only after them
+        if let Some(stop_word_filter) = StopWordFilter::new(language) {
+            text_analyzer_builder = text_analyzer_builder.filter_dynamic(stop_word_filter);
+        }
+        index.tokenizers().register(
+            &tokenizer_name(Some(language_code)),
+            text_analyzer_builder
+                .filter_dynamic(Stemmer::new(language))
+                .filter_dynamic(AsciiFoldingFilter)
+                .build(),
+        );
+    }
+
+    Ok(())
+}
+
+/// `TokenFilter` replacing each token by its prefixes of `min_gram` to `max_gram` characters,
+/// so that the prefix of a word matches the word.
+#[derive(Clone)]
+pub struct EdgeNgramFilter {
+    pub min_gram: usize,
+    pub max_gram: usize,
+}
+
+impl TokenFilter for EdgeNgramFilter {
+    type Tokenizer<T: Tokenizer> = EdgeNgramFilterWrapper<T>;
+
+    fn transform<T: Tokenizer>(self, tokenizer: T) -> EdgeNgramFilterWrapper<T> {
+        EdgeNgramFilterWrapper {
+            min_gram: self.min_gram,
+            max_gram: self.max_gram,
+            inner: tokenizer,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EdgeNgramFilterWrapper<T: Tokenizer> {
+    min_gram: usize,
+    max_gram: usize,
+    inner: T,
+}
+
+impl<T: Tokenizer> Tokenizer for EdgeNgramFilterWrapper<T> {
+    type TokenStream<'a> = EdgeNgramFilterStream<T::TokenStream<'a>>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
+        EdgeNgramFilterStream {
+            min_gram: self.min_gram,
+            max_gram: self.max_gram,
+            tail: self.inner.token_stream(text),
+            word: String::new(),
+            gram_ends: Vec::new(),
+            token: Token::default(),
+        }
+    }
+}
+
+pub struct EdgeNgramFilterStream<T> {
+    min_gram: usize,
+    max_gram: usize,
+    tail: T,
+    /// The token the n-grams are taken from
+    word: String,
+    /// Byte offsets in `word` of the ends of the n-grams still to emit, the next one last
+    gram_ends: Vec<usize>,
+    token: Token,
+}
+
+impl<T: TokenStream> TokenStream for EdgeNgramFilterStream<T> {
+    fn advance(&mut self) -> bool {
+        loop {
+            if let Some(gram_end) = self.gram_ends.pop() {
+                self.token.text.clear();
+                self.token.text.push_str(&self.word[..gram_end]);
+                return true;
+            }
+            if !self.tail.advance() {
+                return false;
+            }
+            // N-grams keep the offsets and position of the token they are taken from
+            self.token = self.tail.token().clone();
+            self.word.clear();
+            self.word.push_str(&self.token.text);
+            self.gram_ends = self
+                .word
+                .char_indices()
+                .map(|(index, c)| index + c.len_utf8())
+                .skip(self.min_gram.saturating_sub(1))
+                .take(self.max_gram.saturating_sub(self.min_gram) + 1)
+                .collect();
+            self.gram_ends.reverse();
+        }
+    }
+
+    fn token(&self) -> &Token {
+        &self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::schema::Schema;
+
+    use super::*;
+
+    /// The tokens of a text analyzed by a registered tokenizer.
+    fn tokens(index: &Index, tokenizer_name: &str, text: &str) -> Vec<String> {
+        let mut text_analyzer = index.tokenizers().get(tokenizer_name).unwrap();
+        let mut tokens = Vec::new();
+        text_analyzer
+            .token_stream(text)
+            .process(&mut |token| tokens.push(token.text.clone()));
+        tokens
+    }
+
+    fn index_with_tokenizers(language_codes: &[&str]) -> anyhow::Result<Index> {
+        let index = Index::create_in_ram(Schema::builder().build());
+        register_tokenizers(
+            &index,
+            &language_codes
+                .iter()
+                .map(|language_code| String::from(*language_code))
+                .collect::<Vec<_>>(),
+        )?;
+        Ok(index)
+    }
+
+    #[test]
+    fn language_analyzers_remove_stop_words_stem_and_fold() {
+        let index = index_with_tokenizers(&["en", "fr"]).unwrap();
+        assert_eq!(
+            tokens(&index, &tokenizer_name(Some("en")), "The running Cats"),
+            ["run", "cat"]
+        );
+        assert_eq!(
+            tokens(&index, &tokenizer_name(Some("fr")), "Les éléphants"),
+            ["eleph"]
+        );
+    }
+
+    #[test]
+    fn default_analyzer_only_lowercases_and_folds() {
+        let index = index_with_tokenizers(&[]).unwrap();
+        assert_eq!(
+            tokens(&index, DEFAULT_TOKENIZER_NAME, "The running Éléphants"),
+            ["the", "running", "elephants"]
+        );
+    }
+
+    #[test]
+    fn languages_without_analyzer_are_rejected() {
+        assert!(index_with_tokenizers(&["xx"]).is_err());
+    }
+
+    #[test]
+    fn language_field_names_start_with_the_text_field() {
+        assert_eq!(primary_language_subtag("en-GB"), "en");
+        assert_eq!(
+            language_field_names("prefLabel", &[String::from("en"), String::from("fr")]),
+            ["prefLabel", "prefLabel_en", "prefLabel_fr"]
+        );
+    }
+
+    #[test]
+    fn edge_ngrams_are_the_prefixes_of_each_word() {
+        let mut text_analyzer = TextAnalyzer::builder(SimpleTokenizer::default())
+            .filter(EdgeNgramFilter {
+                min_gram: 2,
+                max_gram: 3,
+            })
+            .build();
+        let mut grams = Vec::new();
+        text_analyzer
+            .token_stream("été cat a")
+            .process(&mut |token| grams.push((token.text.clone(), token.position)));
+        assert_eq!(
+            grams,
+            [
+                (String::from("ét"), 0),
+                (String::from("été"), 0),
+                (String::from("ca"), 1),
+                (String::from("cat"), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn suggest_analyzer_folds_before_taking_edge_ngrams() {
+        let index = index_with_tokenizers(&[]).unwrap();
+        assert_eq!(
+            tokens(&index, SUGGEST_TOKENIZER_NAME, "Éte"),
+            ["e", "et", "ete"]
+        );
+    }
+
+    #[test]
+    fn collators_follow_the_conventions_of_a_language() {
+        assert_eq!(
+            collator(Some("de")).compare("öl", "zebra"),
+            std::cmp::Ordering::Less
+        );
+        assert_eq!(
+            collator(Some("sv")).compare("öl", "zebra"),
+            std::cmp::Ordering::Greater
+        );
+        // Case and accents only break ties
+        let root_collator = collator(None);
+        assert_eq!(
+            root_collator.compare("Éthique", "etoile"),
+            std::cmp::Ordering::Less
+        );
+        assert_eq!(
+            root_collator.compare("ethique", "Éthique"),
+            std::cmp::Ordering::Less
+        );
+        // Invalid language tags get the root collation
+        assert_eq!(
+            collator(Some("not a tag")).compare("öl", "zebra"),
+            std::cmp::Ordering::Less
+        );
+    }
+}
@@ -0,0 +1,557 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Read;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use oxhttp::model::{HeaderName, Request, Response, Status};
+use serde_json::json;
+use tantivy::{
+    schema::{Field, Value},
+    DocAddress, IndexReader, Searcher, TantivyDocument,
+};
+
+use crate::search::{language_tag_matches, SearchQueryParser};
+use crate::sparql::content_negotiation;
+
+type HttpError = (Status, String);
+
+const MAX_ANNOTATE_BODY_SIZE: u64 = 0x0010_0000;
+
+const WEB_ANNOTATION_MEDIA_TYPE: &str = "application/ld+json";
+
+/// Lowercases a text char by char, with the byte offset in the text of the char each byte of the
+/// lowercased text comes from, followed by the length of the text.
+fn normalize(text: &str) -> (String, Vec<usize>) {
+    let mut normalized_text = String::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+    for (offset, c) in text.char_indices() {
+        for lowercase_c in c.to_lowercase() {
+            normalized_text.push(lowercase_c);
+            offsets.resize(normalized_text.len(), offset);
+        }
+    }
+    offsets.push(text.len());
+    (normalized_text, offsets)
+}
+
+/// A span of a text matching the labels of concepts.
+pub struct Annotation {
+    /// Byte offset of the span in the text
+    pub start: usize,
+    /// Byte offset of the end of the span in the text
+    pub end: usize,
+    pub iris: Vec<String>,
+}
+
+/// Dictionary of the texts of the index, matched case-insensitively against free text.
+pub struct Annotator {
+    automaton: AhoCorasick,
+    /// Concept IRIs and language tags of the texts of each pattern of the automaton
+    concepts_by_pattern: Vec<Vec<(String, Option<String>)>>,
+}
+
+impl Annotator {
+    /// Reads every stored text of the index, with the IRI and language of its document.
+    pub fn build(
+        tantivy_index_searcher: &Searcher,
+        text_fields: &[Field],
+    ) -> tantivy::Result<Self> {
+        let schema = tantivy_index_searcher.schema();
+        let iri_field = schema.get_field("iri")?;
+        let lang_field = schema.get_field("lang")?;
+
+        let mut concepts_by_text: BTreeMap<String, BTreeSet<(String, Option<String>)>> =
+            BTreeMap::new();
+        for (segment_ord, segment_reader) in
+            tantivy_index_searcher.segment_readers().iter().enumerate()
+        {
+            for doc_id in segment_reader.doc_ids_alive() {
+                let document: TantivyDocument =
+                    tantivy_index_searcher.doc(DocAddress::new(segment_ord as u32, doc_id))?;
+                let Some(iri) = document
+                    .get_first(iri_field)
+                    .and_then(|value| value.as_str())
+                else {
+                    continue;
+                };
+                // The full language tag comes before its prefixes
+                let lang = document
+                    .get_first(lang_field)
+                    .and_then(|value| value.as_str())
+                    .map(String::from);
+                for text_field in text_fields {
+                    // Each document holds a single text, in a single field
+                    if let Some(text) = document
+                        .get_first(*text_field)
+                        .and_then(|value| value.as_str())
+                    {
+                        let (normalized_text, _) = normalize(text.trim());
+                        if !normalized_text.is_empty() {
+                            concepts_by_text
+                                .entry(normalized_text)
+                                .or_default()
+                                .insert((String::from(iri), lang.clone()));
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        let (patterns, concepts_by_pattern): (Vec<_>, Vec<_>) = concepts_by_text
+            .into_iter()
+            .map(|(text, concepts)| (text, concepts.into_iter().collect()))
+            .unzip();
+        Ok(Self {
+            // Every match is found, so that matches at word boundaries aren't hidden by
+            // overlapping matches within words
+            automaton: AhoCorasick::builder()
+                .match_kind(MatchKind::Standard)
+                .build(patterns)
+                .map_err(|err| tantivy::TantivyError::InternalError(err.to_string()))?,
+            concepts_by_pattern,
+        })
+    }
+
+    /// Finds the spans of a text equal to a text of the index, case-insensitively, starting and
+    /// ending at word boundaries.
+    ///
+    /// Only texts in the requested languages, or without a language, match. Unless `overlapping`,
+    /// overlapping spans are resolved like regular expressions do: the leftmost span wins, then
+    /// the longest.
+    pub fn annotate(&self, text: &str, languages: &[String], overlapping: bool) -> Vec<Annotation> {
+        let (normalized_text, offsets) = normalize(text);
+        let is_word_char = |offset: usize| {
+            text[offset..]
+                .chars()
+                .next()
+                .is_some_and(char::is_alphanumeric)
+        };
+        let is_word_char_before = |offset: usize| {
+            text[..offset]
+                .chars()
+                .next_back()
+                .is_some_and(char::is_alphanumeric)
+        };
+
+        let mut annotations = Vec::new();
+        for automaton_match in self.automaton.find_overlapping_iter(&normalized_text) {
+            let (normalized_start, normalized_end) =
+                (automaton_match.start(), automaton_match.end());
+            // Spans must cover whole chars of the text, even chars whose lowercase is longer
+            if (normalized_start > 0 && offsets[normalized_start - 1] == offsets[normalized_start])
+                || offsets[normalized_end - 1] == offsets[normalized_end]
+            {
+                continue;
+            }
+            let (start, end) = (offsets[normalized_start], offsets[normalized_end]);
+            if is_word_char_before(start) && is_word_char(start)
+                || is_word_char_before(end) && is_word_char(end)
+            {
+                continue;
+            }
+
+            let mut iris = self.concepts_by_pattern[automaton_match.pattern().as_usize()]
+                .iter()
+                .filter(|(_, lang)| match lang {
+                    Some(lang) => language_tag_matches(lang, languages),
+                    None => true,
+                })
+                .map(|(iri, _)| iri.clone())
+                .collect::<Vec<_>>();
+            iris.dedup();
+            if !iris.is_empty() {
+                annotations.push(Annotation { start, end, iris });
+            }
+        }
+
+        annotations.sort_by(|left, right| {
+            left.start
+                .cmp(&right.start)
+                .then_with(|| right.end.cmp(&left.end))
+        });
+        if !overlapping {
+            let mut end = 0;
+            annotations.retain(|annotation| {
+                if annotation.start < end {
+                    return false;
+                }
+                end = annotation.end;
+                true
+            });
+        }
+        annotations
+    }
+}
+
+/// The annotator of the current state of the index, rebuilt after the index changes.
+#[derive(Default)]
+pub struct AnnotatorCache {
+    state: Arc<Mutex<AnnotatorCacheState>>,
+}
+
+#[derive(Default)]
+struct AnnotatorCacheState {
+    /// The latest annotator, with the generation of the index it was built from
+    annotator: Option<(u64, Arc<Annotator>)>,
+    /// Generation of the index an annotator is being built from in the background
+    building_generation_id: Option<u64>,
+}
+
+impl AnnotatorCacheState {
+    /// Keeps an annotator unless a later generation's annotator is already kept.
+    fn keep(&mut self, generation_id: u64, annotator: Arc<Annotator>) {
+        if self
+            .annotator
+            .as_ref()
+            .is_none_or(|(annotator_generation_id, _)| *annotator_generation_id < generation_id)
+        {
+            self.annotator = Some((generation_id, annotator));
+        }
+    }
+}
+
+impl AnnotatorCache {
+    /// The annotator of the generation of the index of a searcher.
+    ///
+    /// Annotators are built without holding the cache's lock. The first one is built by the
+    /// request needing it; later ones are built in the background, requests being answered with
+    /// the previous annotator meanwhile.
+    pub fn get(
+        &self,
+        tantivy_index_searcher: &Searcher,
+        text_fields: &[Field],
+    ) -> tantivy::Result<Arc<Annotator>> {
+        let generation_id = tantivy_index_searcher.generation().generation_id();
+        {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some((annotator_generation_id, annotator)) = &state.annotator {
+                let annotator = Arc::clone(annotator);
+                if *annotator_generation_id < generation_id
+                    && state.building_generation_id != Some(generation_id)
+                {
+                    state.building_generation_id = Some(generation_id);
+                    self.spawn_build(tantivy_index_searcher.clone(), text_fields.to_vec());
+                }
+                return Ok(annotator);
+            }
+        }
+
+        let annotator = Arc::new(Annotator::build(tantivy_index_searcher, text_fields)?);
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keep(generation_id, Arc::clone(&annotator));
+        Ok(annotator)
+    }
+
+    fn spawn_build(&self, tantivy_index_searcher: Searcher, text_fields: Vec<Field>) {
+        let state = Arc::clone(&self.state);
+        thread::spawn(move || {
+            let generation_id = tantivy_index_searcher.generation().generation_id();
+            let annotator = Annotator::build(&tantivy_index_searcher, &text_fields);
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            if state.building_generation_id == Some(generation_id) {
+                state.building_generation_id = None;
+            }
+            match annotator {
+                Ok(annotator) => state.keep(generation_id, Arc::new(annotator)),
+                Err(err) => eprintln!("error building annotation dictionary: {}", err),
+            }
+        });
+    }
+}
+
+/// Formats `/annotate` answers in: JSON spans, or a W3C Web Annotation page.
+enum AnnotationsFormat {
+    Json,
+    WebAnnotation,
+}
+
+/// Tags the concepts mentioned in the plain text body of a `POST`, matching the texts of the
+/// index.
+///
+/// Spans are answered as a JSON array of `{"start", "end", "text", "iris"}` objects, `start` and
+/// `end` counting Unicode code points, or, for `Accept: application/ld+json`, as a W3C Web
+/// Annotation page of the document named by the `source` parameter.
+///
+/// Once the index changes, texts are matched against its previous texts until the dictionary of
+/// its new texts is built.
+pub fn handle_request(
+    request: &mut Request,
+    tantivy_index_reader: &IndexReader,
+    search_query_parser: &SearchQueryParser,
+    annotator_cache: &AnnotatorCache,
+) -> Result<Response, HttpError> {
+    if request.method().as_ref() != "POST" {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    let url_query: HashMap<_, _> = request.url().query_pairs().into_owned().collect();
+    let languages = request
+        .url()
+        .query_pairs()
+        .filter(|(key, _)| key == "lang")
+        .map(|(_, value)| value.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let overlapping = (match url_query.get("overlapping") {
+        Some(overlapping_string) => overlapping_string.parse::<bool>(),
+        None => Ok(false),
+    })
+    .map_err(|err| {
+        (
+            Status::BAD_REQUEST,
+            format!("error parsing overlapping: {}", err),
+        )
+    })?;
+    let format = content_negotiation(
+        request,
+        &["application/json", WEB_ANNOTATION_MEDIA_TYPE],
+        |media_type| match media_type {
+            "application/json" => Some(AnnotationsFormat::Json),
+            WEB_ANNOTATION_MEDIA_TYPE => Some(AnnotationsFormat::WebAnnotation),
+            _ => None,
+        },
+    )?;
+    let source = match format {
+        AnnotationsFormat::Json => None,
+        AnnotationsFormat::WebAnnotation => {
+            Some(url_query.get("source").cloned().ok_or_else(|| {
+                (
+                    Status::BAD_REQUEST,
+                    String::from(
+                        "Web Annotations need the IRI of the annotated document as source",
+                    ),
+                )
+            })?)
+        }
+    };
+
+    let mut text = String::new();
+    request
+        .body_mut()
+        .take(MAX_ANNOTATE_BODY_SIZE)
+        .read_to_string(&mut text)
+        .map_err(|err| (Status::BAD_REQUEST, format!("error reading text: {}", err)))?;
+
+    let tantivy_index_searcher = tantivy_index_reader.searcher();
+    let annotator = annotator_cache
+        .get(&tantivy_index_searcher, &search_query_parser.text_fields())
+        .map_err(|err| {
+            (
+                Status::INTERNAL_SERVER_ERROR,
+                format!("error building annotation dictionary: {}", err),
+            )
+        })?;
+    let annotations = annotator.annotate(&text, &languages, overlapping);
+
+    // Byte offset of each char, to count the chars before a span
+    let char_offsets = text
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .collect::<Vec<_>>();
+    let char_index =
+        |offset: usize| char_offsets.partition_point(|char_offset| *char_offset < offset);
+
+    let (content_type, body) = match source {
+        None => (
+            "application/json",
+            serde_json::Value::Array(
+                annotations
+                    .iter()
+                    .map(|annotation| {
+                        json!({
+                            "start": char_index(annotation.start),
+                            "end": char_index(annotation.end),
+                            "text": &text[annotation.start..annotation.end],
+                            "iris": annotation.iris,
+                        })
+                    })
+                    .collect(),
+            ),
+        ),
+        Some(source) => (
+            WEB_ANNOTATION_MEDIA_TYPE,
+            json!({
+                "@context": "http://www.w3.org/ns/anno.jsonld",
+                "type": "AnnotationPage",
+                "items": annotations
+                    .iter()
+                    .map(|annotation| {
+                        json!({
+                            "type": "Annotation",
+                            "motivation": "identifying",
+                            "body": annotation.iris,
+                            "target": {
+                                "source": source,
+                                "selector": [
+                                    {
+                                        "type": "TextPositionSelector",
+                                        "start": char_index(annotation.start),
+                                        "end": char_index(annotation.end),
+                                    },
+                                    {
+                                        "type": "TextQuoteSelector",
+                                        "exact": &text[annotation.start..annotation.end],
+                                    },
+                                ],
+                            },
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            }),
+        ),
+    };
+
+    Ok(Response::builder(Status::OK)
+        .with_header(HeaderName::CONTENT_TYPE, content_type)
+        .unwrap()
+        .with_header("X-Total-Count", annotations.len().to_string())
+        .unwrap()
+        .with_body(body.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use oxhttp::model::Method;
+    use oxigraph::model::{GraphName, Literal, NamedNode, Quad};
+
+    use super::*;
+    use crate::testing::{self, EX};
+
+    fn annotator() -> Annotator {
+        let oxigraph_store = testing::store();
+        let search_index = testing::search_index_handle(&oxigraph_store).current();
+        Annotator::build(
+            &search_index.reader.searcher(),
+            &search_index.query_parser.text_fields(),
+        )
+        .unwrap()
+    }
+
+    /// The annotated spans of a text, with their IRIs.
+    fn spans<'a>(annotations: &[Annotation], text: &'a str) -> Vec<(&'a str, Vec<String>)> {
+        annotations
+            .iter()
+            .map(|annotation| {
+                (
+                    &text[annotation.start..annotation.end],
+                    annotation
+                        .iris
+                        .iter()
+                        .map(|iri| iri.trim_start_matches(EX).to_owned())
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_texts_with_the_offsets_of_their_chars() {
+        let (normalized_text, offsets) = normalize("Éİx");
+        // İ lowercases to i followed by a combining dot
+        assert_eq!(normalized_text, "éi\u{307}x");
+        assert_eq!(offsets, [0, 0, 2, 2, 2, 4, 5]);
+    }
+
+    #[test]
+    fn annotates_whole_words_in_the_requested_languages() {
+        let annotator = annotator();
+        let text = "My HOUSE CAT, a catfish, is no Chat or Dogma.";
+        assert_eq!(
+            spans(
+                &annotator.annotate(text, &[String::from("en")], false),
+                text
+            ),
+            [
+                ("HOUSE CAT", vec![String::from("cat")]),
+                ("catfish", vec![String::from("catfish")]),
+            ]
+        );
+        // Texts in every language match when none is requested
+        assert_eq!(
+            spans(&annotator.annotate(text, &[], false), text)
+                .last()
+                .unwrap(),
+            &("Chat", vec![String::from("cat")])
+        );
+    }
+
+    #[test]
+    fn keeps_overlapping_spans_on_demand() {
+        let annotator = annotator();
+        let text = "house cat";
+        assert_eq!(
+            spans(&annotator.annotate(text, &[], true), text),
+            [
+                ("house cat", vec![String::from("cat")]),
+                ("cat", vec![String::from("cat")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn answers_spans_in_chars() {
+        let oxigraph_store = testing::store();
+        let search_index = testing::search_index_handle(&oxigraph_store).current();
+        let mut request = testing::request(Method::POST, "/annotate?lang=en");
+        *request.body_mut() = "Ça, Æ: cat".into();
+        let response = handle_request(
+            &mut request,
+            &search_index.reader,
+            &search_index.query_parser,
+            &search_index.annotator_cache,
+        )
+        .unwrap();
+        assert_eq!(
+            testing::body_json(response),
+            json!([{
+                "start": 7,
+                "end": 10,
+                "text": "cat",
+                "iris": [format!("{}cat", EX)],
+            }])
+        );
+    }
+
+    #[test]
+    fn rebuilds_annotators_in_the_background_after_index_changes() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let search_index = search_index_handle.current();
+        let text_fields = search_index.query_parser.text_fields();
+        let annotate = || {
+            search_index
+                .annotator_cache
+                .get(&search_index.reader.searcher(), &text_fields)
+                .unwrap()
+                .annotate("a tiger", &[], false)
+                .len()
+        };
+        assert_eq!(annotate(), 0);
+
+        let inserted_quad = Quad::new(
+            NamedNode::new_unchecked(format!("{}lion", EX)),
+            NamedNode::new_unchecked("http://www.w3.org/2000/01/rdf-schema#label"),
+            Literal::from("Tiger"),
+            GraphName::DefaultGraph,
+        );
+        oxigraph_store.insert(&inserted_quad).unwrap();
+        search_index_handle
+            .reindex(&oxigraph_store, &[inserted_quad])
+            .unwrap();
+        for _ in 0..500 {
+            if annotate() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(annotate(), 1);
+    }
+}
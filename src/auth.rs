@@ -0,0 +1,91 @@
+use oxhttp::model::{HeaderName, Request, Status};
+
+type HttpError = (Status, String);
+
+/// Checks the `Authorization: Bearer` token of a request against the token configured for the
+/// endpoint.
+///
+/// Endpoints without a configured token are disabled rather than open.
+pub fn check_bearer_token(request: &Request, token: Option<&str>) -> Result<(), HttpError> {
+    let token = token.ok_or_else(|| {
+        (
+            Status::FORBIDDEN,
+            format!(
+                "{} is disabled because the server has no admin token",
+                request.url().path()
+            ),
+        )
+    })?;
+    let request_token = request
+        .header(&HeaderName::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .ok_or_else(|| {
+            (
+                Status::UNAUTHORIZED,
+                String::from("missing Authorization: Bearer token"),
+            )
+        })?;
+    if constant_time_eq(request_token.trim().as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err((Status::UNAUTHORIZED, String::from("invalid token")))
+    }
+}
+
+/// Compares without stopping at the first difference, so that response times don't reveal how
+/// much of a guessed token is right.
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |difference, (left_byte, right_byte)| {
+                difference | (left_byte ^ right_byte)
+            })
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use oxhttp::model::Method;
+
+    use super::*;
+    use crate::testing;
+
+    fn check(authorization: Option<&str>, token: Option<&str>) -> Result<(), Status> {
+        let mut request = testing::request(Method::POST, "/update");
+        if let Some(authorization) = authorization {
+            request
+                .append_header(HeaderName::AUTHORIZATION, authorization)
+                .unwrap();
+        }
+        check_bearer_token(&request, token).map_err(|(status, _)| status)
+    }
+
+    #[test]
+    fn compares_whole_tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn checks_bearer_tokens() {
+        assert_eq!(check(Some("Bearer secret"), Some("secret")), Ok(()));
+        assert_eq!(check(Some("Bearer  secret"), Some("secret")), Ok(()));
+        assert_eq!(
+            check(Some("Bearer guess"), Some("secret")),
+            Err(Status::UNAUTHORIZED)
+        );
+        assert_eq!(
+            check(Some("Basic secret"), Some("secret")),
+            Err(Status::UNAUTHORIZED)
+        );
+        assert_eq!(check(None, Some("secret")), Err(Status::UNAUTHORIZED));
+        // Without a configured token, every token is refused
+        assert_eq!(check(Some("Bearer secret"), None), Err(Status::FORBIDDEN));
+    }
+}
@@ -0,0 +1,424 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use tantivy::{
+    collector::{Collector, SegmentCollector},
+    columnar::StrColumn,
+    DocAddress, DocId, Score, SegmentOrdinal, SegmentReader, TantivyError,
+};
+
+/// A concept matched by a query: the IRI shared by one or more label documents,
+/// scored by its best-matching label.
+#[derive(Clone, Debug)]
+pub struct IriHit {
+    pub iri: String,
+    pub score: Score,
+    /// Address of the best-matching label document
+    pub doc_address: DocAddress,
+}
+
+/// Collects every matching document and groups them by the `iri` fast field,
+/// so that counting and paging work on concepts rather than on labels.
+///
+/// The fruit is sorted by descending score, ties broken by IRI.
+pub struct IriCollector {
+    iri_field_name: String,
+    language_preference: Option<LanguagePreference>,
+}
+
+struct LanguagePreference {
+    lang_field_name: String,
+    languages: Vec<String>,
+}
+
+impl IriCollector {
+    pub fn new(iri_field_name: &str) -> Self {
+        Self {
+            iri_field_name: String::from(iri_field_name),
+            language_preference: None,
+        }
+    }
+
+    /// Only collect documents whose `lang_field_name` fast field has one of `languages`, or that
+    /// have no language at all.
+    ///
+    /// `languages` are in order of preference: a concept is represented by its best-matching
+    /// document in the first language it matched in, falling back to untagged documents last.
+    pub fn with_language_preference(
+        mut self,
+        lang_field_name: &str,
+        languages: Vec<String>,
+    ) -> Self {
+        self.language_preference = if languages.is_empty() {
+            None
+        } else {
+            Some(LanguagePreference {
+                lang_field_name: String::from(lang_field_name),
+                languages,
+            })
+        };
+        self
+    }
+}
+
+impl Collector for IriCollector {
+    type Fruit = Vec<IriHit>;
+
+    type Child = IriSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentOrdinal,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<IriSegmentCollector> {
+        let iri_column = segment
+            .fast_fields()
+            .str(&self.iri_field_name)?
+            .ok_or_else(|| {
+                TantivyError::SchemaError(format!(
+                    "field {} is not a fast text field",
+                    self.iri_field_name
+                ))
+            })?;
+
+        let segment_language_preference = match &self.language_preference {
+            Some(language_preference) => {
+                // A segment without any language-tagged document has no lang column
+                let lang_column = segment
+                    .fast_fields()
+                    .str(&language_preference.lang_field_name)?;
+                let mut preferred_lang_ords =
+                    Vec::with_capacity(language_preference.languages.len());
+                if let Some(lang_column) = &lang_column {
+                    for language in &language_preference.languages {
+                        preferred_lang_ords.push(lang_column.dictionary().term_ord(language)?);
+                    }
+                }
+                Some(SegmentLanguagePreference {
+                    lang_column,
+                    preferred_lang_ords,
+                    untagged_rank: language_preference.languages.len(),
+                })
+            }
+            None => None,
+        };
+
+        Ok(IriSegmentCollector {
+            best_by_ord: HashMap::new(),
+            iri_column,
+            language_preference: segment_language_preference,
+            segment_ord: segment_local_id,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<Vec<(usize, IriHit)>>,
+    ) -> tantivy::Result<Vec<IriHit>> {
+        let mut hits = best_hits(segment_fruits);
+        hits.sort_by(hit_order);
+        Ok(hits)
+    }
+}
+
+/// The best hit of each IRI across segments.
+fn best_hits(segment_fruits: Vec<Vec<(usize, IriHit)>>) -> Vec<IriHit> {
+    let mut best_by_iri: HashMap<String, (usize, IriHit)> = HashMap::new();
+    for (language_rank, hit) in segment_fruits.into_iter().flatten() {
+        let is_better = match best_by_iri.get(&hit.iri) {
+            Some((best_language_rank, best)) => {
+                language_rank < *best_language_rank
+                    || (language_rank == *best_language_rank && hit.score > best.score)
+            }
+            None => true,
+        };
+        if is_better {
+            best_by_iri.insert(hit.iri.clone(), (language_rank, hit));
+        }
+    }
+    best_by_iri.into_values().map(|(_, hit)| hit).collect()
+}
+
+/// Descending score, ties broken by IRI.
+fn hit_order(left: &IriHit, right: &IriHit) -> Ordering {
+    right
+        .score
+        .total_cmp(&left.score)
+        .then_with(|| left.iri.cmp(&right.iri))
+}
+
+/// A page of the hits of an `IriCollector`.
+pub struct IriPage {
+    /// Number of hits, on every page
+    pub count: usize,
+    pub hits: Vec<IriHit>,
+}
+
+/// Collects the first page of the hits of an `IriCollector`, in the same order: the `limit`
+/// best hits.
+///
+/// The other hits are only counted, and are not sorted.
+pub struct IriPageCollector {
+    iri_collector: IriCollector,
+    limit: usize,
+}
+
+impl IriPageCollector {
+    pub fn new(iri_collector: IriCollector, limit: usize) -> Self {
+        Self {
+            iri_collector,
+            limit,
+        }
+    }
+}
+
+impl Collector for IriPageCollector {
+    type Fruit = IriPage;
+
+    type Child = IriSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentOrdinal,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<IriSegmentCollector> {
+        self.iri_collector.for_segment(segment_local_id, segment)
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Vec<(usize, IriHit)>>) -> tantivy::Result<IriPage> {
+        let mut hits = best_hits(segment_fruits);
+        let count = hits.len();
+        if hits.len() > self.limit {
+            hits.select_nth_unstable_by(self.limit, hit_order);
+            hits.truncate(self.limit);
+        }
+        hits.sort_by(hit_order);
+        Ok(IriPage { count, hits })
+    }
+}
+
+struct SegmentLanguagePreference {
+    lang_column: Option<StrColumn>,
+    /// Term ordinals of the preferred languages in this segment, in order of preference
+    preferred_lang_ords: Vec<Option<u64>>,
+    untagged_rank: usize,
+}
+
+impl SegmentLanguagePreference {
+    /// Position of the document's language in the preference order, or `None` if the document
+    /// is in a language that was not asked for.
+    fn rank(&self, doc: DocId) -> Option<usize> {
+        let lang_column = match &self.lang_column {
+            Some(lang_column) => lang_column,
+            None => return Some(self.untagged_rank),
+        };
+        let mut rank = None;
+        let mut is_tagged = false;
+        for lang_ord in lang_column.term_ords(doc) {
+            is_tagged = true;
+            if let Some(preferred_rank) = self
+                .preferred_lang_ords
+                .iter()
+                .position(|preferred_lang_ord| *preferred_lang_ord == Some(lang_ord))
+            {
+                rank = Some(rank.map_or(preferred_rank, |rank: usize| rank.min(preferred_rank)));
+            }
+        }
+        if is_tagged {
+            rank
+        } else {
+            Some(self.untagged_rank)
+        }
+    }
+}
+
+pub struct IriSegmentCollector {
+    best_by_ord: HashMap<u64, (usize, Score, DocId)>,
+    iri_column: StrColumn,
+    language_preference: Option<SegmentLanguagePreference>,
+    segment_ord: SegmentOrdinal,
+}
+
+impl SegmentCollector for IriSegmentCollector {
+    type Fruit = Vec<(usize, IriHit)>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        let language_rank = match &self.language_preference {
+            Some(language_preference) => match language_preference.rank(doc) {
+                Some(language_rank) => language_rank,
+                None => return,
+            },
+            None => 0,
+        };
+        for iri_ord in self.iri_column.term_ords(doc) {
+            let best = self
+                .best_by_ord
+                .entry(iri_ord)
+                .or_insert((language_rank, score, doc));
+            if language_rank < best.0 || (language_rank == best.0 && score > best.1) {
+                *best = (language_rank, score, doc);
+            }
+        }
+    }
+
+    fn harvest(self) -> Vec<(usize, IriHit)> {
+        let mut hits = Vec::with_capacity(self.best_by_ord.len());
+        for (iri_ord, (language_rank, score, doc)) in self.best_by_ord {
+            let mut iri = String::new();
+            // Term ordinals come from the segment's own dictionary, so the lookup can only fail
+            // on a corrupted segment.
+            if let Ok(true) = self.iri_column.ord_to_str(iri_ord, &mut iri) {
+                hits.push((
+                    language_rank,
+                    IriHit {
+                        iri,
+                        score,
+                        doc_address: DocAddress::new(self.segment_ord, doc),
+                    },
+                ));
+            }
+        }
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::query::TermQuery;
+    use tantivy::schema::{IndexRecordOption, Schema, FAST, STRING, TEXT};
+    use tantivy::{doc, Index, Term};
+
+    use super::*;
+
+    /// Searches `cat` in documents of (IRI, language tag, text), added in order to a single
+    /// segment.
+    fn search_cat<C: Collector>(
+        documents: &[(&str, Option<&str>, &str)],
+        collector: &C,
+    ) -> C::Fruit {
+        let mut schema_builder = Schema::builder();
+        let iri_field = schema_builder.add_text_field("iri", STRING | FAST);
+        let lang_field = schema_builder.add_text_field("lang", STRING | FAST);
+        let text_field = schema_builder.add_text_field("text", TEXT);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut index_writer = index.writer_with_num_threads(1, 15_000_000).unwrap();
+        for (iri, lang, text) in documents {
+            let mut document = doc!(iri_field => *iri, text_field => *text);
+            if let Some(lang) = lang {
+                document.add_text(lang_field, lang);
+            }
+            index_writer.add_document(document).unwrap();
+        }
+        index_writer.commit().unwrap();
+        index
+            .reader()
+            .unwrap()
+            .searcher()
+            .search(
+                &TermQuery::new(
+                    Term::from_field_text(text_field, "cat"),
+                    IndexRecordOption::WithFreqs,
+                ),
+                collector,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn groups_documents_by_iri_keeping_the_best_score() {
+        let iri_hits = search_cat(
+            &[
+                ("urn:a", None, "cat"),
+                ("urn:a", None, "the cat of the house"),
+                ("urn:b", None, "cat cat cat"),
+                ("urn:c", None, "dog"),
+            ],
+            &IriCollector::new("iri"),
+        );
+        assert_eq!(
+            iri_hits
+                .iter()
+                .map(|iri_hit| iri_hit.iri.as_str())
+                .collect::<Vec<_>>(),
+            ["urn:b", "urn:a"]
+        );
+        // The short label of urn:a outscores its long one
+        assert_eq!(iri_hits[1].doc_address, DocAddress::new(0, 0));
+        assert!(iri_hits[0].score > iri_hits[1].score);
+    }
+
+    #[test]
+    fn breaks_score_ties_by_iri() {
+        let iri_hits = search_cat(
+            &[("urn:b", None, "cat"), ("urn:a", None, "cat")],
+            &IriCollector::new("iri"),
+        );
+        assert_eq!(iri_hits[0].iri, "urn:a");
+        assert_eq!(iri_hits[1].iri, "urn:b");
+    }
+
+    #[test]
+    fn represents_concepts_by_their_preferred_language() {
+        let iri_hits = search_cat(
+            &[
+                // The English text scores higher, but French is preferred
+                ("urn:a", Some("en"), "cat"),
+                ("urn:a", Some("fr"), "cat le cat du chat"),
+                ("urn:b", None, "cat"),
+                ("urn:c", Some("de"), "cat"),
+            ],
+            &IriCollector::new("iri")
+                .with_language_preference("lang", vec![String::from("fr"), String::from("en")]),
+        );
+        let iri_a_hit = iri_hits
+            .iter()
+            .find(|iri_hit| iri_hit.iri == "urn:a")
+            .unwrap();
+        assert_eq!(iri_a_hit.doc_address, DocAddress::new(0, 1));
+        // Untagged documents are in every language, documents in other languages are skipped
+        assert!(iri_hits.iter().any(|iri_hit| iri_hit.iri == "urn:b"));
+        assert!(!iri_hits.iter().any(|iri_hit| iri_hit.iri == "urn:c"));
+    }
+
+    #[test]
+    fn collects_every_language_without_preference() {
+        let iri_hits = search_cat(
+            &[("urn:a", Some("de"), "cat"), ("urn:b", None, "cat")],
+            &IriCollector::new("iri").with_language_preference("lang", Vec::new()),
+        );
+        assert_eq!(iri_hits.len(), 2);
+    }
+
+    #[test]
+    fn collects_the_first_page_of_hits() {
+        let documents = [
+            ("urn:a", None, "cat"),
+            ("urn:b", None, "cat cat cat"),
+            ("urn:c", None, "cat"),
+            ("urn:d", None, "cat the cat"),
+            ("urn:e", None, "cat"),
+        ];
+        let iri_hits = search_cat(&documents, &IriCollector::new("iri"));
+        let iris = |iri_hits: &[IriHit]| {
+            iri_hits
+                .iter()
+                .map(|iri_hit| iri_hit.iri.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let first_iri_page = search_cat(
+            &documents,
+            &IriPageCollector::new(IriCollector::new("iri"), 2),
+        );
+        assert_eq!(first_iri_page.count, 5);
+        assert_eq!(iris(&first_iri_page.hits), iris(&iri_hits[..2]));
+    }
+}
@@ -0,0 +1,222 @@
+use std::fmt;
+use std::sync::Arc;
+
+use levenshtein_automata::{Distance, LevenshteinAutomatonBuilder, DFA, SINK_STATE};
+use tantivy::{
+    query::{AutomatonWeight, BooleanQuery, BoostQuery, EnableScoring, Occur, Query, Weight},
+    schema::Field,
+    Index, Score,
+};
+use tantivy_fst::Automaton;
+
+/// Levenshtein automaton of a term whose first characters must match exactly.
+///
+/// Tantivy's `FuzzyTermQuery` has no prefix length, so the exact prefix is matched byte by byte
+/// before the rest of the term is handed to the Levenshtein automaton of the term's suffix.
+struct PrefixedDfa {
+    prefix: Vec<u8>,
+    dfa: DFA,
+}
+
+impl Automaton for PrefixedDfa {
+    /// Number of bytes of the prefix matched so far and state of the suffix automaton, or `None`
+    /// once the prefix failed to match
+    type State = Option<(usize, u32)>;
+
+    fn start(&self) -> Self::State {
+        Some((0, self.dfa.initial_state()))
+    }
+
+    fn is_match(&self, state: &Self::State) -> bool {
+        match state {
+            Some((prefix_matched, dfa_state)) => {
+                *prefix_matched == self.prefix.len()
+                    && matches!(self.dfa.distance(*dfa_state), Distance::Exact(_))
+            }
+            None => false,
+        }
+    }
+
+    fn can_match(&self, state: &Self::State) -> bool {
+        match state {
+            Some((_, dfa_state)) => *dfa_state != SINK_STATE,
+            None => false,
+        }
+    }
+
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
+        let (prefix_matched, dfa_state) = (*state)?;
+        if prefix_matched < self.prefix.len() {
+            if self.prefix[prefix_matched] == byte {
+                Some((prefix_matched + 1, dfa_state))
+            } else {
+                None
+            }
+        } else {
+            Some((prefix_matched, self.dfa.transition(dfa_state, byte)))
+        }
+    }
+}
+
+/// Matches the documents with a term of a field within an edit distance of a text, every match
+/// scoring the same.
+#[derive(Clone)]
+struct PrefixedFuzzyTermQuery {
+    field: Field,
+    text: String,
+    automaton: Arc<PrefixedDfa>,
+}
+
+impl fmt::Debug for PrefixedFuzzyTermQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrefixedFuzzyTermQuery")
+            .field("field", &self.field)
+            .field("text", &self.text)
+            .finish()
+    }
+}
+
+impl Query for PrefixedFuzzyTermQuery {
+    fn weight(&self, _enable_scoring: EnableScoring<'_>) -> tantivy::Result<Box<dyn Weight>> {
+        Ok(Box::new(AutomatonWeight::<PrefixedDfa>::new(
+            self.field,
+            self.automaton.clone(),
+        )))
+    }
+}
+
+/// Turns the words of a search into fuzzy term queries on the same fields as the `QueryParser`.
+///
+/// Each word is analyzed with the tokenizer of each field, so that e.g. a misspelled English
+/// word is stemmed before being compared to the stemmed terms of an English field.
+pub struct FuzzyQueryParser {
+    automaton_builder: LevenshteinAutomatonBuilder,
+    field_boosts: Vec<(Field, Score)>,
+    index: Index,
+    prefix_length: usize,
+}
+
+impl FuzzyQueryParser {
+    /// `distance` is the maximum number of edits (0, 1 or 2), a transposition counting as one,
+    /// and `prefix_length` the number of leading characters of a word that must match exactly.
+    pub fn for_index(
+        index: &Index,
+        fields: Vec<Field>,
+        distance: u8,
+        prefix_length: usize,
+    ) -> Self {
+        Self {
+            automaton_builder: LevenshteinAutomatonBuilder::new(distance, true),
+            field_boosts: fields.into_iter().map(|field| (field, 1.0)).collect(),
+            index: index.clone(),
+            prefix_length,
+        }
+    }
+
+    pub fn fields(&self) -> Vec<Field> {
+        self.field_boosts.iter().map(|(field, _)| *field).collect()
+    }
+
+    pub fn set_field_boost(&mut self, field: Field, boost: Score) {
+        for (boosted_field, field_boost) in &mut self.field_boosts {
+            if *boosted_field == field {
+                *field_boost = boost;
+            }
+        }
+    }
+
+    /// Matches documents with a term close to any word of `text` in any field.
+    ///
+    /// Each matching term scores its field's boost, and scores are scaled so that the query
+    /// scores at most 1.
+    pub fn parse_query(&self, text: &str) -> tantivy::Result<Box<dyn Query>> {
+        let mut field_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+        let mut max_score: Score = 0.0;
+        for (field, boost) in &self.field_boosts {
+            let mut term_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+            let mut text_analyzer = self.index.tokenizer_for_field(*field)?;
+            let mut token_stream = text_analyzer.token_stream(text);
+            while token_stream.advance() {
+                let word = &token_stream.token().text;
+                let prefix_end = word
+                    .char_indices()
+                    .nth(self.prefix_length)
+                    .map_or(word.len(), |(index, _)| index);
+                term_queries.push((
+                    Occur::Should,
+                    Box::new(PrefixedFuzzyTermQuery {
+                        field: *field,
+                        text: word.clone(),
+                        automaton: Arc::new(PrefixedDfa {
+                            prefix: word.as_bytes()[..prefix_end].to_vec(),
+                            dfa: self.automaton_builder.build_dfa(&word[prefix_end..]),
+                        }),
+                    }),
+                ));
+            }
+            if !term_queries.is_empty() {
+                max_score += boost * term_queries.len() as Score;
+                field_queries.push((
+                    Occur::Should,
+                    Box::new(BoostQuery::new(
+                        Box::new(BooleanQuery::new(term_queries)),
+                        *boost,
+                    )),
+                ));
+            }
+        }
+        let query = Box::new(BooleanQuery::new(field_queries));
+        if max_score > 1.0 {
+            Ok(Box::new(BoostQuery::new(query, 1.0 / max_score)))
+        } else {
+            Ok(query)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the automaton of a word matches a term.
+    fn matches(word: &str, distance: u8, prefix_length: usize, term: &str) -> bool {
+        let prefix_end = word
+            .char_indices()
+            .nth(prefix_length)
+            .map_or(word.len(), |(index, _)| index);
+        let automaton = PrefixedDfa {
+            prefix: word.as_bytes()[..prefix_end].to_vec(),
+            dfa: LevenshteinAutomatonBuilder::new(distance, true).build_dfa(&word[prefix_end..]),
+        };
+        let mut state = automaton.start();
+        for byte in term.bytes() {
+            if !automaton.can_match(&state) {
+                return false;
+            }
+            state = automaton.accept(&state, byte);
+        }
+        automaton.is_match(&state)
+    }
+
+    #[test]
+    fn matches_terms_within_the_distance() {
+        assert!(matches("cat", 1, 0, "cat"));
+        assert!(matches("cat", 1, 0, "bat"));
+        assert!(matches("cat", 1, 0, "cart"));
+        assert!(matches("cat", 1, 0, "act"));
+        assert!(!matches("cat", 1, 0, "dog"));
+        assert!(!matches("cat", 0, 0, "bat"));
+        assert!(matches("cat", 2, 0, "bar"));
+    }
+
+    #[test]
+    fn matches_the_prefix_exactly() {
+        assert!(matches("cat", 1, 1, "cut"));
+        assert!(!matches("cat", 1, 1, "bat"));
+        assert!(!matches("cat", 1, 2, "cut"));
+        // A prefix longer than the word is the whole word
+        assert!(matches("cat", 1, 5, "cat"));
+        assert!(!matches("cat", 1, 5, "cart"));
+        assert!(matches("été", 1, 1, "éte"));
+    }
+}
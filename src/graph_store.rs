@@ -0,0 +1,576 @@
+// Adapted from oxigraph_server main.rs, MIT OR Apache-2.0 license
+
+use std::io::BufReader;
+use std::sync::Arc;
+
+use oxhttp::model::{HeaderName, Request, Response, Status};
+use oxigraph::io::{DatasetParser, DatasetSerializer, GraphParser, GraphSerializer};
+use oxigraph::model::{BlankNode, GraphName, GraphNameRef, NamedNode, Quad, Triple};
+use oxigraph::sparql::EvaluationError;
+use oxigraph::store::{StorageError, Store, Transaction};
+use url::form_urlencoded;
+
+use crate::auth::check_bearer_token;
+use crate::init::GraphOrDatasetFormat;
+use crate::search_index::SearchIndexHandle;
+use crate::sparql::{
+    bad_request, base_url, content_type, dataset_content_negotiation, graph_content_negotiation,
+    internal_server_error, read_ahead, unsupported_media_type, with_truncation_warning,
+    ReadForWrite, SparqlGuardrails,
+};
+
+type HttpError = (Status, String);
+
+/// The graph a request targets: the named graph of its `graph` parameter, the default graph for
+/// a `default` parameter, or `None` for the whole dataset.
+fn store_target(request: &Request) -> Result<Option<GraphName>, HttpError> {
+    let mut graph = None;
+    let mut default = false;
+    for (k, v) in request.url().query_pairs() {
+        match k.as_ref() {
+            "graph" => graph = Some(v.into_owned()),
+            "default" => default = true,
+            _ => (),
+        }
+    }
+    match (graph, default) {
+        (Some(_), true) => Err(bad_request(
+            "Both graph and default parameters should not be set at the same time",
+        )),
+        (Some(graph), false) => Ok(Some(NamedNode::new(graph).map_err(bad_request)?.into())),
+        (None, true) => Ok(Some(GraphName::DefaultGraph)),
+        (None, false) => Ok(None),
+    }
+}
+
+fn contains_graph(oxigraph_store: &Store, graph_name: &GraphName) -> Result<bool, HttpError> {
+    match graph_name {
+        GraphName::NamedNode(graph_name) => oxigraph_store
+            .contains_named_graph(graph_name)
+            .map_err(internal_server_error),
+        // The default graph always exists
+        _ => Ok(true),
+    }
+}
+
+fn graph_not_found(graph_name: &GraphName) -> HttpError {
+    (
+        Status::NOT_FOUND,
+        format!("The graph {graph_name} does not exist"),
+    )
+}
+
+/// The quads of an RDF body: the triples of a graph body in `graph_name`, or the quads of a
+/// dataset body, if the body may be a dataset.
+fn read_quads(
+    request: &mut Request,
+    graph_name: &GraphName,
+    dataset_allowed: bool,
+) -> Result<Vec<Quad>, HttpError> {
+    let content_type = content_type(request).ok_or_else(|| bad_request("No Content-Type given"))?;
+    let format = GraphOrDatasetFormat::from_media_type(&content_type)
+        .ok_or_else(|| unsupported_media_type(&content_type))?;
+    // Relative IRIs of a named graph resolve against the graph IRI
+    let base_iri = match graph_name {
+        GraphName::NamedNode(graph_name) => graph_name.as_str().to_owned(),
+        _ => base_url(request),
+    };
+    let reader = BufReader::new(request.body_mut());
+    match format {
+        GraphOrDatasetFormat::Graph(format) => GraphParser::from_format(format)
+            .with_base_iri(base_iri)
+            .map_err(internal_server_error)?
+            .read_triples(reader)
+            .map_err(bad_request)?
+            .map(|triple| Ok(triple.map_err(bad_request)?.in_graph(graph_name.clone())))
+            .collect(),
+        GraphOrDatasetFormat::Dataset(format) if dataset_allowed => {
+            DatasetParser::from_format(format)
+                .with_base_iri(base_iri)
+                .map_err(internal_server_error)?
+                .read_quads(reader)
+                .map_err(bad_request)?
+                .map(|quad| quad.map_err(bad_request))
+                .collect()
+        }
+        GraphOrDatasetFormat::Dataset(_) => Err(unsupported_media_type(&content_type)),
+    }
+}
+
+fn graph_quads(
+    transaction: &Transaction<'_>,
+    graph_name: GraphNameRef<'_>,
+) -> Result<Vec<Quad>, StorageError> {
+    transaction
+        .quads_for_pattern(None, None, None, Some(graph_name))
+        .collect()
+}
+
+/// Replaces a graph with quads, creating it if needed.
+///
+/// Returns whether the graph existed, and the quads it had.
+fn replace_graph(
+    oxigraph_store: &Store,
+    graph_name: &GraphName,
+    quads: &[Quad],
+) -> Result<(bool, Vec<Quad>), StorageError> {
+    oxigraph_store.transaction(|mut transaction| {
+        let existed = match graph_name {
+            GraphName::NamedNode(graph_name) => {
+                let existed = transaction.contains_named_graph(graph_name)?;
+                transaction.insert_named_graph(graph_name)?;
+                existed
+            }
+            _ => true,
+        };
+        let previous_quads = graph_quads(&transaction, graph_name.as_ref())?;
+        transaction.clear_graph(graph_name)?;
+        transaction.extend(quads)?;
+        Ok((existed, previous_quads))
+    })
+}
+
+/// Adds quads to a graph, creating it if needed.
+///
+/// Returns whether the graph existed.
+fn add_to_graph(
+    oxigraph_store: &Store,
+    graph_name: &GraphName,
+    quads: &[Quad],
+) -> Result<bool, StorageError> {
+    oxigraph_store.transaction(|mut transaction| {
+        let existed = match graph_name {
+            GraphName::NamedNode(graph_name) => {
+                let existed = transaction.contains_named_graph(graph_name)?;
+                transaction.insert_named_graph(graph_name)?;
+                existed
+            }
+            _ => true,
+        };
+        transaction.extend(quads)?;
+        Ok(existed)
+    })
+}
+
+/// Removes a graph, or empties the default graph.
+///
+/// Returns the quads it had, or `None` if it doesn't exist.
+fn remove_graph(
+    oxigraph_store: &Store,
+    graph_name: &GraphName,
+) -> Result<Option<Vec<Quad>>, StorageError> {
+    oxigraph_store.transaction(|mut transaction| {
+        let previous_quads = graph_quads(&transaction, graph_name.as_ref())?;
+        match graph_name {
+            GraphName::NamedNode(graph_name) => {
+                if !transaction.remove_named_graph(graph_name)? {
+                    return Ok(None);
+                }
+            }
+            _ => transaction.clear_graph(graph_name)?,
+        }
+        Ok(Some(previous_quads))
+    })
+}
+
+/// Replaces the whole dataset with quads.
+fn replace_dataset(oxigraph_store: &Store, quads: &[Quad]) -> Result<(), StorageError> {
+    oxigraph_store.transaction(|mut transaction| {
+        let named_graphs = transaction.named_graphs().collect::<Result<Vec<_>, _>>()?;
+        for named_graph in &named_graphs {
+            transaction.remove_named_graph(named_graph.as_ref())?;
+        }
+        transaction.clear_graph(GraphNameRef::DefaultGraph)?;
+        transaction.extend(quads)?;
+        Ok(())
+    })
+}
+
+/// Re-indexes the resources whose quads changed.
+fn reindex(
+    oxigraph_store: &Store,
+    search_index_handle: &Arc<SearchIndexHandle>,
+    changed_quads: &[Quad],
+) -> Result<(), HttpError> {
+    search_index_handle
+        .reindex(oxigraph_store, changed_quads)
+        .map_err(|err| {
+            internal_server_error(format!("graph stored, but re-indexing failed: {}", err))
+        })
+}
+
+/// Reads and writes the graphs of the store, per the SPARQL 1.1 Graph Store HTTP Protocol.
+///
+/// `GET`, `HEAD`, `PUT`, `POST` and `DELETE` requests target the named graph of the `graph`
+/// parameter, the default graph with a `default` parameter, or the whole dataset with neither.
+/// Reads are capped like `/sparql` responses by the `max_results` of the `guardrails`, so that
+/// the dataset can't be read past them. Writes need the admin token, and re-index the resources
+/// of the graphs they change. Writes to the whole dataset rebuild the whole index in the
+/// background instead.
+pub fn handle_request(
+    request: &mut Request,
+    oxigraph_store: Store,
+    search_index_handle: &Arc<SearchIndexHandle>,
+    admin_token: Option<&str>,
+    enable_update: bool,
+    guardrails: &SparqlGuardrails,
+) -> Result<Response, HttpError> {
+    let target = store_target(request)?;
+    match request.method().as_ref() {
+        "GET" | "HEAD" => {}
+        "PUT" | "POST" | "DELETE" => {
+            if !enable_update {
+                return Err((
+                    Status::FORBIDDEN,
+                    String::from("writes to /store are disabled (see --enable-update)"),
+                ));
+            }
+            check_bearer_token(request, admin_token)?;
+        }
+        _ => {
+            return Err((
+                Status::METHOD_NOT_ALLOWED,
+                format!("{} is not supported by this server", request.method()),
+            ))
+        }
+    }
+
+    match (request.method().as_ref(), target) {
+        ("GET", Some(graph_name)) => {
+            if !contains_graph(&oxigraph_store, &graph_name)? {
+                return Err(graph_not_found(&graph_name));
+            }
+            let format = graph_content_negotiation(request)?;
+            let (triples, truncated_to) = read_ahead(
+                oxigraph_store
+                    .quads_for_pattern(None, None, None, Some(graph_name.as_ref()))
+                    .map(|quad| quad.map(Triple::from).map_err(EvaluationError::from)),
+                guardrails,
+                None,
+            )?;
+            let response = ReadForWrite::build_response(
+                move |w| {
+                    Ok((
+                        GraphSerializer::from_format(format).triple_writer(w)?,
+                        triples,
+                    ))
+                },
+                |(mut writer, mut triples)| {
+                    Ok(if let Some(t) = triples.next() {
+                        writer.write(&t?)?;
+                        Some((writer, triples))
+                    } else {
+                        writer.finish()?;
+                        None
+                    })
+                },
+                format.media_type(),
+                None,
+            )?;
+            Ok(with_truncation_warning(response, truncated_to, "triples"))
+        }
+        ("GET", None) => {
+            let format = dataset_content_negotiation(request)?;
+            let (quads, truncated_to) = read_ahead(
+                oxigraph_store
+                    .iter()
+                    .map(|quad| quad.map_err(EvaluationError::from)),
+                guardrails,
+                None,
+            )?;
+            let response = ReadForWrite::build_response(
+                move |w| {
+                    Ok((
+                        DatasetSerializer::from_format(format).quad_writer(w)?,
+                        quads,
+                    ))
+                },
+                |(mut writer, mut quads)| {
+                    Ok(if let Some(q) = quads.next() {
+                        writer.write(&q?)?;
+                        Some((writer, quads))
+                    } else {
+                        writer.finish()?;
+                        None
+                    })
+                },
+                format.media_type(),
+                None,
+            )?;
+            Ok(with_truncation_warning(response, truncated_to, "quads"))
+        }
+        ("HEAD", Some(graph_name)) => {
+            if !contains_graph(&oxigraph_store, &graph_name)? {
+                return Err(graph_not_found(&graph_name));
+            }
+            let format = graph_content_negotiation(request)?;
+            Ok(Response::builder(Status::OK)
+                .with_header(HeaderName::CONTENT_TYPE, format.media_type())
+                .unwrap()
+                .build())
+        }
+        ("HEAD", None) => {
+            let format = dataset_content_negotiation(request)?;
+            Ok(Response::builder(Status::OK)
+                .with_header(HeaderName::CONTENT_TYPE, format.media_type())
+                .unwrap()
+                .build())
+        }
+        ("PUT", Some(graph_name)) => {
+            let quads = read_quads(request, &graph_name, false)?;
+            let (existed, mut changed_quads) = replace_graph(&oxigraph_store, &graph_name, &quads)
+                .map_err(internal_server_error)?;
+            changed_quads.extend(quads);
+            reindex(&oxigraph_store, search_index_handle, &changed_quads)?;
+            Ok(Response::builder(if existed {
+                Status::NO_CONTENT
+            } else {
+                Status::CREATED
+            })
+            .build())
+        }
+        ("PUT", None) => {
+            let quads = read_quads(request, &GraphName::DefaultGraph, true)?;
+            replace_dataset(&oxigraph_store, &quads).map_err(internal_server_error)?;
+            search_index_handle.rebuild_after_change(oxigraph_store);
+            Ok(Response::builder(Status::NO_CONTENT).build())
+        }
+        ("POST", Some(graph_name)) => {
+            let quads = read_quads(request, &graph_name, false)?;
+            let existed = add_to_graph(&oxigraph_store, &graph_name, &quads)
+                .map_err(internal_server_error)?;
+            reindex(&oxigraph_store, search_index_handle, &quads)?;
+            Ok(Response::builder(if existed {
+                Status::NO_CONTENT
+            } else {
+                Status::CREATED
+            })
+            .build())
+        }
+        ("POST", None) => {
+            let content_type =
+                content_type(request).ok_or_else(|| bad_request("No Content-Type given"))?;
+            if let Some(GraphOrDatasetFormat::Graph(_)) =
+                GraphOrDatasetFormat::from_media_type(&content_type)
+            {
+                // A graph posted to the store becomes a new graph, named by the server
+                let graph_iri = NamedNode::new(format!(
+                    "{}/{}",
+                    base_url(request),
+                    BlankNode::default().as_str()
+                ))
+                .map_err(internal_server_error)?;
+                let graph_name = GraphName::from(graph_iri.clone());
+                let quads = read_quads(request, &graph_name, false)?;
+                add_to_graph(&oxigraph_store, &graph_name, &quads)
+                    .map_err(internal_server_error)?;
+                reindex(&oxigraph_store, search_index_handle, &quads)?;
+                Ok(Response::builder(Status::CREATED)
+                    .with_header(
+                        HeaderName::LOCATION,
+                        format!(
+                            "{}?graph={}",
+                            base_url(request),
+                            form_urlencoded::byte_serialize(graph_iri.as_str().as_bytes())
+                                .collect::<String>()
+                        ),
+                    )
+                    .unwrap()
+                    .build())
+            } else {
+                let quads = read_quads(request, &GraphName::DefaultGraph, true)?;
+                oxigraph_store
+                    .transaction(|mut transaction| transaction.extend(&quads))
+                    .map_err(internal_server_error)?;
+                reindex(&oxigraph_store, search_index_handle, &quads)?;
+                Ok(Response::builder(Status::NO_CONTENT).build())
+            }
+        }
+        ("DELETE", Some(graph_name)) => {
+            let previous_quads = remove_graph(&oxigraph_store, &graph_name)
+                .map_err(internal_server_error)?
+                .ok_or_else(|| graph_not_found(&graph_name))?;
+            reindex(&oxigraph_store, search_index_handle, &previous_quads)?;
+            Ok(Response::builder(Status::NO_CONTENT).build())
+        }
+        ("DELETE", None) => {
+            replace_dataset(&oxigraph_store, &[]).map_err(internal_server_error)?;
+            search_index_handle.rebuild_after_change(oxigraph_store);
+            Ok(Response::builder(Status::NO_CONTENT).build())
+        }
+        _ => unreachable!("unsupported methods are rejected above"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use oxhttp::model::Method;
+
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::search::index_result_triples;
+    use crate::sparql::OnTooManyResults;
+    use crate::testing::{self, EX};
+
+    fn target(path_and_query: &str) -> Result<Option<GraphName>, Status> {
+        store_target(&testing::request(Method::GET, path_and_query)).map_err(|(status, _)| status)
+    }
+
+    #[test]
+    fn targets_a_graph_or_the_dataset() {
+        assert_eq!(
+            target("/store?graph=http%3A%2F%2Fexample.com%2Fg"),
+            Ok(Some(NamedNode::new_unchecked(format!("{EX}g")).into()))
+        );
+        assert_eq!(target("/store?default"), Ok(Some(GraphName::DefaultGraph)));
+        assert_eq!(target("/store"), Ok(None));
+        assert_eq!(
+            target("/store?graph=http%3A%2F%2Fexample.com%2Fg&default"),
+            Err(Status::BAD_REQUEST)
+        );
+        assert_eq!(
+            target("/store?graph=not%20an%20IRI"),
+            Err(Status::BAD_REQUEST)
+        );
+    }
+
+    fn store_request(
+        oxigraph_store: &Store,
+        search_index_handle: &Arc<SearchIndexHandle>,
+        mut request: Request,
+        authorization: Option<&str>,
+    ) -> Result<Response, HttpError> {
+        if let Some(authorization) = authorization {
+            request
+                .append_header(HeaderName::AUTHORIZATION, authorization)
+                .unwrap();
+        }
+        handle_request(
+            &mut request,
+            oxigraph_store.clone(),
+            search_index_handle,
+            Some("secret"),
+            true,
+            &SparqlGuardrails::default(),
+        )
+    }
+
+    #[test]
+    fn stores_and_indexes_named_graphs() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let graph_path_and_query = "/store?graph=http%3A%2F%2Fexample.com%2Fhorses";
+        let horse_iri = format!("{EX}horse");
+        let put_horse = || {
+            testing::request_with_body(
+                Method::PUT,
+                graph_path_and_query,
+                "text/turtle",
+                "@prefix skos: <http://www.w3.org/2004/02/skos/core#> .\n\
+                 <http://example.com/horse> a skos:Concept ; skos:prefLabel \"Horse\"@en .",
+            )
+        };
+
+        assert_eq!(
+            store_request(&oxigraph_store, &search_index_handle, put_horse(), None)
+                .unwrap_err()
+                .0,
+            Status::UNAUTHORIZED
+        );
+        assert_eq!(
+            store_request(
+                &oxigraph_store,
+                &search_index_handle,
+                put_horse(),
+                Some("Bearer secret")
+            )
+            .unwrap()
+            .status(),
+            Status::CREATED
+        );
+        // Concepts of named graphs are indexed and described
+        assert_eq!(
+            testing::count_documents(&search_index_handle, &horse_iri),
+            1
+        );
+        assert!(index_result_triples(
+            &oxigraph_store,
+            testing::INDEX_RESULT_SPARQL,
+            std::iter::once(horse_iri.as_str()),
+            Vec::new(),
+        )
+        .unwrap()
+        .next()
+        .is_some());
+
+        let mut get_request = testing::request(Method::GET, graph_path_and_query);
+        get_request
+            .append_header(HeaderName::ACCEPT, "application/n-triples")
+            .unwrap();
+        let response =
+            store_request(&oxigraph_store, &search_index_handle, get_request, None).unwrap();
+        assert!(testing::body_string(response).contains("\"Horse\"@en"));
+
+        assert_eq!(
+            store_request(
+                &oxigraph_store,
+                &search_index_handle,
+                testing::request(Method::DELETE, graph_path_and_query),
+                Some("Bearer secret")
+            )
+            .unwrap()
+            .status(),
+            Status::NO_CONTENT
+        );
+        assert_eq!(
+            testing::count_documents(&search_index_handle, &horse_iri),
+            0
+        );
+        assert_eq!(
+            store_request(
+                &oxigraph_store,
+                &search_index_handle,
+                testing::request(Method::GET, graph_path_and_query),
+                None
+            )
+            .unwrap_err()
+            .0,
+            Status::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn caps_dataset_reads_like_sparql_responses() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let get_dataset = |on_too_many_results: OnTooManyResults| {
+            let mut request = testing::request(Method::GET, "/store");
+            request
+                .append_header(HeaderName::ACCEPT, "application/n-quads")
+                .unwrap();
+            handle_request(
+                &mut request,
+                oxigraph_store.clone(),
+                &search_index_handle,
+                None,
+                false,
+                &SparqlGuardrails {
+                    max_results: Some(2),
+                    on_too_many_results,
+                    ..SparqlGuardrails::default()
+                },
+            )
+        };
+
+        let response = get_dataset(OnTooManyResults::Truncate).unwrap();
+        assert!(response
+            .header(&HeaderName::from_str("Warning").unwrap())
+            .is_some());
+        assert_eq!(testing::body_string(response).lines().count(), 2);
+        assert_eq!(
+            get_dataset(OnTooManyResults::Reject).unwrap_err().0,
+            Status::FORBIDDEN
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use oxigraph::model::{Quad, Subject, Term};
+use oxigraph::sparql::QueryResults;
+use oxigraph::store::Store;
+use tantivy::schema::{Field, Schema};
+use tantivy::{doc, Index, IndexWriter, TantivyDocument};
+
+use crate::analysis;
+use crate::init::RESERVED_VARIABLE_NAMES;
+use crate::sparql::{union_default_graph_query, with_iri_values};
+
+/// A text of a concept, to be indexed as its own document.
+#[derive(Eq, Ord, PartialEq, PartialOrd)]
+struct IndexedText {
+    field: Field,
+    text: String,
+    lang: Option<String>,
+    suggest: bool,
+}
+
+#[derive(Default)]
+struct IndexedConcept {
+    texts: BTreeSet<IndexedText>,
+    facet_values: BTreeSet<(Field, String)>,
+    notations: BTreeSet<String>,
+    sort_labels: BTreeSet<String>,
+}
+
+/// Separates the lowercased language tag of a label, empty for untagged labels, from the label
+/// in the values of the `sortLabel` field. Labels are compared when searching, with the collator
+/// of the requested language.
+pub(crate) const SORT_LABEL_LANG_SEPARATOR: char = '\u{1f}';
+
+/// Writes the results of the index init query to the Tantivy index, for the whole store or for
+/// the concepts affected by a change.
+///
+/// Tantivy allows a single `IndexWriter` per index, so the indexer keeps one open for the
+/// lifetime of the process.
+pub struct Indexer {
+    facet_field_names: Vec<String>,
+    index_init_sparql: String,
+    index_writer: Mutex<IndexWriter<TantivyDocument>>,
+    iri_field: Field,
+    lang_field: Field,
+    notation_field: Field,
+    schema: Schema,
+    sort_label_field: Field,
+    suggest_field: Field,
+    suggest_field_names: Vec<String>,
+}
+
+impl Indexer {
+    /// The values of the `facet_field_names` variables of the index init query are facets of the
+    /// concept: IRIs and literal values are added to every document of the concept, so that
+    /// filtering documents by facet filters concepts. So are the literals of the `?notation` and
+    /// `?sortLabel` variables, which hits can be sorted by.
+    ///
+    /// Texts bound to the variables in `suggest_field_names` also feed the `/suggest` endpoint;
+    /// texts of every variable do when `suggest_field_names` is empty.
+    pub fn new(
+        index: &Index,
+        index_init_sparql: String,
+        facet_field_names: Vec<String>,
+        suggest_field_names: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let schema = index.schema();
+        Ok(Self {
+            facet_field_names,
+            index_init_sparql,
+            index_writer: Mutex::new(index.writer(50_000_000)?),
+            iri_field: schema.get_field("iri")?,
+            lang_field: schema.get_field("lang")?,
+            notation_field: schema.get_field("notation")?,
+            sort_label_field: schema.get_field("sortLabel")?,
+            suggest_field: schema.get_field("suggest")?,
+            schema,
+            suggest_field_names,
+        })
+    }
+
+    /// Indexes every result of the index init query, for an empty index.
+    pub fn index_all(&self, oxigraph_store: &Store) -> anyhow::Result<()> {
+        eprintln!("building Tantivy index");
+
+        let concepts_by_iri = self.query_concepts(oxigraph_store, &self.index_init_sparql)?;
+        let mut index_writer = self.lock_index_writer()?;
+        self.add_documents(&index_writer, concepts_by_iri)?;
+        index_writer.commit()?;
+
+        eprintln!("built Tantivy index");
+
+        Ok(())
+    }
+
+    /// Replaces the documents of the concepts affected by changed quads, inserted or deleted, with
+    /// the results of the index init query restricted to those concepts.
+    ///
+    /// Must be called once the changes are in the store. Returns the number of concepts
+    /// re-indexed; concepts the index init query no longer returns are removed from the index.
+    pub fn reindex(&self, oxigraph_store: &Store, changed_quads: &[Quad]) -> anyhow::Result<usize> {
+        let iris = affected_iris(oxigraph_store, changed_quads)?;
+        if iris.is_empty() {
+            return Ok(0);
+        }
+
+        let concepts_by_iri = self.query_concepts(
+            oxigraph_store,
+            &with_iri_values(&self.index_init_sparql, iris.iter().map(String::as_str)),
+        )?;
+        let mut index_writer = self.lock_index_writer()?;
+        for iri in &iris {
+            index_writer.delete_term(tantivy::Term::from_field_text(self.iri_field, iri));
+        }
+        self.add_documents(&index_writer, concepts_by_iri)?;
+        index_writer.commit()?;
+
+        Ok(iris.len())
+    }
+
+    fn lock_index_writer(
+        &self,
+    ) -> anyhow::Result<std::sync::MutexGuard<'_, IndexWriter<TantivyDocument>>> {
+        self.index_writer
+            .lock()
+            .map_err(|_| anyhow!("a previous indexing failed while writing to the index"))
+    }
+
+    /// Runs an index init query over the union of the graphs of the store and groups its rows
+    /// by IRI, so that a concept's texts are written together and a text bound by several
+    /// properties (e.g., rdfs:label and skos:prefLabel) is only indexed once per field.
+    fn query_concepts(
+        &self,
+        oxigraph_store: &Store,
+        index_init_sparql: &str,
+    ) -> anyhow::Result<BTreeMap<String, IndexedConcept>> {
+        let mut concepts_by_iri: BTreeMap<String, IndexedConcept> = BTreeMap::new();
+        if let QueryResults::Solutions(solutions) =
+            oxigraph_store.query(union_default_graph_query(index_init_sparql)?)?
+        {
+            let facet_variables = solutions
+                .variables()
+                .iter()
+                .filter(|variable| {
+                    self.facet_field_names
+                        .iter()
+                        .any(|name| name == variable.as_str())
+                })
+                .map(|variable| {
+                    Ok::<_, anyhow::Error>((
+                        variable.clone(),
+                        self.schema.get_field(variable.as_str())?,
+                    ))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let text_variables = solutions
+                .variables()
+                .iter()
+                .filter(|variable| {
+                    !RESERVED_VARIABLE_NAMES.contains(&variable.as_str())
+                        && !self
+                            .facet_field_names
+                            .iter()
+                            .any(|name| name == variable.as_str())
+                })
+                .map(|variable| {
+                    Ok::<_, anyhow::Error>((
+                        variable.clone(),
+                        self.schema.get_field(variable.as_str())?,
+                        self.suggest_field_names.is_empty()
+                            || self
+                                .suggest_field_names
+                                .iter()
+                                .any(|name| name == variable.as_str()),
+                    ))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            for solution in solutions.filter_map(|s| s.ok()) {
+                if let Some(Term::NamedNode(iri)) = solution.get("iri") {
+                    let concept = concepts_by_iri
+                        .entry(String::from(iri.as_str()))
+                        .or_default();
+                    for (facet_variable, facet_field) in &facet_variables {
+                        let facet_value = match solution.get(facet_variable) {
+                            Some(Term::NamedNode(facet_iri)) => facet_iri.as_str(),
+                            Some(Term::Literal(facet_literal)) => facet_literal.value(),
+                            _ => continue,
+                        };
+                        concept
+                            .facet_values
+                            .insert((*facet_field, String::from(facet_value)));
+                    }
+                    if let Some(Term::Literal(notation_literal)) = solution.get("notation") {
+                        concept
+                            .notations
+                            .insert(String::from(notation_literal.value()));
+                    }
+                    if let Some(Term::Literal(sort_label_literal)) = solution.get("sortLabel") {
+                        concept.sort_labels.insert(format!(
+                            "{}{}{}",
+                            sort_label_literal
+                                .language()
+                                .unwrap_or_default()
+                                .to_ascii_lowercase(),
+                            SORT_LABEL_LANG_SEPARATOR,
+                            sort_label_literal.value().trim()
+                        ));
+                    }
+                    for (text_variable, text_field, suggest) in &text_variables {
+                        if let Some(Term::Literal(text_literal)) = solution.get(text_variable) {
+                            // Texts in a language with its own analyzer go to that language's
+                            // field
+                            let text_field = text_literal
+                                .language()
+                                .and_then(|language_tag| {
+                                    self.schema
+                                        .get_field(&analysis::language_field_name(
+                                            text_variable.as_str(),
+                                            &analysis::primary_language_subtag(language_tag),
+                                        ))
+                                        .ok()
+                                })
+                                .unwrap_or(*text_field);
+                            concept.texts.insert(IndexedText {
+                                field: text_field,
+                                text: String::from(text_literal.value()),
+                                lang: text_literal.language().map(str::to_ascii_lowercase),
+                                suggest: *suggest,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        Ok(concepts_by_iri)
+    }
+
+    /// Adds one document per (field, text), so that a concept is ranked by its best-matching text.
+    fn add_documents(
+        &self,
+        index_writer: &IndexWriter<TantivyDocument>,
+        concepts_by_iri: BTreeMap<String, IndexedConcept>,
+    ) -> anyhow::Result<()> {
+        for (iri, concept) in concepts_by_iri {
+            for indexed_text in concept.texts {
+                let mut document = doc!(self.iri_field => iri.clone());
+                if indexed_text.suggest {
+                    document.add_text(self.suggest_field, &indexed_text.text);
+                }
+                document.add_text(indexed_text.field, indexed_text.text);
+                if let Some(lang) = indexed_text.lang {
+                    for lang_range in language_tag_prefixes(&lang) {
+                        document.add_text(self.lang_field, lang_range);
+                    }
+                }
+                for (facet_field, facet_value) in &concept.facet_values {
+                    document.add_text(*facet_field, facet_value);
+                }
+                for notation in &concept.notations {
+                    document.add_text(self.notation_field, notation);
+                }
+                for sort_label in &concept.sort_labels {
+                    document.add_text(self.sort_label_field, sort_label);
+                }
+                index_writer.add_document(document)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// IRIs of the concepts whose index documents may change with changed quads: the IRI subjects of
+/// the quads, and the IRI subjects of the quads pointing to them (e.g., the concept of a changed
+/// SKOS-XL label).
+///
+/// This covers index init queries that read the texts of a concept at most one hop away from it.
+pub fn affected_iris(
+    oxigraph_store: &Store,
+    changed_quads: &[Quad],
+) -> anyhow::Result<BTreeSet<String>> {
+    let mut iris = BTreeSet::new();
+    for changed_quad in changed_quads {
+        if let Subject::NamedNode(iri) = &changed_quad.subject {
+            iris.insert(String::from(iri.as_str()));
+        }
+        let changed_subject = match &changed_quad.subject {
+            Subject::NamedNode(iri) => Term::from(iri.clone()),
+            Subject::BlankNode(blank_node) => Term::from(blank_node.clone()),
+            Subject::Triple(_) => continue,
+        };
+        for quad in
+            oxigraph_store.quads_for_pattern(None, None, Some(changed_subject.as_ref()), None)
+        {
+            if let Subject::NamedNode(iri) = quad?.subject {
+                iris.insert(String::from(iri.as_str()));
+            }
+        }
+    }
+    Ok(iris)
+}
+
+/// The language tag followed by its successively shorter prefixes (e.g., `zh-hant-tw`, `zh-hant`
+/// and `zh`), so that a search for `zh` also matches texts tagged `zh-hant-tw`.
+fn language_tag_prefixes(language_tag: &str) -> impl Iterator<Item = &str> {
+    std::iter::once(language_tag).chain(
+        language_tag
+            .match_indices('-')
+            .rev()
+            .map(|(index, _)| &language_tag[..index]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use oxigraph::model::{GraphName, Literal, NamedNode};
+
+    use super::*;
+    use crate::testing::{self, EX};
+
+    fn ex(name: &str) -> NamedNode {
+        NamedNode::new_unchecked(format!("{}{}", EX, name))
+    }
+
+    #[test]
+    fn affected_iris_include_the_concepts_of_changed_labels() {
+        let oxigraph_store = testing::store();
+        let label = oxigraph_store
+            .quads_for_pattern(
+                Some(ex("lion").as_ref().into()),
+                Some(
+                    NamedNode::new_unchecked("http://www.w3.org/2008/05/skos-xl#prefLabel")
+                        .as_ref(),
+                ),
+                None,
+                None,
+            )
+            .next()
+            .unwrap()
+            .unwrap()
+            .object;
+        let Term::BlankNode(label) = label else {
+            panic!("the SKOS-XL label of ex:lion is a blank node")
+        };
+        let changed_quads = [
+            Quad::new(
+                label,
+                NamedNode::new_unchecked("http://www.w3.org/2008/05/skos-xl#literalForm"),
+                Literal::new_language_tagged_literal_unchecked("Lioness", "en"),
+                GraphName::DefaultGraph,
+            ),
+            Quad::new(
+                ex("dog"),
+                NamedNode::new_unchecked("http://www.w3.org/2004/02/skos/core#altLabel"),
+                Literal::from("Hound"),
+                GraphName::DefaultGraph,
+            ),
+        ];
+        assert_eq!(
+            affected_iris(&oxigraph_store, &changed_quads).unwrap(),
+            BTreeSet::from([format!("{}dog", EX), format!("{}lion", EX)])
+        );
+    }
+
+    #[test]
+    fn reindex_replaces_the_documents_of_affected_concepts() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let count_documents =
+            |iri: &NamedNode| testing::count_documents(&search_index_handle, iri.as_str());
+        // Chat, Cat, House cat and the notation A1
+        assert_eq!(count_documents(&ex("cat")), 4);
+
+        let inserted_quad = Quad::new(
+            ex("cat"),
+            NamedNode::new_unchecked("http://www.w3.org/2004/02/skos/core#prefLabel"),
+            Literal::new_language_tagged_literal_unchecked("Gato", "es"),
+            GraphName::DefaultGraph,
+        );
+        oxigraph_store.insert(&inserted_quad).unwrap();
+        search_index_handle
+            .reindex(&oxigraph_store, std::slice::from_ref(&inserted_quad))
+            .unwrap();
+        assert_eq!(count_documents(&ex("cat")), 5);
+
+        // Concepts the index init query no longer returns are removed
+        let deleted_quads = oxigraph_store
+            .quads_for_pattern(Some(ex("dog").as_ref().into()), None, None, None)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        for deleted_quad in &deleted_quads {
+            oxigraph_store.remove(deleted_quad).unwrap();
+        }
+        search_index_handle
+            .reindex(&oxigraph_store, &deleted_quads)
+            .unwrap();
+        assert_eq!(count_documents(&ex("dog")), 0);
+        assert_eq!(count_documents(&ex("cat")), 5);
+    }
+
+    #[test]
+    fn language_tag_prefixes_shorten_the_tag() {
+        assert_eq!(
+            language_tag_prefixes("zh-hant-tw").collect::<Vec<_>>(),
+            ["zh-hant-tw", "zh-hant", "zh"]
+        );
+        assert_eq!(language_tag_prefixes("en").collect::<Vec<_>>(), ["en"]);
+    }
+}
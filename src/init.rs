@@ -1,9 +1,8 @@
+use crate::analysis;
 use anyhow::{self, bail};
 use flate2::read::MultiGzDecoder;
 use oxigraph::io::{DatasetFormat, GraphFormat};
 use oxigraph::model::GraphNameRef;
-use oxigraph::model::Term::Literal;
-use oxigraph::model::Term::NamedNode;
 use oxigraph::sparql::QueryResults;
 use oxigraph::store::{BulkLoader, Store};
 use rayon_core::ThreadPoolBuilder;
@@ -15,10 +14,13 @@ use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::thread::available_parallelism;
 use std::time::Instant;
-use tantivy::{doc, Index, IndexWriter, TantivyDocument};
+use tantivy::schema::{
+    IndexRecordOption, Schema, TextFieldIndexing, TextOptions, FAST, STORED, STRING,
+};
 
+/// Format of RDF files under the init path and of RDF bodies sent to `/store`.
 #[derive(Copy, Clone)]
-enum GraphOrDatasetFormat {
+pub(crate) enum GraphOrDatasetFormat {
     Graph(GraphFormat),
     Dataset(DatasetFormat),
 }
@@ -37,6 +39,12 @@ impl GraphOrDatasetFormat {
             bail!("The file extension '{name}' is unknown")
         })
     }
+
+    pub(crate) fn from_media_type(media_type: &str) -> Option<Self> {
+        GraphFormat::from_media_type(media_type)
+            .map(Self::Graph)
+            .or_else(|| DatasetFormat::from_media_type(media_type).map(Self::Dataset))
+    }
 }
 
 fn bulk_load_oxigraph(
@@ -71,19 +79,21 @@ fn format_from_path<T>(
     }
 }
 
-pub fn init_oxigraph_store(init_path: PathBuf, store: &Store) -> anyhow::Result<()> {
-    let init_path_fs_metadata = match fs::metadata(init_path.clone()) {
+/// RDF files under the init path: the path itself if it is a file, or the files of the directory
+/// other than hidden files. There are none if the init path does not exist.
+pub(crate) fn init_file_paths(init_path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let init_path_fs_metadata = match fs::metadata(init_path) {
         Ok(init_path_fs_metadata) => init_path_fs_metadata,
         Err(_) => {
             eprintln!("init path {} does not exist", init_path.display());
-            return Ok(());
+            return Ok(Vec::new());
         }
     };
 
-    let file_paths = if init_path_fs_metadata.is_file() {
-        vec![init_path]
+    if init_path_fs_metadata.is_file() {
+        Ok(vec![init_path.to_path_buf()])
     } else if init_path_fs_metadata.is_dir() {
-        fs::read_dir(init_path)?
+        Ok(fs::read_dir(init_path)?
             .filter_map(|res| res.ok())
             .filter(|dir_entry| {
                 if dir_entry.file_name().as_os_str().as_bytes()[0] == b'.' {
@@ -95,12 +105,19 @@ pub fn init_oxigraph_store(init_path: PathBuf, store: &Store) -> anyhow::Result<
                     .is_ok_and(|file_type| file_type.is_file())
             })
             .map(|dir_entry| dir_entry.path())
-            .collect::<Vec<_>>()
+            .collect::<Vec<_>>())
     } else {
-        return Err(anyhow::anyhow!(
+        Err(anyhow::anyhow!(
             "init path is neither a file nor a directory"
-        ));
-    };
+        ))
+    }
+}
+
+pub fn init_oxigraph_store(init_path: PathBuf, store: &Store) -> anyhow::Result<()> {
+    let file_paths = init_file_paths(&init_path)?;
+    if file_paths.is_empty() {
+        return Ok(());
+    }
 
     eprintln!("bulk-loading Oxigraph");
 
@@ -136,10 +153,7 @@ pub fn init_oxigraph_store(init_path: PathBuf, store: &Store) -> anyhow::Result<
                         }
                     };
                     if let Err(error) = {
-                        if file_path
-                            .extension()
-                            .map_or(false, |e| e == OsStr::new("gz"))
-                        {
+                        if file_path.extension().is_some_and(|e| e == OsStr::new("gz")) {
                             bulk_load_oxigraph(
                                 &loader,
                                 BufReader::new(MultiGzDecoder::new(fp)),
@@ -171,37 +185,201 @@ pub fn init_oxigraph_store(init_path: PathBuf, store: &Store) -> anyhow::Result<
     Ok(())
 }
 
-pub fn init_tantivy_index(
-    index: &Index,
-    index_init_sparql: String,
+/// Names of the fields of the Tantivy index that are not text or facet fields: `?iri` binds the
+/// concept IRI, and the optional `?notation` and `?sortLabel` bind the values `/search` results
+/// can be sorted by. These are not full-text searched: a query making notations searchable also
+/// binds them to a text variable, as the default one does.
+pub(crate) const RESERVED_VARIABLE_NAMES: [&str; 5] =
+    ["iri", "lang", "notation", "sortLabel", "suggest"];
+
+/// Names of the text fields of the Tantivy index: one per variable projected by the index init
+/// query other than `?iri` and the facet variables.
+pub fn index_text_field_names(
+    index_init_sparql: &str,
     oxigraph_store: &Store,
-) -> anyhow::Result<()> {
-    eprintln!("building Tantivy index");
-
-    let iri_field = index.schema().get_field("iri")?;
-    let text_field = index.schema().get_field("text")?;
-
-    let mut index_writer: IndexWriter<TantivyDocument> = index.writer(50_000_000)?;
-    if let QueryResults::Solutions(solutions) = oxigraph_store.query(index_init_sparql.as_str())? {
-        for solution in solutions.filter_map(|s| s.ok()) {
-            if let Some(iri_term) = solution.get("iri") {
-                if let NamedNode(iri) = iri_term {
-                    if let Some(text_term) = solution.get("text") {
-                        if let Literal(text_literal) = text_term {
-                            index_writer.add_document(doc!(
-                                iri_field => iri.to_string(),
-                                text_field => text_literal.value()
-                            ))?;
-                            // println!("IRI: {}, text: {}", iri.to_string(), text_literal.value());
-                        }
-                    }
-                }
+    facet_field_names: &[String],
+) -> anyhow::Result<Vec<String>> {
+    if let QueryResults::Solutions(solutions) = oxigraph_store.query(index_init_sparql)? {
+        let text_field_names = solutions
+            .variables()
+            .iter()
+            .map(|variable| String::from(variable.as_str()))
+            .filter(|variable_name| {
+                !RESERVED_VARIABLE_NAMES.contains(&variable_name.as_str())
+                    && !facet_field_names.contains(variable_name)
+            })
+            .collect::<Vec<_>>();
+        if text_field_names.is_empty() {
+            bail!("index init query should project at least one text variable");
+        }
+        Ok(text_field_names)
+    } else {
+        bail!("index init query did not return solutions (is it a SELECT query?)")
+    }
+}
+
+/// Version of the schema of the Tantivy index and of the documents the indexer writes to it, to
+/// be increased whenever either changes so that indexes built by earlier versions are stale.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Builds the schema of the Tantivy index: the `iri`, `lang`, `notation`, `sortLabel` and
+/// `suggest` fields, the facet fields, and every text field in each of the languages that have
+/// their own analyzer.
+pub fn build_tantivy_index_schema(
+    text_field_names: &[String],
+    facet_field_names: &[String],
+    language_codes: &[String],
+) -> anyhow::Result<Schema> {
+    let mut schema_builder = Schema::builder();
+    // Fast so that search hits can be grouped by IRI
+    schema_builder.add_text_field("iri", STRING | STORED | FAST);
+    // Language tag of the text and its prefixes, fast so that search hits can be filtered by it
+    schema_builder.add_text_field("lang", STRING | STORED | FAST);
+    // Edge n-grams of the text, for type-ahead suggestions
+    schema_builder.add_text_field(
+        "suggest",
+        TextOptions::default()
+            .set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(analysis::SUGGEST_TOKENIZER_NAME)
+                    .set_index_option(IndexRecordOption::WithFreqs),
+            )
+            .set_stored(),
+    );
+    // Notations and language-tagged labels of the concept, fast so that search hits can be sorted
+    // by them
+    schema_builder.add_text_field("notation", STRING | STORED | FAST);
+    schema_builder.add_text_field("sortLabel", STRING | FAST);
+    // Values of the concept's facets (e.g., its types), fast so that they can be counted
+    for facet_field_name in facet_field_names {
+        if RESERVED_VARIABLE_NAMES.contains(&facet_field_name.as_str()) {
+            bail!("facet field {} is reserved", facet_field_name);
+        }
+        schema_builder.add_text_field(facet_field_name, STRING | STORED | FAST);
+    }
+    for text_field_name in text_field_names {
+        schema_builder.add_text_field(text_field_name, text_field_options(None));
+        for language_code in language_codes {
+            let language_field_name = analysis::language_field_name(text_field_name, language_code);
+            if text_field_names.contains(&language_field_name)
+                || facet_field_names.contains(&language_field_name)
+            {
+                bail!(
+                    "index init query variable ?{} clashes with the {} field of ?{}",
+                    language_field_name,
+                    language_code,
+                    text_field_name
+                );
             }
+            schema_builder.add_text_field(
+                &language_field_name,
+                text_field_options(Some(language_code)),
+            );
         }
     }
-    index_writer.commit()?;
+    Ok(schema_builder.build())
+}
+
+/// Stored so that search results can show the text that matched
+fn text_field_options(language_code: Option<&str>) -> TextOptions {
+    TextOptions::default()
+        .set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer(&analysis::tokenizer_name(language_code))
+                .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+        )
+        .set_stored()
+}
 
-    eprintln!("built Tantivy index");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
 
-    Ok(())
+    fn facet_field_names() -> Vec<String> {
+        vec![String::from("type"), String::from("scheme")]
+    }
+
+    #[test]
+    fn text_fields_are_the_unreserved_variables() {
+        let oxigraph_store = testing::store();
+        assert_eq!(
+            index_text_field_names(
+                testing::INDEX_INIT_SPARQL,
+                &oxigraph_store,
+                &facet_field_names()
+            )
+            .unwrap(),
+            ["text"]
+        );
+        assert_eq!(
+            index_text_field_names(
+                "SELECT ?iri ?prefLabel ?altLabel ?type ?notation WHERE { ?iri ?p ?prefLabel }",
+                &oxigraph_store,
+                &facet_field_names()
+            )
+            .unwrap(),
+            ["prefLabel", "altLabel"]
+        );
+    }
+
+    #[test]
+    fn index_init_query_needs_a_text_variable() {
+        let oxigraph_store = testing::store();
+        assert!(index_text_field_names(
+            "SELECT ?iri ?type WHERE { ?iri a ?type }",
+            &oxigraph_store,
+            &facet_field_names()
+        )
+        .is_err());
+        assert!(index_text_field_names(
+            "ASK { ?iri a ?type }",
+            &oxigraph_store,
+            &facet_field_names()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn schema_has_a_field_per_text_field_and_language() {
+        let schema = build_tantivy_index_schema(
+            &[String::from("prefLabel")],
+            &facet_field_names(),
+            &[String::from("en"), String::from("fr")],
+        )
+        .unwrap();
+        for field_name in [
+            "iri",
+            "lang",
+            "suggest",
+            "notation",
+            "sortLabel",
+            "type",
+            "scheme",
+            "prefLabel",
+            &analysis::language_field_name("prefLabel", "en"),
+            &analysis::language_field_name("prefLabel", "fr"),
+        ] {
+            assert!(schema.get_field(field_name).is_ok(), "{}", field_name);
+        }
+    }
+
+    #[test]
+    fn schema_rejects_clashing_fields() {
+        assert!(build_tantivy_index_schema(
+            &[String::from("text")],
+            &[String::from("notation")],
+            &[]
+        )
+        .is_err());
+        assert!(build_tantivy_index_schema(
+            &[
+                String::from("text"),
+                analysis::language_field_name("text", "en")
+            ],
+            &[],
+            &[String::from("en")]
+        )
+        .is_err());
+    }
 }
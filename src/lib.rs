@@ -1,4 +1,23 @@
+pub mod admin;
+pub mod analysis;
+pub mod annotate;
+pub mod auth;
+pub mod collector;
 pub mod cors;
+pub mod fuzzy;
+pub mod graph_store;
+pub mod indexer;
 pub mod init;
+pub mod manifest;
+pub mod reconcile;
 pub mod search;
+pub mod search_index;
+pub mod search_service;
+pub mod similar;
 pub mod sparql;
+pub mod suggest;
+#[cfg(test)]
+mod testing;
+pub mod timeout;
+pub mod update;
+pub mod vocab;
@@ -1,18 +1,30 @@
 // Adapted from oxigraph_server main.rs, MIT OR Apache-2.0 license
 
 #![allow(clippy::print_stderr, clippy::cast_precision_loss, clippy::use_debug)]
-use clap::Parser;
-use kos_kit_server::init::{init_oxigraph_store, init_tantivy_index};
-use kos_kit_server::{cors, search, sparql};
+use clap::{Parser, Subcommand};
+use kos_kit_server::init::init_oxigraph_store;
+use kos_kit_server::manifest::{must_rebuild, Manifest, OnStale};
+use kos_kit_server::search::SearchMode;
+use kos_kit_server::search_index::{
+    has_index, lock_data_directory, rebuild_index_directory, remove_index_directory,
+    SearchIndexConfig, SearchIndexHandle,
+};
+use kos_kit_server::search_service::search_service_query_options;
+use kos_kit_server::sparql::{OnTooManyResults, SparqlFeature, SparqlGuardrails};
+use kos_kit_server::timeout::QueryTimeouts;
+use kos_kit_server::vocab::kos;
+use kos_kit_server::{
+    admin, annotate, cors, graph_store, reconcile, search, similar, sparql, suggest, update,
+};
 use oxhttp::model::{HeaderName, Request, Response, Status};
 use oxhttp::Server;
 use oxigraph::store::Store;
-use std::path::PathBuf;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{fmt, fs};
-use tantivy::directory::MmapDirectory;
-use tantivy::query::QueryParser;
-use tantivy::{Index, IndexReader, ReloadPolicy};
 
 type HttpError = (Status, String);
 
@@ -22,9 +34,38 @@ const HTTP_TIMEOUT: Duration = Duration::from_secs(60);
 const YASGUI_HTML: &str = include_str!("./yasgui.html");
 
 #[derive(Parser)]
-#[command(about, version)]
+#[command(
+    about,
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 /// kos-kit server
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Rebuilds the Tantivy index into a fresh directory, then exits.
+    ///
+    /// The server uses the new index the next time it starts. Only works offline: while a server
+    /// uses the Tantivy index data directory, rebuild its index with POST /admin/reindex instead.
+    Reindex(Args),
+}
+
+#[derive(clap::Args)]
 struct Args {
+    /// Bearer token of the /admin and /update endpoints, and of writes to the /store endpoint.
+    ///
+    /// If not present, the /admin and /update endpoints and writes to /store are disabled.
+    #[arg(long)]
+    admin_token: Option<String>,
+
     /// Host and port to listen to.
     #[arg(short, long, default_value = "localhost:7878")]
     bind: String,
@@ -33,14 +74,105 @@ struct Args {
     #[arg(long)]
     cors: bool,
 
-    // Path to a .sparql file containing a query to initialize the index
+    /// Enables the /update endpoint, which applies SPARQL updates to the store, and writes to the
+    /// /store endpoint, which replaces, adds to or deletes graphs of the store. Both re-index the
+    /// resources they change.
+    ///
+    /// Writes also need the --admin-token.
+    #[arg(long)]
+    enable_update: bool,
+
+    /// Index init query variable whose values are a facet of the concept (e.g., type).
+    ///
+    /// /search filters on a facet with parameters named after it, and links to the counts of
+    /// each facet value in its results. Facets cannot be named after other /search parameters
+    /// (e.g., query or sort).
+    #[arg(long, default_values = ["type", "scheme"])]
+    index_facet_field: Vec<String>,
+
+    /// Boost applied to matches in an index field, as FIELD=BOOST (e.g., prefLabel=2).
+    ///
+    /// Index fields are named after the variables projected by the index init query, and boosts
+    /// are positive. Fields without a boost have a boost of 1.
+    #[arg(long, value_parser = parse_field_boost)]
+    index_field_boost: Vec<(String, f32)>,
+
+    /// ISO 639-1 code of a language whose texts get their own analyzer (e.g., fr).
+    ///
+    /// Analyzers lowercase, remove stop words, stem and ASCII-fold. Texts in other languages or
+    /// without a language tag are only lowercased and ASCII-folded.
+    #[arg(long, default_value = "en")]
+    index_language: Vec<String>,
+
+    /// Index init query variable whose texts feed the /suggest endpoint (e.g., prefLabel).
+    ///
+    /// If not present, the texts of every variable do.
+    #[arg(long)]
+    index_suggest_field: Vec<String>,
+
+    // Path to a .sparql file containing a query to initialize the index. Its ?notation and
+    // ?sortLabel values only sort /search results: bind them to a text variable as well to
+    // search them. Like the index result query, it runs over the union of all graphs. The file
+    // is read again when the index is rebuilt with POST /admin/reindex
     #[arg(long)]
     index_init_sparql_file_path: Option<PathBuf>,
 
-    // Path to a .sparql file containing a query for each result
+    // Path to a .sparql file containing a query for each result, run over the union of all
+    // graphs
     #[arg(long)]
     index_result_sparql_file_path: Option<PathBuf>,
 
+    /// Maximum number of solutions or triples of a /sparql response, or of triples or quads of a
+    /// GET /store response.
+    ///
+    /// If not present, responses have every result.
+    #[arg(long)]
+    sparql_max_results: Option<usize>,
+
+    /// What /sparql answers for queries with more results than --sparql-max-results: truncate
+    /// answers the results up to the maximum, with a Warning header stating it, and reject answers
+    /// an error.
+    #[arg(long, default_value = "truncate")]
+    sparql_on_too_many_results: OnTooManyResults,
+
+    /// Maximum number of triple patterns and operators (joins, unions, filters...) in the algebra
+    /// of a /sparql query.
+    #[arg(long)]
+    sparql_max_query_complexity: Option<usize>,
+
+    /// Feature /sparql queries cannot use: property-path or service.
+    #[arg(long)]
+    sparql_deny: Vec<SparqlFeature>,
+
+    /// IRI of a service /sparql queries can call even if --sparql-deny service (e.g.,
+    /// urn:kos-kit:search).
+    #[arg(long)]
+    sparql_allow_service: Vec<String>,
+
+    /// How /search matches words when the request has no mode parameter: exact or fuzzy.
+    #[arg(long, default_value = "exact")]
+    search_mode: SearchMode,
+
+    /// Maximum number of edits (0 to 2) between a word and the terms it matches in fuzzy mode.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=2))]
+    search_fuzzy_distance: u8,
+
+    /// Number of leading characters of a word that must match exactly in fuzzy mode.
+    #[arg(long, default_value_t = 0)]
+    search_fuzzy_prefix_length: usize,
+
+    /// What to do at startup when the files under the init path, the index init query or the
+    /// --index-language, --index-facet-field and --index-suggest-field arguments changed since the
+    /// persisted Oxigraph store or Tantivy index was built: rebuild, refuse or warn.
+    ///
+    /// rebuild reloads the store from the init path and rebuilds the index, refuse exits with an
+    /// error, and warn logs the change and serves the data as it is. What data directories were
+    /// built from is recorded in a .manifest.json file next to each of them; data directories
+    /// without one were built from unknown content, and are stale. A store changed through
+    /// /update or /store is never reloaded, as its changes would be lost: rebuild refuses it.
+    #[arg(long, default_value = "warn")]
+    on_stale: OnStale,
+
     /// Directory in which Oxigraph data should be persisted.
     ///
     /// If not present, store data in memory.
@@ -51,12 +183,55 @@ struct Args {
     #[arg(long, required = true)]
     oxigraph_init_path: PathBuf,
 
+    /// Time limit of /sparql and /search queries, in seconds.
+    ///
+    /// Requests can lower or raise it with a timeout parameter, up to --max-query-timeout.
+    /// Once it is past, no further results are computed or streamed, but the step of the
+    /// evaluation then running (e.g., the search itself) is not interrupted.
+    #[arg(long, default_value_t = 30.0)]
+    query_timeout: f64,
+
+    /// Maximum time limit /sparql and /search requests can set with a timeout parameter, in
+    /// seconds.
+    #[arg(long, default_value_t = 60.0)]
+    max_query_timeout: f64,
+
     /// Directory in which the Tantivy index should be persisted.
     /// If not present, use a temporary directory
+    ///
+    /// Rebuilt indexes go to subdirectories of it, the current one being named in its CURRENT
+    /// file.
     #[arg(long)]
     tantivy_index_data_directory_path: Option<PathBuf>,
 }
 
+/// Parse a field boost, a positive finite number so that boosted scores stay comparable
+fn parse_field_boost(s: &str) -> Result<(String, f32), Box<dyn Error + Send + Sync + 'static>> {
+    let (field_name, boost) = parse_key_val::<String, f32>(s)?;
+    if !boost.is_finite() || boost <= 0.0 {
+        return Err(format!(
+            "invalid boost of field {}: {} is not a positive finite number",
+            field_name, boost
+        )
+        .into());
+    }
+    Ok((field_name, boost))
+}
+
+/// Parse a single key-value pair
+fn parse_key_val<T, U>(s: &str) -> Result<(T, U), Box<dyn Error + Send + Sync + 'static>>
+where
+    T: FromStr,
+    T::Err: Error + Send + Sync + 'static,
+    U: FromStr,
+    U::Err: Error + Send + Sync + 'static,
+{
+    let pos = s
+        .find('=')
+        .ok_or_else(|| format!("invalid KEY=value: no `=` found in `{}`", s))?;
+    Ok((s[..pos].parse()?, s[pos + 1..].parse()?))
+}
+
 fn error(status: Status, message: impl fmt::Display) -> Response {
     Response::builder(status)
         .with_header(HeaderName::CONTENT_TYPE, "text/plain; charset=utf-8")
@@ -65,36 +240,28 @@ fn error(status: Status, message: impl fmt::Display) -> Response {
 }
 
 pub fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Reindex(args)) => reindex(args),
+        None => serve(cli.args),
+    }
+}
 
+/// Opens the Oxigraph store, loading the init path into it if it is empty or stale, and gathers
+/// the index settings.
+///
+/// Also returns the manifest the Tantivy index should have, if it is persisted.
+fn open(args: &Args) -> anyhow::Result<(Store, SearchIndexConfig, Option<Manifest>)> {
     let oxigraph_store =
-        if let Some(oxigraph_data_directory_path) = args.oxigraph_data_directory_path {
+        if let Some(oxigraph_data_directory_path) = &args.oxigraph_data_directory_path {
             fs::create_dir_all(oxigraph_data_directory_path.clone())?;
             Store::open(oxigraph_data_directory_path)
         } else {
             Store::new()
         }?;
 
-    use tantivy::schema::{Schema, STORED, STRING, TEXT};
-
-    let mut tantivy_index_schema_builder = Schema::builder();
-    tantivy_index_schema_builder.add_text_field("iri", STRING | STORED);
-    let tantivy_index_text_field = tantivy_index_schema_builder.add_text_field("text", TEXT);
-    let tantivy_index_schema = tantivy_index_schema_builder.build();
-
-    let tantivy_index =
-        if let Some(index_data_directory_path) = args.tantivy_index_data_directory_path {
-            fs::create_dir_all(index_data_directory_path.clone())?;
-            Index::open_or_create(
-                MmapDirectory::open(index_data_directory_path)?,
-                tantivy_index_schema,
-            )?
-        } else {
-            Index::create_in_ram(tantivy_index_schema)
-        };
-
     let index_init_sparql =
-        if let Some(index_init_sparql_file_path) = args.index_init_sparql_file_path {
+        if let Some(index_init_sparql_file_path) = &args.index_init_sparql_file_path {
             match fs::read_to_string(index_init_sparql_file_path.clone()) {
                 Ok(s) => s,
                 Err(e) => panic!(
@@ -107,6 +274,145 @@ pub fn main() -> anyhow::Result<()> {
             String::from(INDEX_INIT_SPARQL)
         };
 
+    // Only persisted data directories can be stale
+    let oxigraph_store_manifest = if args.oxigraph_data_directory_path.is_some()
+        || args.tantivy_index_data_directory_path.is_some()
+    {
+        Some(Manifest::for_oxigraph_store(&args.oxigraph_init_path)?)
+    } else {
+        None
+    };
+
+    let mut write_oxigraph_store_manifest = false;
+    if let (Some(oxigraph_data_directory_path), Some(oxigraph_store_manifest)) =
+        (&args.oxigraph_data_directory_path, &oxigraph_store_manifest)
+    {
+        // An empty store is loaded from the init path below
+        if oxigraph_store.is_empty()? {
+            write_oxigraph_store_manifest = true;
+        } else if must_rebuild(
+            "Oxigraph store",
+            oxigraph_store_manifest,
+            Manifest::read(oxigraph_data_directory_path)?.as_ref(),
+            args.on_stale,
+        )? {
+            oxigraph_store.clear()?;
+            write_oxigraph_store_manifest = true;
+        }
+    }
+
+    if oxigraph_store.is_empty()? {
+        init_oxigraph_store(args.oxigraph_init_path.clone(), &oxigraph_store)?
+    } else {
+        eprintln!("Oxigraph store is not empty, skipping init")
+    }
+
+    if let (true, Some(oxigraph_data_directory_path), Some(oxigraph_store_manifest)) = (
+        write_oxigraph_store_manifest,
+        &args.oxigraph_data_directory_path,
+        &oxigraph_store_manifest,
+    ) {
+        oxigraph_store_manifest.write(oxigraph_data_directory_path)?;
+    }
+
+    let search_index_config = SearchIndexConfig {
+        default_search_mode: args.search_mode,
+        facet_field_names: args.index_facet_field.clone(),
+        field_boosts: args.index_field_boost.clone(),
+        fuzzy_distance: args.search_fuzzy_distance,
+        fuzzy_prefix_length: args.search_fuzzy_prefix_length,
+        index_init_sparql,
+        index_init_sparql_file_path: args.index_init_sparql_file_path.clone(),
+        language_codes: args.index_language.clone(),
+        suggest_field_names: args.index_suggest_field.clone(),
+    };
+
+    let tantivy_index_manifest = match (
+        &args.tantivy_index_data_directory_path,
+        &oxigraph_store_manifest,
+    ) {
+        (Some(_), Some(oxigraph_store_manifest)) => Some(Manifest::for_tantivy_index(
+            oxigraph_store_manifest,
+            &search_index_config,
+        )),
+        _ => None,
+    };
+
+    Ok((oxigraph_store, search_index_config, tantivy_index_manifest))
+}
+
+fn reindex(args: Args) -> anyhow::Result<()> {
+    let Some(tantivy_index_data_directory_path) = args.tantivy_index_data_directory_path.clone()
+    else {
+        anyhow::bail!("reindex requires --tantivy-index-data-directory-path");
+    };
+    let _data_directory_lock =
+        lock_data_directory(&tantivy_index_data_directory_path).map_err(|err| {
+            anyhow::anyhow!(
+                "{}: reindex only works offline, use POST /admin/reindex on a running server",
+                err
+            )
+        })?;
+    let (oxigraph_store, search_index_config, tantivy_index_manifest) = open(&args)?;
+    rebuild_tantivy_index(
+        &search_index_config,
+        &oxigraph_store,
+        &tantivy_index_data_directory_path,
+    )?;
+    if let Some(tantivy_index_manifest) = tantivy_index_manifest {
+        tantivy_index_manifest.write(&tantivy_index_data_directory_path)?;
+    }
+    Ok(())
+}
+
+fn rebuild_tantivy_index(
+    search_index_config: &SearchIndexConfig,
+    oxigraph_store: &Store,
+    tantivy_index_data_directory_path: &Path,
+) -> anyhow::Result<()> {
+    let (search_index, previous_directory_path) = rebuild_index_directory(
+        search_index_config,
+        oxigraph_store,
+        tantivy_index_data_directory_path,
+    )?;
+    // Releases the new index's writer, so that the index can be opened again
+    drop(search_index);
+    remove_index_directory(tantivy_index_data_directory_path, &previous_directory_path);
+    Ok(())
+}
+
+fn serve(args: Args) -> anyhow::Result<()> {
+    // Held until the server stops
+    let _data_directory_lock = args
+        .tantivy_index_data_directory_path
+        .as_deref()
+        .map(lock_data_directory)
+        .transpose()?;
+    let (oxigraph_store, search_index_config, tantivy_index_manifest) = open(&args)?;
+
+    // The manifest of an index built when the index is opened below
+    let mut built_tantivy_index_manifest = None;
+    if let (Some(tantivy_index_data_directory_path), Some(tantivy_index_manifest)) = (
+        &args.tantivy_index_data_directory_path,
+        &tantivy_index_manifest,
+    ) {
+        if !has_index(tantivy_index_data_directory_path)? {
+            built_tantivy_index_manifest = Some(tantivy_index_manifest);
+        } else if must_rebuild(
+            "Tantivy index",
+            tantivy_index_manifest,
+            Manifest::read(tantivy_index_data_directory_path)?.as_ref(),
+            args.on_stale,
+        )? {
+            rebuild_tantivy_index(
+                &search_index_config,
+                &oxigraph_store,
+                tantivy_index_data_directory_path,
+            )?;
+            tantivy_index_manifest.write(tantivy_index_data_directory_path)?;
+        }
+    }
+
     let index_result_sparql =
         if let Some(index_result_sparql_file_path) = args.index_result_sparql_file_path {
             match fs::read_to_string(index_result_sparql_file_path.clone()) {
@@ -121,38 +427,46 @@ pub fn main() -> anyhow::Result<()> {
             String::from(INDEX_RESULT_SPARQL)
         };
 
-    if oxigraph_store.is_empty()? {
-        init_oxigraph_store(args.oxigraph_init_path, &oxigraph_store)?
-    } else {
-        eprintln!("Oxigraph store is not empty, skipping init")
-    }
-
-    let tantivy_index_reader = tantivy_index
-        .reader_builder()
-        .reload_policy(ReloadPolicy::OnCommitWithDelay)
-        .try_into()?;
-
-    {
-        if tantivy_index_reader.searcher().num_docs() == 0 {
-            init_tantivy_index(&tantivy_index, index_init_sparql, &oxigraph_store)?;
-            // tantivy_index_reader.reload()?;
-            assert!(tantivy_index_reader.searcher().num_docs() == 0);
-        } else {
-            eprintln!("Tantivy index is not empty, skipping init")
-        }
+    // Holds the index's only writer until the server stops, or until a rebuilt index replaces it
+    let search_index_handle = Arc::new(
+        SearchIndexHandle::open(
+            search_index_config,
+            &oxigraph_store,
+            args.tantivy_index_data_directory_path.clone(),
+        )?
+        .with_oxigraph_data_directory_path(args.oxigraph_data_directory_path.clone()),
+    );
+    if let (Some(tantivy_index_data_directory_path), Some(tantivy_index_manifest)) = (
+        &args.tantivy_index_data_directory_path,
+        built_tantivy_index_manifest,
+    ) {
+        tantivy_index_manifest.write(tantivy_index_data_directory_path)?;
     }
 
-    let tantivy_query_parser =
-        QueryParser::for_index(&tantivy_index, vec![tantivy_index_text_field]);
-
+    let query_timeouts = QueryTimeouts {
+        default: Duration::try_from_secs_f64(args.query_timeout)?,
+        max: Duration::try_from_secs_f64(args.max_query_timeout)?,
+    };
+    let sparql_guardrails = SparqlGuardrails {
+        max_results: args.sparql_max_results,
+        on_too_many_results: args.sparql_on_too_many_results,
+        max_query_complexity: args.sparql_max_query_complexity,
+        denied_features: args.sparql_deny,
+        allowed_services: args.sparql_allow_service,
+    };
+    let admin_token = args.admin_token;
+    let enable_update = args.enable_update;
     let mut server = if args.cors {
         Server::new(cors::middleware(move |request| {
             handle_request(
                 index_result_sparql.clone(),
                 request,
                 oxigraph_store.clone(),
-                &tantivy_index_reader,
-                &tantivy_query_parser,
+                &search_index_handle,
+                admin_token.as_deref(),
+                enable_update,
+                &query_timeouts,
+                &sparql_guardrails,
             )
             .unwrap_or_else(|(status, message)| error(status, message))
         }))
@@ -162,8 +476,11 @@ pub fn main() -> anyhow::Result<()> {
                 index_result_sparql.clone(),
                 request,
                 oxigraph_store.clone(),
-                &tantivy_index_reader,
-                &tantivy_query_parser,
+                &search_index_handle,
+                admin_token.as_deref(),
+                enable_update,
+                &query_timeouts,
+                &sparql_guardrails,
             )
             .unwrap_or_else(|(status, message)| error(status, message))
         })
@@ -175,13 +492,20 @@ pub fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn handle_request(
     index_result_sparql: String,
     request: &mut Request,
     oxigraph_store: Store,
-    tantivy_index_reader: &IndexReader,
-    tantivy_query_parser: &QueryParser,
+    search_index_handle: &Arc<SearchIndexHandle>,
+    admin_token: Option<&str>,
+    enable_update: bool,
+    query_timeouts: &QueryTimeouts,
+    sparql_guardrails: &SparqlGuardrails,
 ) -> Result<Response, HttpError> {
+    // Kept for the whole request, even if a rebuilt index replaces it meanwhile
+    let search_index = search_index_handle.current();
+    let index_facet_field_names = &search_index_handle.config().facet_field_names;
     match request.url().path() {
         "/" => {
             if request.method().as_ref() != "GET" {
@@ -191,19 +515,99 @@ pub fn handle_request(
                 ));
             }
 
-            return Ok(Response::builder(Status::OK)
+            Ok(Response::builder(Status::OK)
                 .with_header("Content-Type", String::from("text/html"))
                 .unwrap()
-                .with_body(YASGUI_HTML));
+                .with_body(YASGUI_HTML))
+        }
+        "/admin/reindex" => {
+            admin::handle_reindex_request(request, oxigraph_store, search_index_handle, admin_token)
+        }
+        "/annotate" => annotate::handle_request(
+            request,
+            &search_index.reader,
+            &search_index.query_parser,
+            &search_index.annotator_cache,
+        ),
+        "/reconcile" => reconcile::handle_request(
+            request,
+            &index_result_sparql,
+            &oxigraph_store,
+            &search_index.reader,
+            &search_index.query_parser,
+            index_facet_field_names,
+        ),
+        "/reconcile/preview" => {
+            reconcile::handle_preview_request(request, &index_result_sparql, &oxigraph_store)
         }
+        "/reconcile/suggest/entity" => {
+            reconcile::handle_suggest_entity_request(request, &search_index.reader)
+        }
+        "/reconcile/suggest/property" => reconcile::handle_suggest_property_request(
+            request,
+            &oxigraph_store,
+            &search_index.property_cache,
+            search_index_handle.store_generation(),
+        ),
+        "/reconcile/suggest/type" => reconcile::handle_suggest_type_request(
+            request,
+            &oxigraph_store,
+            &search_index.type_cache,
+            search_index_handle.store_generation(),
+        ),
         "/search" => search::handle_request(
             index_result_sparql,
             oxigraph_store,
             request,
-            tantivy_index_reader,
-            tantivy_query_parser,
+            &search_index.reader,
+            &search_index.query_parser,
+            &search_index.searcher_cache,
+            index_facet_field_names,
+            query_timeouts,
+        ),
+        "/search/facets" => search::handle_facets_request(
+            request,
+            &search_index.reader,
+            &search_index.query_parser,
+            index_facet_field_names,
+        ),
+        "/search/similar" => similar::handle_request(
+            request,
+            &oxigraph_store,
+            &search_index.reader,
+            &search_index.query_parser,
+        ),
+        "/sparql" => sparql::handle_request(
+            request,
+            oxigraph_store,
+            search_service_query_options(Arc::clone(&search_index)),
+            &[kos::SEARCH],
+            |oxigraph_store| {
+                search_index
+                    .triple_count_cache
+                    .get(oxigraph_store, search_index_handle.store_generation())
+            },
+            query_timeouts,
+            sparql_guardrails,
+        ),
+        "/update" => {
+            if !enable_update {
+                return Err((
+                    Status::FORBIDDEN,
+                    String::from("/update is disabled (see --enable-update)"),
+                ));
+            }
+            update::handle_request(request, oxigraph_store, search_index_handle, admin_token)
+        }
+        "/store" => graph_store::handle_request(
+            request,
+            oxigraph_store,
+            search_index_handle,
+            admin_token,
+            enable_update,
+            sparql_guardrails,
         ),
-        "/sparql" => sparql::handle_request(request, oxigraph_store),
+        "/suggest" => suggest::handle_request(request, &search_index.reader),
         _ => Err((
             Status::NOT_FOUND,
             format!(
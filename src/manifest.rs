@@ -0,0 +1,516 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::init::{init_file_paths, SCHEMA_VERSION};
+use crate::search_index::SearchIndexConfig;
+
+/// What to do at startup when the content a data directory was built from has changed since.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OnStale {
+    /// Reload the store from the init path, or rebuild the index from the store
+    Rebuild,
+    /// Exit with an error
+    Refuse,
+    /// Log the change and use the data directory as it is
+    Warn,
+}
+
+impl FromStr for OnStale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "rebuild" => Ok(Self::Rebuild),
+            "refuse" => Ok(Self::Refuse),
+            "warn" => Ok(Self::Warn),
+            _ => bail!("invalid stale policy {s}: expected rebuild, refuse or warn"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileFingerprint {
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// Fingerprint of the content a data directory was built from, stored as JSON next to the
+/// directory.
+///
+/// The Oxigraph store is built from the files under the init path. The Tantivy index is built
+/// from the store with the index init query, so its manifest also records the query and the
+/// settings the index schema depends on.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Manifest {
+    /// Files under the init path, by path relative to it
+    pub init_files: BTreeMap<String, FileFingerprint>,
+    pub index_init_sparql_sha256: Option<String>,
+    pub index_settings: Option<IndexSettings>,
+    /// Whether the data was changed through the server since it was built, so that rebuilding
+    /// it would discard the changes
+    pub modified: bool,
+}
+
+/// The settings of the Tantivy index that change its schema or its documents.
+///
+/// Field and language names are sorted, as their order does not matter.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IndexSettings {
+    pub schema_version: u32,
+    /// `--index-language`
+    pub language_codes: Vec<String>,
+    /// `--index-facet-field`
+    pub facet_field_names: Vec<String>,
+    /// `--index-suggest-field`
+    pub suggest_field_names: Vec<String>,
+}
+
+impl IndexSettings {
+    fn changes_since(&self, recorded: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.schema_version != recorded.schema_version {
+            changes.push(format!(
+                "index schema version changed from {} to {}",
+                recorded.schema_version, self.schema_version
+            ));
+        }
+        if self.language_codes != recorded.language_codes {
+            changes.push(String::from("index languages changed"));
+        }
+        if self.facet_field_names != recorded.facet_field_names {
+            changes.push(String::from("index facet fields changed"));
+        }
+        if self.suggest_field_names != recorded.suggest_field_names {
+            changes.push(String::from("index suggest fields changed"));
+        }
+        changes
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "schemaVersion": self.schema_version,
+            "languages": self.language_codes,
+            "facetFields": self.facet_field_names,
+            "suggestFields": self.suggest_field_names,
+        })
+    }
+
+    fn from_json(index_settings_json: &Value) -> anyhow::Result<Self> {
+        let strings = |key: &str| {
+            index_settings_json[key]
+                .as_array()
+                .ok_or_else(|| anyhow!("missing {key} of indexSettings"))?
+                .iter()
+                .map(|value| {
+                    value
+                        .as_str()
+                        .map(String::from)
+                        .ok_or_else(|| anyhow!("invalid {key} of indexSettings"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        Ok(Self {
+            schema_version: index_settings_json["schemaVersion"]
+                .as_u64()
+                .and_then(|schema_version| u32::try_from(schema_version).ok())
+                .ok_or_else(|| anyhow!("missing schemaVersion of indexSettings"))?,
+            language_codes: strings("languages")?,
+            facet_field_names: strings("facetFields")?,
+            suggest_field_names: strings("suggestFields")?,
+        })
+    }
+}
+
+fn sorted(names: &[String]) -> Vec<String> {
+    let mut names = names.to_vec();
+    names.sort();
+    names.dedup();
+    names
+}
+
+impl Manifest {
+    /// Fingerprints the files under the Oxigraph init path.
+    pub fn for_oxigraph_store(oxigraph_init_path: &Path) -> anyhow::Result<Self> {
+        let mut init_files = BTreeMap::new();
+        for file_path in init_file_paths(oxigraph_init_path)? {
+            let relative_file_path = file_path
+                .strip_prefix(oxigraph_init_path)
+                .ok()
+                .filter(|relative_file_path| !relative_file_path.as_os_str().is_empty())
+                .or_else(|| file_path.file_name().map(Path::new))
+                .unwrap_or(&file_path);
+            init_files.insert(
+                relative_file_path.display().to_string(),
+                file_fingerprint(&file_path)?,
+            );
+        }
+        Ok(Self {
+            init_files,
+            index_init_sparql_sha256: None,
+            index_settings: None,
+            modified: false,
+        })
+    }
+
+    /// Fingerprints the index init query and records the index settings on top of the files the
+    /// store was loaded from.
+    pub fn for_tantivy_index(
+        oxigraph_store_manifest: &Self,
+        search_index_config: &SearchIndexConfig,
+    ) -> Self {
+        Self {
+            init_files: oxigraph_store_manifest.init_files.clone(),
+            index_init_sparql_sha256: Some(format!(
+                "{:x}",
+                Sha256::digest(search_index_config.index_init_sparql.as_bytes())
+            )),
+            index_settings: Some(IndexSettings {
+                schema_version: SCHEMA_VERSION,
+                language_codes: sorted(&search_index_config.language_codes),
+                facet_field_names: sorted(&search_index_config.facet_field_names),
+                suggest_field_names: sorted(&search_index_config.suggest_field_names),
+            }),
+            modified: false,
+        }
+    }
+
+    /// Path of the manifest of a data directory: a sibling file named after it.
+    pub fn path(data_directory_path: &Path) -> PathBuf {
+        let mut file_name = data_directory_path
+            .file_name()
+            .map_or_else(OsString::new, OsString::from);
+        file_name.push(".manifest.json");
+        data_directory_path.with_file_name(file_name)
+    }
+
+    /// Reads the manifest of a data directory, or returns `None` if it has none yet.
+    pub fn read(data_directory_path: &Path) -> anyhow::Result<Option<Self>> {
+        let manifest_path = Self::path(data_directory_path);
+        let manifest_json = match fs::read_to_string(&manifest_path) {
+            Ok(manifest_json) => manifest_json,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Self::from_json(&serde_json::from_str(&manifest_json)?)
+            .map(Some)
+            .map_err(|err| err.context(format!("invalid manifest {}", manifest_path.display())))
+    }
+
+    /// Writes the manifest of a data directory, replacing the previous one atomically.
+    pub fn write(&self, data_directory_path: &Path) -> anyhow::Result<()> {
+        let manifest_path = Self::path(data_directory_path);
+        let new_manifest_path = manifest_path.with_extension("json.new");
+        fs::write(
+            &new_manifest_path,
+            serde_json::to_string_pretty(&self.to_json())?,
+        )?;
+        fs::rename(new_manifest_path, manifest_path)?;
+        Ok(())
+    }
+
+    /// Records in the manifest of a data directory that its data was changed through the
+    /// server, if it has a manifest that does not record it yet.
+    pub fn record_modified(data_directory_path: &Path) -> anyhow::Result<()> {
+        if let Some(mut manifest) = Self::read(data_directory_path)? {
+            if !manifest.modified {
+                manifest.modified = true;
+                manifest.write(data_directory_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// What changed between a recorded manifest and this one, as human-readable messages; empty
+    /// if nothing did.
+    pub fn changes_since(&self, recorded: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        for (file_path, file_fingerprint) in &self.init_files {
+            match recorded.init_files.get(file_path) {
+                None => changes.push(format!("init file {file_path} was added")),
+                Some(recorded_file_fingerprint)
+                    if recorded_file_fingerprint != file_fingerprint =>
+                {
+                    changes.push(format!("init file {file_path} changed"))
+                }
+                Some(_) => {}
+            }
+        }
+        for file_path in recorded.init_files.keys() {
+            if !self.init_files.contains_key(file_path) {
+                changes.push(format!("init file {file_path} was removed"));
+            }
+        }
+        if self.index_init_sparql_sha256 != recorded.index_init_sparql_sha256 {
+            changes.push(String::from("index init query changed"));
+        }
+        match (&self.index_settings, &recorded.index_settings) {
+            (Some(index_settings), Some(recorded_index_settings)) => {
+                changes.extend(index_settings.changes_since(recorded_index_settings))
+            }
+            // Recorded before the index settings were
+            (Some(_), None) => changes.push(String::from("index settings are unknown")),
+            _ => {}
+        }
+        changes
+    }
+
+    fn to_json(&self) -> Value {
+        let init_files = self
+            .init_files
+            .iter()
+            .map(|(file_path, file_fingerprint)| {
+                (
+                    file_path.clone(),
+                    json!({
+                        "sha256": file_fingerprint.sha256,
+                        "size": file_fingerprint.size,
+                    }),
+                )
+            })
+            .collect::<Map<_, _>>();
+        let mut manifest_json = json!({ "initFiles": init_files });
+        if let Some(index_init_sparql_sha256) = &self.index_init_sparql_sha256 {
+            manifest_json["indexInitSparqlSha256"] = json!(index_init_sparql_sha256);
+        }
+        if let Some(index_settings) = &self.index_settings {
+            manifest_json["indexSettings"] = index_settings.to_json();
+        }
+        if self.modified {
+            manifest_json["modified"] = json!(true);
+        }
+        manifest_json
+    }
+
+    fn from_json(manifest_json: &Value) -> anyhow::Result<Self> {
+        let mut init_files = BTreeMap::new();
+        for (file_path, file_json) in manifest_json["initFiles"]
+            .as_object()
+            .ok_or_else(|| anyhow!("missing initFiles"))?
+        {
+            init_files.insert(
+                file_path.clone(),
+                FileFingerprint {
+                    sha256: String::from(
+                        file_json["sha256"]
+                            .as_str()
+                            .ok_or_else(|| anyhow!("missing sha256 of {file_path}"))?,
+                    ),
+                    size: file_json["size"]
+                        .as_u64()
+                        .ok_or_else(|| anyhow!("missing size of {file_path}"))?,
+                },
+            );
+        }
+        Ok(Self {
+            init_files,
+            index_init_sparql_sha256: manifest_json["indexInitSparqlSha256"]
+                .as_str()
+                .map(String::from),
+            index_settings: match &manifest_json["indexSettings"] {
+                Value::Null => None,
+                index_settings_json => Some(IndexSettings::from_json(index_settings_json)?),
+            },
+            modified: manifest_json["modified"].as_bool().unwrap_or(false),
+        })
+    }
+}
+
+fn file_fingerprint(file_path: &Path) -> anyhow::Result<FileFingerprint> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut BufReader::new(File::open(file_path)?), &mut hasher)?;
+    Ok(FileFingerprint {
+        sha256: format!("{:x}", hasher.finalize()),
+        size,
+    })
+}
+
+/// Whether a data directory built before from a recorded manifest must be rebuilt, according to
+/// the stale policy. Logs what changed; fails if the policy refuses stale data directories.
+///
+/// Data directories without a manifest were built from unknown content, and are stale. Data
+/// changed through the server since it was built is never rebuilt, as the changes would be lost:
+/// the rebuild policy refuses it.
+pub fn must_rebuild(
+    description: &str,
+    manifest: &Manifest,
+    recorded_manifest: Option<&Manifest>,
+    on_stale: OnStale,
+) -> anyhow::Result<bool> {
+    let changes = match recorded_manifest {
+        Some(recorded_manifest) => manifest.changes_since(recorded_manifest),
+        None => vec![String::from("provenance unknown")],
+    };
+    if changes.is_empty() {
+        return Ok(false);
+    }
+    let message = format!("{} is stale: {}", description, changes.join(", "));
+    match on_stale {
+        OnStale::Rebuild if recorded_manifest.is_some_and(|manifest| manifest.modified) => bail!(
+            "{message}, but it was modified through the server since it was built: rebuilding \
+             it would discard the modifications (remove its data directory to rebuild it)"
+        ),
+        OnStale::Rebuild => {
+            eprintln!("{message}, rebuilding");
+            Ok(true)
+        }
+        OnStale::Refuse => bail!("{message} (see --on-stale)"),
+        OnStale::Warn => {
+            eprintln!("{message}, using it anyway");
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn tantivy_index_manifest(search_index_config: &SearchIndexConfig) -> Manifest {
+        let oxigraph_store_manifest = Manifest {
+            init_files: BTreeMap::from([(
+                String::from("vocabulary.ttl"),
+                FileFingerprint {
+                    sha256: format!("{:x}", Sha256::digest(testing::VOCABULARY_TURTLE)),
+                    size: testing::VOCABULARY_TURTLE.len() as u64,
+                },
+            )]),
+            ..Manifest::default()
+        };
+        Manifest::for_tantivy_index(&oxigraph_store_manifest, search_index_config)
+    }
+
+    #[test]
+    fn manifests_round_trip_through_json() {
+        let manifest = tantivy_index_manifest(&testing::search_index_config());
+        assert_eq!(Manifest::from_json(&manifest.to_json()).unwrap(), manifest);
+    }
+
+    #[test]
+    fn index_settings_ignore_the_order_of_names() {
+        let mut search_index_config = testing::search_index_config();
+        search_index_config.facet_field_names.reverse();
+        assert_eq!(
+            tantivy_index_manifest(&search_index_config),
+            tantivy_index_manifest(&testing::search_index_config())
+        );
+    }
+
+    #[test]
+    fn rebuilds_stale_data_directories_according_to_the_policy() {
+        let recorded_manifest = tantivy_index_manifest(&testing::search_index_config());
+        for on_stale in [OnStale::Rebuild, OnStale::Refuse, OnStale::Warn] {
+            assert!(!must_rebuild(
+                "index",
+                &recorded_manifest,
+                Some(&recorded_manifest),
+                on_stale
+            )
+            .unwrap());
+        }
+
+        let mut search_index_config = testing::search_index_config();
+        search_index_config.language_codes.push(String::from("fr"));
+        let manifest = tantivy_index_manifest(&search_index_config);
+        assert_eq!(
+            manifest.changes_since(&recorded_manifest),
+            ["index languages changed"]
+        );
+        assert!(must_rebuild(
+            "index",
+            &manifest,
+            Some(&recorded_manifest),
+            OnStale::Rebuild
+        )
+        .unwrap());
+        assert!(must_rebuild(
+            "index",
+            &manifest,
+            Some(&recorded_manifest),
+            OnStale::Refuse
+        )
+        .is_err());
+        assert!(
+            !must_rebuild("index", &manifest, Some(&recorded_manifest), OnStale::Warn).unwrap()
+        );
+    }
+
+    #[test]
+    fn data_directories_without_a_manifest_are_stale() {
+        let manifest = tantivy_index_manifest(&testing::search_index_config());
+        assert!(must_rebuild("index", &manifest, None, OnStale::Rebuild).unwrap());
+        assert!(must_rebuild("index", &manifest, None, OnStale::Refuse).is_err());
+        assert!(!must_rebuild("index", &manifest, None, OnStale::Warn).unwrap());
+    }
+
+    #[test]
+    fn modified_data_directories_are_not_rebuilt() {
+        let mut recorded_manifest = tantivy_index_manifest(&testing::search_index_config());
+        recorded_manifest.modified = true;
+        assert_eq!(
+            Manifest::from_json(&recorded_manifest.to_json()).unwrap(),
+            recorded_manifest
+        );
+        let mut search_index_config = testing::search_index_config();
+        search_index_config.language_codes.push(String::from("fr"));
+        let manifest = tantivy_index_manifest(&search_index_config);
+        // Modifications do not make data stale by themselves
+        assert_eq!(
+            manifest.changes_since(&recorded_manifest),
+            ["index languages changed"]
+        );
+        assert!(must_rebuild(
+            "index",
+            &manifest,
+            Some(&recorded_manifest),
+            OnStale::Rebuild
+        )
+        .is_err());
+        assert!(
+            !must_rebuild("index", &manifest, Some(&recorded_manifest), OnStale::Warn).unwrap()
+        );
+    }
+
+    #[test]
+    fn schema_and_field_changes_make_indexes_stale() {
+        let recorded_manifest = tantivy_index_manifest(&testing::search_index_config());
+
+        let mut search_index_config = testing::search_index_config();
+        search_index_config.facet_field_names.pop();
+        search_index_config
+            .suggest_field_names
+            .push(String::from("text"));
+        let mut manifest = tantivy_index_manifest(&search_index_config);
+        manifest.index_settings.as_mut().unwrap().schema_version += 1;
+        assert_eq!(
+            manifest.changes_since(&recorded_manifest),
+            [
+                format!(
+                    "index schema version changed from {} to {}",
+                    SCHEMA_VERSION,
+                    SCHEMA_VERSION + 1
+                ),
+                String::from("index facet fields changed"),
+                String::from("index suggest fields changed"),
+            ]
+        );
+
+        let recorded_manifest = Manifest {
+            index_settings: None,
+            ..recorded_manifest
+        };
+        assert_eq!(
+            tantivy_index_manifest(&testing::search_index_config())
+                .changes_since(&recorded_manifest),
+            ["index settings are unknown"]
+        );
+    }
+}
@@ -0,0 +1,892 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use oxhttp::model::{HeaderName, Request, Response, Status};
+use oxigraph::{
+    model::{
+        vocab::{rdf, rdfs},
+        Literal, NamedNode, Term,
+    },
+    sparql::{EvaluationError, QueryResults},
+    store::Store,
+};
+use serde_json::{json, Value};
+use tantivy::IndexReader;
+use url::{form_urlencoded, Url};
+
+use crate::search::{
+    describe, search, Description, ParsedUrl, SearchMode, SearchQueryParser, SKOS_PREF_LABEL,
+};
+use crate::sparql::union_default_graph_query;
+use crate::suggest::suggest;
+
+type HttpError = (Status, String);
+
+/// IRIs of the candidates of a query, with their scores, best first.
+type Candidates = Vec<(String, f32)>;
+
+const MAX_RECONCILE_BODY_SIZE: u64 = 0x0010_0000;
+
+/// Candidates returned per query when the query has no limit.
+const DEFAULT_LIMIT: usize = 10;
+
+/// Hits checked against the type and property constraints of a query before giving up on
+/// filling its limit.
+const MAX_CHECKED_HITS: usize = 1000;
+
+/// A reconciliation query: a cell of a spreadsheet column, with the types and property values
+/// the matching resource should have.
+struct ReconciliationQuery {
+    limit: usize,
+    /// Property IRIs and the values, any of which the resource should have for them
+    properties: Vec<(NamedNode, Vec<PropertyValue>)>,
+    query: String,
+    types: Vec<NamedNode>,
+}
+
+enum PropertyValue {
+    /// A resource, given by its IRI
+    Iri(NamedNode),
+    /// A literal, compared to the lexical forms of the values case-insensitively
+    Text(String),
+}
+
+impl ReconciliationQuery {
+    fn parse(query_json: &Value) -> Result<Self, String> {
+        let query = query_json["query"]
+            .as_str()
+            .ok_or("query should have a query string")?;
+        let limit = match &query_json["limit"] {
+            Value::Null => DEFAULT_LIMIT,
+            limit_json => limit_json
+                .as_u64()
+                .ok_or("limit should be a non-negative integer")?
+                as usize,
+        };
+        let types = match &query_json["type"] {
+            Value::Null => Vec::new(),
+            Value::Array(types_json) => {
+                types_json.iter().map(parse_iri).collect::<Result<_, _>>()?
+            }
+            type_json => vec![parse_iri(type_json)?],
+        };
+        let mut properties = Vec::new();
+        if let Some(properties_json) = query_json["properties"].as_array() {
+            for property_json in properties_json {
+                let values = match &property_json["v"] {
+                    Value::Array(values_json) => values_json
+                        .iter()
+                        .map(PropertyValue::parse)
+                        .collect::<Result<_, _>>()?,
+                    value_json => vec![PropertyValue::parse(value_json)?],
+                };
+                properties.push((parse_iri(&property_json["pid"])?, values));
+            }
+        }
+        Ok(Self {
+            limit,
+            properties,
+            query: String::from(query),
+            types,
+        })
+    }
+}
+
+impl PropertyValue {
+    fn parse(value_json: &Value) -> Result<Self, String> {
+        match value_json {
+            Value::Object(_) => Ok(Self::Iri(parse_iri(&value_json["id"])?)),
+            Value::String(text) => Ok(Self::Text(text.clone())),
+            Value::Number(number) => Ok(Self::Text(number.to_string())),
+            Value::Bool(boolean) => Ok(Self::Text(boolean.to_string())),
+            _ => Err(format!("unsupported property value {}", value_json)),
+        }
+    }
+
+    /// SPARQL condition on `?value` for the value to match.
+    fn sparql_filter(&self) -> String {
+        match self {
+            Self::Iri(iri) => format!("?value = {}", iri),
+            Self::Text(text) => format!(
+                "LCASE(STR(?value)) = LCASE({})",
+                Literal::new_simple_literal(text)
+            ),
+        }
+    }
+}
+
+fn parse_iri(iri_json: &Value) -> Result<NamedNode, String> {
+    let iri = iri_json
+        .as_str()
+        .ok_or_else(|| format!("{} should be an IRI string", iri_json))?;
+    NamedNode::new(iri).map_err(|err| format!("invalid IRI {}: {}", iri, err))
+}
+
+/// The last segment of an IRI, as the name of resources without labels (e.g., types).
+fn local_name(iri: &str) -> &str {
+    iri.rsplit(['#', '/', ':'])
+        .find(|segment| !segment.is_empty())
+        .unwrap_or(iri)
+}
+
+/// The candidates, among hit IRIs, that have one of the types and one of the values of each
+/// property of a query, checked by a single query.
+///
+/// Types are only checked here when they are not a facet of the index, which filters them.
+fn satisfying_iris(
+    oxigraph_store: &Store,
+    iris: &[&str],
+    reconciliation_query: &ReconciliationQuery,
+    check_types: bool,
+) -> Result<HashSet<String>, HttpError> {
+    let mut conditions = Vec::new();
+    if check_types && !reconciliation_query.types.is_empty() {
+        let types = reconciliation_query
+            .types
+            .iter()
+            .map(NamedNode::to_string)
+            .collect::<Vec<_>>()
+            .join(" ");
+        conditions.push(format!(
+            "FILTER EXISTS {{ ?iri {} ?type VALUES ?type {{ {} }} }}",
+            rdf::TYPE,
+            types
+        ));
+    }
+    for (property, values) in &reconciliation_query.properties {
+        let filter = values
+            .iter()
+            .map(PropertyValue::sparql_filter)
+            .collect::<Vec<_>>()
+            .join(" || ");
+        conditions.push(format!(
+            "FILTER EXISTS {{ ?iri {} ?value FILTER({}) }}",
+            property, filter
+        ));
+    }
+    if conditions.is_empty() {
+        return Ok(iris.iter().map(|iri| String::from(*iri)).collect());
+    }
+
+    // Hits come from the index, so their IRIs are well-formed. The IRIs are bound before the
+    // conditions are evaluated, as FILTER EXISTS patterns only see the bindings of their group.
+    let query = format!(
+        "SELECT ?iri WHERE {{ VALUES ?iri {{ {} }} {} }}",
+        iris.iter()
+            .map(|iri| format!("<{}>", iri))
+            .collect::<Vec<_>>()
+            .join(" "),
+        conditions.join(" ")
+    );
+    let QueryResults::Solutions(solutions) = union_default_graph_query(&query)
+        .map_err(EvaluationError::from)
+        .and_then(|query| oxigraph_store.query(query))
+        .map_err(|err| {
+            internal_server_error(format!(
+                "error executing constraint query:\nQuery:\n{}\nError:\n{}",
+                query, err
+            ))
+        })?
+    else {
+        return Err(internal_server_error(
+            "constraint query is not a SELECT query",
+        ));
+    };
+    let mut satisfying_iris = HashSet::new();
+    for solution in solutions {
+        if let Some(Term::NamedNode(iri)) = solution.map_err(internal_server_error)?.get("iri") {
+            satisfying_iris.insert(String::from(iri.as_str()));
+        }
+    }
+    Ok(satisfying_iris)
+}
+
+/// Answers a batch of reconciliation queries: a JSON object mapping each query key to
+/// `{"result": [{"id", "name", "type", "score", "match"}]}`, best candidate first.
+fn reconcile(
+    queries_json: &str,
+    languages: Vec<String>,
+    index_result_sparql: &str,
+    oxigraph_store: &Store,
+    tantivy_index_reader: &IndexReader,
+    search_query_parser: &SearchQueryParser,
+    facet_field_names: &[String],
+) -> Result<Value, HttpError> {
+    let queries_json = serde_json::from_str::<Value>(queries_json)
+        .map_err(|err| bad_request(format!("error parsing queries: {}", err)))?;
+    let queries_json = queries_json
+        .as_object()
+        .ok_or_else(|| bad_request("queries should be a JSON object"))?;
+    let type_is_facet = facet_field_names
+        .iter()
+        .any(|facet_field_name| facet_field_name == "type");
+
+    let tantivy_index_searcher = tantivy_index_reader.searcher();
+    // Candidate IRIs and scores of each query
+    let mut candidates_by_key: Vec<(&String, &ReconciliationQuery, Candidates)> = Vec::new();
+    let reconciliation_queries = queries_json
+        .iter()
+        .map(|(key, query_json)| {
+            ReconciliationQuery::parse(query_json)
+                .map(|reconciliation_query| (key, reconciliation_query))
+                .map_err(|err| bad_request(format!("error parsing query {}: {}", key, err)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (key, reconciliation_query) in &reconciliation_queries {
+        let mut candidates = Vec::new();
+        if reconciliation_query.limit > 0 && !reconciliation_query.query.trim().is_empty() {
+            let (_, iri_hits) = search(
+                &ParsedUrl {
+                    facet_filters: if type_is_facet && !reconciliation_query.types.is_empty() {
+                        vec![(
+                            String::from("type"),
+                            reconciliation_query
+                                .types
+                                .iter()
+                                .map(|type_iri| type_iri.as_str().to_owned())
+                                .collect(),
+                        )]
+                    } else {
+                        Vec::new()
+                    },
+                    highlight: false,
+                    languages: languages.clone(),
+                    limit: reconciliation_query.limit,
+                    // Cells are not query syntax and are often misspelled
+                    mode: Some(SearchMode::Fuzzy),
+                    offset: 0,
+                    query: reconciliation_query.query.clone(),
+                },
+                &tantivy_index_searcher,
+                search_query_parser,
+            )?;
+            let iri_hits = iri_hits
+                .into_iter()
+                .take(MAX_CHECKED_HITS)
+                .collect::<Vec<_>>();
+            let satisfying_iris = satisfying_iris(
+                oxigraph_store,
+                &iri_hits
+                    .iter()
+                    .map(|iri_hit| iri_hit.iri.as_str())
+                    .collect::<Vec<_>>(),
+                reconciliation_query,
+                !type_is_facet,
+            )?;
+            candidates.extend(
+                iri_hits
+                    .into_iter()
+                    .filter(|iri_hit| satisfying_iris.contains(&iri_hit.iri))
+                    .take(reconciliation_query.limit)
+                    .map(|iri_hit| (iri_hit.iri, iri_hit.score)),
+            );
+        }
+        candidates_by_key.push((key, reconciliation_query, candidates));
+    }
+
+    // The candidates of every query are described by one evaluation of the index result query
+    let descriptions = describe(
+        oxigraph_store,
+        index_result_sparql,
+        candidates_by_key
+            .iter()
+            .flat_map(|(_, _, candidates)| candidates.iter().map(|(iri, _)| iri.as_str())),
+        languages,
+    )?;
+    let no_description = Description::default();
+
+    let mut results = serde_json::Map::new();
+    for (key, reconciliation_query, candidates) in candidates_by_key {
+        let query = reconciliation_query.query.trim().to_lowercase();
+        let is_exact_match = |iri: &str| {
+            descriptions
+                .get(iri)
+                .unwrap_or(&no_description)
+                .names()
+                .any(|name| name.trim().to_lowercase() == query)
+        };
+        // A candidate is a match if it is the only one named exactly like the query, and the
+        // search ranked it first
+        let match_count = candidates
+            .iter()
+            .filter(|(iri, _)| is_exact_match(iri))
+            .count();
+        let result = candidates
+            .iter()
+            .enumerate()
+            .map(|(rank_index, (iri, score))| {
+                let description = descriptions.get(iri).unwrap_or(&no_description);
+                json!({
+                    "id": iri,
+                    "name": description.name().unwrap_or(iri),
+                    "type": description
+                        .types
+                        .iter()
+                        .map(|type_iri| json!({ "id": type_iri, "name": local_name(type_iri) }))
+                        .collect::<Vec<_>>(),
+                    "score": score,
+                    "match": rank_index == 0 && match_count == 1 && is_exact_match(iri),
+                })
+            })
+            .collect::<Vec<_>>();
+        results.insert(key.clone(), json!({ "result": result }));
+    }
+    Ok(Value::Object(results))
+}
+
+/// The URL of the reconciliation service, from which the URLs of its other services derive.
+fn service_url(request: &Request) -> Url {
+    let mut url = request.url().clone();
+    url.set_path("/reconcile");
+    url.set_query(None);
+    url.set_fragment(None);
+    url
+}
+
+/// The service manifest, describing the reconciliation service and its preview and suggest
+/// services to clients like OpenRefine.
+fn manifest(request: &Request) -> Value {
+    let service_url = service_url(request);
+    json!({
+        "versions": ["0.1", "0.2"],
+        "name": "kos-kit",
+        // Identifiers are the IRIs of the resources
+        "identifierSpace": "urn:ietf:rfc:3987",
+        // Types and properties are RDF resources
+        "schemaSpace": "http://www.w3.org/1999/02/22-rdf-syntax-ns#",
+        "view": { "url": "{{id}}" },
+        "preview": {
+            "url": format!("{}/preview?id={{{{id}}}}", service_url),
+            "width": 400,
+            "height": 100,
+        },
+        "suggest": {
+            "entity": {
+                "service_url": service_url.as_str(),
+                "service_path": "/suggest/entity",
+            },
+            "property": {
+                "service_url": service_url.as_str(),
+                "service_path": "/suggest/property",
+            },
+            "type": {
+                "service_url": service_url.as_str(),
+                "service_path": "/suggest/type",
+            },
+        },
+    })
+}
+
+/// Reads the parameters of the URL and, for a form POST, of the body.
+fn parameters(request: &mut Request) -> Result<Vec<(String, String)>, HttpError> {
+    let mut parameters = request.url().query_pairs().into_owned().collect::<Vec<_>>();
+    if request.method().as_ref() == "POST" {
+        let mut buffer = Vec::new();
+        request
+            .body_mut()
+            .take(MAX_RECONCILE_BODY_SIZE)
+            .read_to_end(&mut buffer)
+            .map_err(bad_request)?;
+        parameters.extend(form_urlencoded::parse(&buffer).into_owned());
+    }
+    Ok(parameters)
+}
+
+fn parameter<'a>(parameters: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    parameters
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Requested language tags, lowercased, in order of preference
+fn languages(parameters: &[(String, String)]) -> Vec<String> {
+    parameters
+        .iter()
+        .filter(|(key, _)| key == "lang")
+        .map(|(_, value)| value.to_ascii_lowercase())
+        .collect()
+}
+
+fn json_response(value: Value) -> Response {
+    Response::builder(Status::OK)
+        .with_header(HeaderName::CONTENT_TYPE, "application/json")
+        .unwrap()
+        .with_body(value.to_string())
+}
+
+/// Implements the [W3C Reconciliation Service API](https://www.w3.org/community/reports/reconciliation/CG-FINAL-specs-0.2-20230410/)
+/// on top of the search index: `GET /reconcile` returns the service manifest, and `GET` or
+/// `POST` with a `queries` parameter reconciles a batch of queries.
+///
+/// Candidates are the search hits, filtered by type with the type facet if the index has one
+/// and with `rdf:type` triples otherwise, and by property values with Oxigraph. They are named
+/// and typed by the index result query.
+pub fn handle_request(
+    request: &mut Request,
+    index_result_sparql: &str,
+    oxigraph_store: &Store,
+    tantivy_index_reader: &IndexReader,
+    search_query_parser: &SearchQueryParser,
+    facet_field_names: &[String],
+) -> Result<Response, HttpError> {
+    if !matches!(request.method().as_ref(), "GET" | "POST") {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    let parameters = parameters(request)?;
+    match parameter(&parameters, "queries") {
+        Some(queries_json) => reconcile(
+            queries_json,
+            languages(&parameters),
+            index_result_sparql,
+            oxigraph_store,
+            tantivy_index_reader,
+            search_query_parser,
+            facet_field_names,
+        )
+        .map(json_response),
+        None => Ok(json_response(manifest(request))),
+    }
+}
+
+/// Answers `GET /reconcile/preview?id=...` with an HTML card of the resource.
+pub fn handle_preview_request(
+    request: &mut Request,
+    index_result_sparql: &str,
+    oxigraph_store: &Store,
+) -> Result<Response, HttpError> {
+    if request.method().as_ref() != "GET" {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    let parameters = parameters(request)?;
+    let iri = parameter(&parameters, "id").ok_or_else(|| bad_request("missing id"))?;
+    let iri = NamedNode::new(iri).map_err(bad_request)?;
+    let descriptions = describe(
+        oxigraph_store,
+        index_result_sparql,
+        std::iter::once(iri.as_str()),
+        languages(&parameters),
+    )?;
+    let description = descriptions
+        .get(iri.as_str())
+        .ok_or_else(|| (Status::NOT_FOUND, format!("{} is not described", iri)))?;
+
+    let mut html = format!(
+        "<html><body><div style=\"font-family: sans-serif; font-size: small\"><a href=\"{}\" target=\"_blank\"><b>{}</b></a>",
+        escape_html(iri.as_str()),
+        escape_html(description.name().unwrap_or(iri.as_str()))
+    );
+    if !description.types.is_empty() {
+        html.push_str("<br>");
+        html.push_str(
+            &description
+                .types
+                .iter()
+                .map(|type_iri| escape_html(local_name(type_iri)))
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+    }
+    html.push_str(&format!("<br><code>{}</code>", escape_html(iri.as_str())));
+    html.push_str("</div></body></html>");
+
+    Ok(Response::builder(Status::OK)
+        .with_header(HeaderName::CONTENT_TYPE, "text/html; charset=utf-8")
+        .unwrap()
+        .with_body(html))
+}
+
+/// Answers the entity suggest service with the `/suggest` suggestions for `prefix`.
+pub fn handle_suggest_entity_request(
+    request: &mut Request,
+    tantivy_index_reader: &IndexReader,
+) -> Result<Response, HttpError> {
+    if request.method().as_ref() != "GET" {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    let parameters = parameters(request)?;
+    let prefix = parameter(&parameters, "prefix").ok_or_else(|| bad_request("missing prefix"))?;
+    let cursor = parse_cursor(&parameters)?;
+    let result = suggest(
+        &tantivy_index_reader.searcher(),
+        prefix,
+        languages(&parameters),
+        cursor + DEFAULT_LIMIT,
+    )?
+    .into_iter()
+    .skip(cursor)
+    .map(|suggestion| {
+        json!({
+            "id": suggestion.iri,
+            "name": suggestion.label.as_deref().unwrap_or(&suggestion.iri),
+            "notable": suggestion
+                .types
+                .iter()
+                .map(|type_iri| json!({ "id": type_iri, "name": local_name(type_iri) }))
+                .collect::<Vec<_>>(),
+        })
+    })
+    .collect::<Vec<_>>();
+    Ok(json_response(json!({ "result": result })))
+}
+
+/// A resource of the store the schema suggest services suggest, with its first label or its local
+/// name.
+struct SchemaResource {
+    iri: String,
+    name: String,
+}
+
+/// The types or the properties of the store, listed again after the store changes rather than on
+/// every keystroke of a suggest service.
+#[derive(Default)]
+pub struct SchemaCache {
+    resources: Mutex<Option<(u64, Arc<Vec<SchemaResource>>)>>,
+}
+
+impl SchemaCache {
+    /// The resources bound to `?iri` by a query, as of a generation of the store (see
+    /// `SearchIndexHandle::store_generation`).
+    fn get(
+        &self,
+        oxigraph_store: &Store,
+        iris_sparql: &str,
+        store_generation: u64,
+    ) -> Result<Arc<Vec<SchemaResource>>, HttpError> {
+        if let Some((resources_store_generation, resources)) = self
+            .resources
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            if *resources_store_generation == store_generation {
+                return Ok(Arc::clone(resources));
+            }
+        }
+
+        // Listed without holding the lock, so that concurrent requests don't queue up behind it
+        let resources = Arc::new(schema_resources(oxigraph_store, iris_sparql)?);
+        *self
+            .resources
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) =
+            Some((store_generation, Arc::clone(&resources)));
+        Ok(resources)
+    }
+}
+
+fn schema_resources(
+    oxigraph_store: &Store,
+    iris_sparql: &str,
+) -> Result<Vec<SchemaResource>, HttpError> {
+    let sparql = format!(
+        "SELECT ?iri (SAMPLE(?label) AS ?name) WHERE {{ {{ {} }} OPTIONAL {{ ?iri {}|{} ?label }} }} GROUP BY ?iri",
+        iris_sparql,
+        SKOS_PREF_LABEL,
+        rdfs::LABEL
+    );
+    let QueryResults::Solutions(solutions) = union_default_graph_query(&sparql)
+        .map_err(EvaluationError::from)
+        .and_then(|query| oxigraph_store.query(query))
+        .map_err(internal_server_error)?
+    else {
+        return Err(internal_server_error("suggest query is not a SELECT query"));
+    };
+    let mut resources = Vec::new();
+    for solution in solutions {
+        let solution = solution.map_err(internal_server_error)?;
+        let Some(Term::NamedNode(iri)) = solution.get("iri") else {
+            continue;
+        };
+        let name = match solution.get("name") {
+            Some(Term::Literal(name)) => name.value(),
+            _ => local_name(iri.as_str()),
+        };
+        resources.push(SchemaResource {
+            iri: String::from(iri.as_str()),
+            name: String::from(name),
+        });
+    }
+    // Suggested in a stable order
+    resources.sort_by(|left, right| left.iri.cmp(&right.iri));
+    Ok(resources)
+}
+
+/// Answers the property suggest service with the predicates of the store whose label or local
+/// name contains `prefix`.
+pub fn handle_suggest_property_request(
+    request: &mut Request,
+    oxigraph_store: &Store,
+    property_cache: &SchemaCache,
+    store_generation: u64,
+) -> Result<Response, HttpError> {
+    handle_suggest_schema_request(request, || {
+        property_cache.get(
+            oxigraph_store,
+            "SELECT DISTINCT ?iri WHERE { ?subject ?iri ?object }",
+            store_generation,
+        )
+    })
+}
+
+/// Answers the type suggest service with the types of the store whose label or local name
+/// contains `prefix`.
+pub fn handle_suggest_type_request(
+    request: &mut Request,
+    oxigraph_store: &Store,
+    type_cache: &SchemaCache,
+    store_generation: u64,
+) -> Result<Response, HttpError> {
+    handle_suggest_schema_request(request, || {
+        type_cache.get(
+            oxigraph_store,
+            &format!(
+                "SELECT DISTINCT ?iri WHERE {{ ?subject {} ?iri }}",
+                rdf::TYPE
+            ),
+            store_generation,
+        )
+    })
+}
+
+/// Suggests schema resources by their name or their local name.
+fn handle_suggest_schema_request(
+    request: &mut Request,
+    resources: impl FnOnce() -> Result<Arc<Vec<SchemaResource>>, HttpError>,
+) -> Result<Response, HttpError> {
+    if request.method().as_ref() != "GET" {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    let parameters = parameters(request)?;
+    let prefix = parameter(&parameters, "prefix")
+        .ok_or_else(|| bad_request("missing prefix"))?
+        .to_lowercase();
+    let cursor = parse_cursor(&parameters)?;
+
+    let result = resources()?
+        .iter()
+        .filter(|resource| {
+            resource.name.to_lowercase().contains(&prefix)
+                || local_name(&resource.iri).to_lowercase().contains(&prefix)
+        })
+        .skip(cursor)
+        .take(DEFAULT_LIMIT)
+        .map(|resource| json!({ "id": resource.iri, "name": resource.name }))
+        .collect::<Vec<_>>();
+    Ok(json_response(json!({ "result": result })))
+}
+
+fn parse_cursor(parameters: &[(String, String)]) -> Result<usize, HttpError> {
+    parameter(parameters, "cursor")
+        .map(|cursor| {
+            cursor
+                .parse::<usize>()
+                .map_err(|err| bad_request(format!("error parsing cursor: {}", err)))
+        })
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn bad_request(message: impl std::fmt::Display) -> HttpError {
+    (Status::BAD_REQUEST, message.to_string())
+}
+
+fn internal_server_error(message: impl std::fmt::Display) -> HttpError {
+    eprintln!("Internal server error: {message}");
+    (Status::INTERNAL_SERVER_ERROR, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use oxhttp::model::Method;
+
+    use super::*;
+    use crate::testing::{self, EX};
+
+    const SKOS_CONCEPT: &str = "http://www.w3.org/2004/02/skos/core#Concept";
+    const SKOS_NOTATION: &str = "http://www.w3.org/2004/02/skos/core#notation";
+
+    fn ex(name: &str) -> String {
+        format!("{}{}", EX, name)
+    }
+
+    #[test]
+    fn parses_reconciliation_queries() {
+        let reconciliation_query = ReconciliationQuery::parse(&json!({
+            "query": "Cat",
+            "type": SKOS_CONCEPT,
+            "properties": [
+                { "pid": SKOS_NOTATION, "v": ["A1", 2] },
+                { "pid": "http://www.w3.org/2004/02/skos/core#inScheme", "v": { "id": ex("animals") } },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(reconciliation_query.query, "Cat");
+        assert_eq!(reconciliation_query.limit, DEFAULT_LIMIT);
+        assert_eq!(reconciliation_query.types[0].as_str(), SKOS_CONCEPT);
+        assert_eq!(
+            reconciliation_query.properties[0]
+                .1
+                .iter()
+                .map(PropertyValue::sparql_filter)
+                .collect::<Vec<_>>(),
+            [
+                r#"LCASE(STR(?value)) = LCASE("A1")"#,
+                r#"LCASE(STR(?value)) = LCASE("2")"#
+            ]
+        );
+        assert_eq!(
+            reconciliation_query.properties[1].1[0].sparql_filter(),
+            format!("?value = <{}>", ex("animals"))
+        );
+
+        assert!(ReconciliationQuery::parse(&json!({ "limit": 1 })).is_err());
+        assert!(ReconciliationQuery::parse(&json!({ "query": "Cat", "limit": -1 })).is_err());
+        assert!(
+            ReconciliationQuery::parse(&json!({ "query": "Cat", "type": "not an IRI" })).is_err()
+        );
+    }
+
+    #[test]
+    fn checks_the_constraints_of_every_candidate_at_once() {
+        let oxigraph_store = testing::store();
+        let reconciliation_query = ReconciliationQuery::parse(&json!({
+            "query": "a",
+            "type": SKOS_CONCEPT,
+            "properties": [{ "pid": SKOS_NOTATION, "v": ["a1", "B1"] }],
+        }))
+        .unwrap();
+        let iris = [ex("animals"), ex("cat"), ex("catfish"), ex("dog")];
+        let iris = iris.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(
+            satisfying_iris(&oxigraph_store, &iris, &reconciliation_query, true).unwrap(),
+            HashSet::from([ex("cat"), ex("catfish")])
+        );
+
+        // Without constraints, every candidate is kept
+        let reconciliation_query =
+            ReconciliationQuery::parse(&json!({ "query": "a", "type": SKOS_CONCEPT })).unwrap();
+        assert_eq!(
+            satisfying_iris(&oxigraph_store, &iris, &reconciliation_query, false)
+                .unwrap()
+                .len(),
+            4
+        );
+    }
+
+    #[test]
+    fn names_resources_by_local_name() {
+        assert_eq!(
+            local_name("http://www.w3.org/2004/02/skos/core#Concept"),
+            "Concept"
+        );
+        assert_eq!(local_name("http://example.com/animals/"), "animals");
+        assert_eq!(local_name("urn:isbn"), "isbn");
+    }
+
+    #[test]
+    fn reconciles_batches_of_queries() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let search_index = search_index_handle.current();
+        let queries = json!({
+            "q0": { "query": "Cat", "type": SKOS_CONCEPT },
+            "q1": { "query": "Dog", "properties": [{ "pid": SKOS_NOTATION, "v": "a2" }] },
+            "q2": { "query": "Cat", "properties": [{ "pid": SKOS_NOTATION, "v": "B1" }] },
+        });
+        let mut request = testing::request(
+            Method::GET,
+            &format!(
+                "/reconcile?{}",
+                form_urlencoded::Serializer::new(String::new())
+                    .append_pair("queries", &queries.to_string())
+                    .append_pair("lang", "en")
+                    .finish()
+            ),
+        );
+        let results = testing::body_json(
+            handle_request(
+                &mut request,
+                testing::INDEX_RESULT_SPARQL,
+                &oxigraph_store,
+                &search_index.reader,
+                &search_index.query_parser,
+                &search_index_handle.config().facet_field_names,
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(results["q0"]["result"][0]["id"], ex("cat"));
+        assert_eq!(results["q0"]["result"][0]["name"], "Cat");
+        assert_eq!(results["q0"]["result"][0]["match"], true);
+        assert_eq!(
+            results["q1"]["result"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|result| result["id"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            [ex("dog")]
+        );
+        assert_eq!(results["q2"]["result"], json!([]));
+    }
+
+    #[test]
+    fn suggests_types_until_the_store_changes() {
+        let oxigraph_store = testing::store();
+        let type_cache = SchemaCache::default();
+        let suggest_types = |store_generation: u64| {
+            let mut request = testing::request(Method::GET, "/reconcile/suggest/type?prefix=con");
+            testing::body_json(
+                handle_suggest_type_request(
+                    &mut request,
+                    &oxigraph_store,
+                    &type_cache,
+                    store_generation,
+                )
+                .unwrap(),
+            )["result"]
+                .clone()
+        };
+        assert_eq!(
+            suggest_types(0),
+            json!([
+                { "id": SKOS_CONCEPT, "name": "Concept" },
+                { "id": "http://www.w3.org/2004/02/skos/core#ConceptScheme", "name": "ConceptScheme" },
+            ])
+        );
+
+        oxigraph_store
+            .update(&format!(
+                "INSERT DATA {{ <{}> a <http://example.com/Container> }}",
+                ex("box")
+            ))
+            .unwrap();
+        assert_eq!(suggest_types(0).as_array().unwrap().len(), 2);
+        assert_eq!(suggest_types(1).as_array().unwrap().len(), 3);
+    }
+}
@@ -1,44 +1,204 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
 
-use oxhttp::model::{Request, Response, Status};
+use icu_collator::Collator;
+use oxhttp::model::{HeaderName, Request, Response, Status};
 use oxigraph::{
-    io::GraphSerializer,
-    model::{GraphNameRef, QuadRef},
-    sparql::QueryResults,
+    io::{write::TripleWriter, GraphFormat, GraphSerializer},
+    model::{
+        vocab::{rdf, rdfs},
+        BlankNode, Literal, NamedNode, NamedNodeRef, Subject, Term, Triple,
+    },
+    sparql::{
+        Query as SparqlQuery, QueryOptions, QueryResults, QueryResultsFormat, QuerySolution,
+        QuerySolutionIter, QueryTripleIter, Variable,
+    },
     store::Store,
 };
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use spargebra::algebra::{Expression, GraphPattern, OrderExpression};
+use spargebra::term::{NamedNodePattern, TermPattern, TriplePattern};
 use tantivy::{
-    collector::{Count, TopDocs},
-    query::QueryParser,
-    schema::Value,
-    IndexReader, TantivyDocument,
+    collector::Collector,
+    query::{BooleanQuery, ConstScoreQuery, Occur, Query, QueryParser, TermSetQuery},
+    schema::{Field, Value},
+    DocAddress, IndexReader, Score, Searcher, SnippetGenerator, TantivyDocument,
 };
-use url::Url;
+use url::{form_urlencoded, Url};
 
-use crate::sparql::{graph_content_negotiation, ReadForWrite};
+use crate::analysis;
+use crate::collector::{IriCollector, IriHit, IriPageCollector};
+use crate::fuzzy::FuzzyQueryParser;
+use crate::indexer::SORT_LABEL_LANG_SEPARATOR;
+use crate::sparql::{
+    content_negotiation, union_default_graph_query, url_query, with_iri_values, ReadForWrite,
+    ReadForWriteWriter, ResultIter,
+};
+use crate::timeout::{Deadline, QueryTimeouts};
+use crate::vocab::kos;
 
 type HttpError = (Status, String);
 
-struct ParsedUrl {
-    limit: usize,
-    offset: usize,
-    query: String,
+/// Time a generation of the index is kept for its cursors after one of them was last used.
+const CURSOR_TIME_TO_LIVE: Duration = Duration::from_secs(10 * 60);
+
+/// Rankings of searches kept for the pages after their cursors, across generations.
+const MAX_CURSOR_RANKINGS: usize = 16;
+
+/// Score added to exact matches in fuzzy mode, at least the score of any fuzzy match.
+const EXACT_MATCH_SCORE: Score = 1.0;
+
+/// Parameters of `/search` requests, which facets cannot be named after.
+const SEARCH_PARAMETER_NAMES: [&str; 9] = [
+    "cursor",
+    "highlight",
+    "lang",
+    "limit",
+    "mode",
+    "offset",
+    "query",
+    "sort",
+    "timeout",
+];
+
+/// How the words of a search are matched against the texts of the index.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SearchMode {
+    /// Words match terms equal to them, with the `QueryParser` syntax
+    Exact,
+    /// Words also match terms within an edit distance of them, exact matches ranking first
+    Fuzzy,
+}
+
+impl FromStr for SearchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(Self::Exact),
+            "fuzzy" => Ok(Self::Fuzzy),
+            _ => Err(format!(
+                "unknown search mode {} (expected exact or fuzzy)",
+                s
+            )),
+        }
+    }
+}
+
+/// Parses the `query` of a search in the requested mode, or in the server's default mode.
+pub struct SearchQueryParser {
+    default_mode: SearchMode,
+    fuzzy_query_parser: FuzzyQueryParser,
+    query_parser: QueryParser,
+}
+
+impl SearchQueryParser {
+    pub fn new(
+        query_parser: QueryParser,
+        fuzzy_query_parser: FuzzyQueryParser,
+        default_mode: SearchMode,
+    ) -> Self {
+        Self {
+            default_mode,
+            fuzzy_query_parser,
+            query_parser,
+        }
+    }
+
+    /// The text fields searched, in every language.
+    pub(crate) fn text_fields(&self) -> Vec<Field> {
+        self.fuzzy_query_parser.fields()
+    }
+
+    fn parse_query(
+        &self,
+        query: &str,
+        mode: Option<SearchMode>,
+    ) -> Result<Box<dyn Query>, HttpError> {
+        match mode.unwrap_or(self.default_mode) {
+            SearchMode::Exact => self
+                .query_parser
+                .parse_query(query)
+                .map_err(|err| (Status::BAD_REQUEST, err.to_string())),
+            SearchMode::Fuzzy => {
+                // Exact matches score EXACT_MATCH_SCORE on top of their exact and fuzzy scores,
+                // and fuzzy queries score at most 1, so exact matches rank above fuzzy matches
+                // whatever the field boosts. Users typing misspelled words are not expected to
+                // write query syntax, which is parsed leniently.
+                let (exact_query, _) = self.query_parser.parse_query_lenient(query);
+                let fuzzy_query = self.fuzzy_query_parser.parse_query(query).map_err(|err| {
+                    (
+                        Status::INTERNAL_SERVER_ERROR,
+                        format!("error building fuzzy query: {}", err),
+                    )
+                })?;
+                Ok(Box::new(BooleanQuery::new(vec![
+                    (
+                        Occur::Should,
+                        Box::new(ConstScoreQuery::new(
+                            exact_query.box_clone(),
+                            EXACT_MATCH_SCORE,
+                        )),
+                    ),
+                    (Occur::Should, exact_query),
+                    (Occur::Should, fuzzy_query),
+                ])))
+            }
+        }
+    }
+}
+
+pub(crate) struct ParsedUrl {
+    /// Facet field names and the values requested for them: a concept must have one of the
+    /// values of every filtered facet
+    pub(crate) facet_filters: Vec<(String, Vec<String>)>,
+    /// Whether to return the matched text of each hit, with the words matching the query
+    /// highlighted
+    pub(crate) highlight: bool,
+    /// Requested language tags, lowercased, in order of preference
+    pub(crate) languages: Vec<String>,
+    pub(crate) limit: usize,
+    pub(crate) mode: Option<SearchMode>,
+    pub(crate) offset: usize,
+    pub(crate) query: String,
 }
 
 impl ParsedUrl {
-    fn parse(url: &Url) -> Result<Self, String> {
+    fn parse(url: &Url, facet_field_names: &[String]) -> Result<Self, String> {
         let url_query: HashMap<_, _> = url.query_pairs().into_owned().collect();
         Ok(Self {
-            limit: (match url_query.get("limit") {
-                Some(limit_string) => limit_string.parse::<usize>(),
-                None => Ok(10),
-            })
-            .map_err(|err| format!("error parsing limit: {}", err))?,
-            offset: (match url_query.get("offset") {
-                Some(offset_string) => offset_string.parse::<usize>(),
-                None => Ok(0),
+            facet_filters: facet_field_names
+                .iter()
+                .map(|facet_field_name| {
+                    (
+                        facet_field_name.clone(),
+                        url.query_pairs()
+                            .filter(|(key, _)| key == facet_field_name)
+                            .map(|(_, value)| value.into_owned())
+                            .collect::<Vec<_>>(),
+                    )
+                })
+                .filter(|(_, facet_values)| !facet_values.is_empty())
+                .collect(),
+            highlight: (match url_query.get("highlight") {
+                Some(highlight_string) => highlight_string.parse::<bool>(),
+                None => Ok(false),
             })
-            .map_err(|err| format!("error parsing offset: {}", err))?,
+            .map_err(|err| format!("error parsing highlight: {}", err))?,
+            languages: parse_languages(url),
+            limit: parse_limit(&url_query)?,
+            mode: url_query
+                .get("mode")
+                .map(|mode_string| mode_string.parse::<SearchMode>())
+                .transpose()?,
+            offset: parse_offset(&url_query)?,
             query: url_query
                 .get("query")
                 .ok_or("missing query string")?
@@ -47,36 +207,190 @@ impl ParsedUrl {
     }
 }
 
-pub fn handle_request(
-    index_result_sparql: String,
-    oxigraph_store: Store,
-    request: &mut Request,
-    tantivy_index_reader: &IndexReader,
-    tantivy_query_parser: &QueryParser,
-) -> Result<Response, HttpError> {
-    if request.method().as_ref() != "GET" {
-        return Err((
-            Status::METHOD_NOT_ALLOWED,
-            format!("{} is not supported by this server", request.method()),
-        ));
+/// The `lang` parameters: requested language tags, lowercased, in order of preference.
+///
+/// Endpoints paging through concepts share the `lang`, `limit` and `offset` parameters of
+/// `/search`.
+pub(crate) fn parse_languages(url: &Url) -> Vec<String> {
+    url.query_pairs()
+        .filter(|(key, _)| key == "lang")
+        .map(|(_, value)| value.to_ascii_lowercase())
+        .collect()
+}
+
+/// The `limit` parameter, 10 by default.
+pub(crate) fn parse_limit(url_query: &HashMap<String, String>) -> Result<usize, String> {
+    (match url_query.get("limit") {
+        Some(limit_string) => limit_string.parse::<usize>(),
+        None => Ok(10),
+    })
+    .map_err(|err| format!("error parsing limit: {}", err))
+}
+
+/// The `offset` parameter, 0 by default.
+pub(crate) fn parse_offset(url_query: &HashMap<String, String>) -> Result<usize, String> {
+    (match url_query.get("offset") {
+        Some(offset_string) => offset_string.parse::<usize>(),
+        None => Ok(0),
+    })
+    .map_err(|err| format!("error parsing offset: {}", err))
+}
+
+/// Fails if a facet is named after a `/search` parameter, which its filter would clash with.
+pub(crate) fn check_facet_field_names(facet_field_names: &[String]) -> Result<(), String> {
+    match facet_field_names
+        .iter()
+        .find(|facet_field_name| SEARCH_PARAMETER_NAMES.contains(&facet_field_name.as_str()))
+    {
+        Some(facet_field_name) => Err(format!(
+            "facet {} is named after a /search parameter (reserved names are {})",
+            facet_field_name,
+            SEARCH_PARAMETER_NAMES.join(", ")
+        )),
+        None => Ok(()),
     }
+}
+
+/// Fails if a field is boosted that is not a text field of the index init query, which the boost
+/// would have no effect on.
+pub(crate) fn check_field_boosts(
+    field_boosts: &[(String, f32)],
+    text_field_names: &[String],
+) -> Result<(), String> {
+    match field_boosts
+        .iter()
+        .find(|(field_name, _)| !text_field_names.contains(field_name))
+    {
+        Some((field_name, _)) => Err(format!(
+            "field {} is boosted but is not a text variable of the index init query (text \
+             variables are {})",
+            field_name,
+            text_field_names.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Whether a language tag matches one of the languages, in the sense of SPARQL's `langMatches`.
+///
+/// Every language tag matches when no language was requested.
+pub(crate) fn language_tag_matches(language_tag: &str, languages: &[String]) -> bool {
+    let language_tag = language_tag.to_ascii_lowercase();
+    languages.is_empty()
+        || languages.iter().any(|language| {
+            language_tag == *language
+                || language_tag
+                    .strip_prefix(language.as_str())
+                    .is_some_and(|suffix| suffix.starts_with('-'))
+        })
+}
 
-    let parsed_url =
-        ParsedUrl::parse(request.url()).map_err(|err_string| (Status::BAD_REQUEST, err_string))?;
+/// Whether a term is a literal in one of the languages.
+///
+/// Terms other than language-tagged literals are in every language.
+fn is_in_languages(term: &Term, languages: &[String]) -> bool {
+    if let Term::Literal(literal) = term {
+        if let Some(language_tag) = literal.language() {
+            return language_tag_matches(language_tag, languages);
+        }
+    }
+    true
+}
 
-    let query = tantivy_query_parser
-        .parse_query(parsed_url.query.as_str())
-        .map_err(|err| (Status::BAD_REQUEST, err.to_string()))?;
+/// Searches the index for concepts matching the query and the facet filters of the URL.
+pub(crate) fn search(
+    parsed_url: &ParsedUrl,
+    tantivy_index_searcher: &Searcher,
+    search_query_parser: &SearchQueryParser,
+) -> Result<(Box<dyn Query>, Vec<IriHit>), HttpError> {
+    search_with(
+        parsed_url,
+        tantivy_index_searcher,
+        search_query_parser,
+        iri_collector(parsed_url),
+    )
+}
+
+/// The collector of every hit of a search, one per concept, in the requested languages.
+pub(crate) fn iri_collector(parsed_url: &ParsedUrl) -> IriCollector {
+    IriCollector::new("iri").with_language_preference("lang", parsed_url.languages.clone())
+}
+
+/// Searches the index like `search`, with every hit ranked in the order of a sort.
+fn ranked_search(
+    parsed_url: &ParsedUrl,
+    tantivy_index_searcher: &Searcher,
+    search_query_parser: &SearchQueryParser,
+    sort: Sort,
+) -> Result<(Box<dyn Query>, Vec<IriHit>), HttpError> {
+    let (query, mut iri_hits) = search(parsed_url, tantivy_index_searcher, search_query_parser)?;
+    sort_iri_hits(
+        tantivy_index_searcher,
+        &mut iri_hits,
+        sort,
+        &parsed_url.languages,
+    )
+    .map_err(|err| {
+        (
+            Status::INTERNAL_SERVER_ERROR,
+            format!("error sorting hits: {}", err),
+        )
+    })?;
+    Ok((query, iri_hits))
+}
+
+/// The query of a search: its text query with the facet filters of the URL.
+fn search_query(
+    parsed_url: &ParsedUrl,
+    tantivy_index_searcher: &Searcher,
+    search_query_parser: &SearchQueryParser,
+) -> Result<Box<dyn Query>, HttpError> {
+    let mut query = search_query_parser.parse_query(parsed_url.query.as_str(), parsed_url.mode)?;
+
+    if !parsed_url.facet_filters.is_empty() {
+        let schema = tantivy_index_searcher.schema();
+        let mut subqueries: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, query)];
+        for (facet_field_name, facet_values) in &parsed_url.facet_filters {
+            let facet_field = schema
+                .get_field(facet_field_name)
+                .map_err(|err| (Status::INTERNAL_SERVER_ERROR, err.to_string()))?;
+            // Filters must not change the scores of the concepts they let through
+            subqueries.push((
+                Occur::Must,
+                Box::new(ConstScoreQuery::new(
+                    Box::new(TermSetQuery::new(facet_values.iter().map(|facet_value| {
+                        tantivy::Term::from_field_text(facet_field, facet_value)
+                    }))),
+                    0.0,
+                )),
+            ));
+        }
+        query = Box::new(BooleanQuery::new(subqueries));
+    }
+    Ok(query)
+}
+
+/// Searches the index like `search`, collecting the hits with another collector (e.g., of a
+/// page of hits).
+pub(crate) fn search_with<C: Collector>(
+    parsed_url: &ParsedUrl,
+    tantivy_index_searcher: &Searcher,
+    search_query_parser: &SearchQueryParser,
+    collector: C,
+) -> Result<(Box<dyn Query>, C::Fruit), HttpError> {
+    let query = search_query(parsed_url, tantivy_index_searcher, search_query_parser)?;
 
-    let tantivy_index_searcher = tantivy_index_reader.searcher();
     if tantivy_index_searcher.num_docs() == 0 {
-        return Err((Status::INTERNAL_SERVER_ERROR, format!("index is empty")));
+        return Err((
+            Status::INTERNAL_SERVER_ERROR,
+            String::from("index is empty"),
+        ));
     }
 
     assert!(tantivy_index_searcher.num_docs() > 0);
 
-    let count = tantivy_index_searcher
-        .search(&query, &Count)
+    let fruit = tantivy_index_searcher
+        .search(&query, &collector)
         .map_err(|err| {
             (
                 Status::INTERNAL_SERVER_ERROR,
@@ -86,137 +400,2070 @@ pub fn handle_request(
                 ),
             )
         })?;
+    Ok((query, fruit))
+}
 
-    if parsed_url.limit == 0 {
-        return Ok(Response::builder(Status::NO_CONTENT)
-            .with_header("X-Total-Count", count.to_string())
-            .unwrap()
-            .build());
+/// Searchers of the generations of the index cursors were issued for, kept alive so that
+/// paging through hits with cursors sees the index as it was on the first page, with the ranked
+/// hits of the searches paged through, so that a page after a cursor is a slice of them.
+///
+/// A generation is kept until no cursor of it was used for the time to live, however many
+/// generations follow it. Rankings are rebuilt when they were dropped to make room for others.
+pub struct SearcherCache {
+    generations: Mutex<Vec<CursorGeneration>>,
+    time_to_live: Duration,
+}
+
+struct CursorGeneration {
+    searcher: Searcher,
+    last_used: Instant,
+    /// Hits of searches, by `search_hash`, in the order of their pages, with their last use
+    rankings: HashMap<u64, (Arc<Vec<IriHit>>, Instant)>,
+}
+
+impl Default for SearcherCache {
+    fn default() -> Self {
+        Self::new(CURSOR_TIME_TO_LIVE)
+    }
+}
+
+impl SearcherCache {
+    pub fn new(time_to_live: Duration) -> Self {
+        Self {
+            generations: Mutex::new(Vec::new()),
+            time_to_live,
+        }
+    }
+
+    /// The generations used within the time to live.
+    fn generations(&self) -> MutexGuard<'_, Vec<CursorGeneration>> {
+        let mut generations = self
+            .generations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        generations.retain(|generation| generation.last_used.elapsed() <= self.time_to_live);
+        generations
+    }
+
+    /// Keeps the generation of a searcher for the pages following a cursor issued in it.
+    pub fn keep(&self, tantivy_index_searcher: &Searcher) {
+        let generation_id = tantivy_index_searcher.generation().generation_id();
+        let mut generations = self.generations();
+        match generations
+            .iter_mut()
+            .find(|generation| generation.searcher.generation().generation_id() == generation_id)
+        {
+            Some(generation) => generation.last_used = Instant::now(),
+            None => generations.push(CursorGeneration {
+                searcher: tantivy_index_searcher.clone(),
+                last_used: Instant::now(),
+                rankings: HashMap::new(),
+            }),
+        }
+    }
+
+    /// The searcher of a generation of the index, if it is still kept.
+    pub fn get(&self, generation_id: u64) -> Option<Searcher> {
+        let mut generations = self.generations();
+        let generation = generations
+            .iter_mut()
+            .find(|generation| generation.searcher.generation().generation_id() == generation_id)?;
+        generation.last_used = Instant::now();
+        Some(generation.searcher.clone())
+    }
+
+    /// The ranked hits of a search in a generation of the index, if they are still kept.
+    fn ranking(&self, generation_id: u64, search_hash: u64) -> Option<Arc<Vec<IriHit>>> {
+        let mut generations = self.generations();
+        let (iri_hits, last_used) = generations
+            .iter_mut()
+            .find(|generation| generation.searcher.generation().generation_id() == generation_id)?
+            .rankings
+            .get_mut(&search_hash)?;
+        *last_used = Instant::now();
+        Some(Arc::clone(iri_hits))
+    }
+
+    /// Keeps the ranked hits of a search in a kept generation of the index, dropping the least
+    /// recently used ranking beyond `MAX_CURSOR_RANKINGS`.
+    fn keep_ranking(&self, generation_id: u64, search_hash: u64, iri_hits: Arc<Vec<IriHit>>) {
+        let mut generations = self.generations();
+        let Some(generation) = generations
+            .iter_mut()
+            .find(|generation| generation.searcher.generation().generation_id() == generation_id)
+        else {
+            return;
+        };
+        generation
+            .rankings
+            .insert(search_hash, (iri_hits, Instant::now()));
+        let ranking_count = generations
+            .iter()
+            .map(|generation| generation.rankings.len())
+            .sum::<usize>();
+        if ranking_count > MAX_CURSOR_RANKINGS {
+            let least_recently_used = generations
+                .iter()
+                .enumerate()
+                .flat_map(|(generation_index, generation)| {
+                    generation
+                        .rankings
+                        .iter()
+                        .map(move |(search_hash, (_, last_used))| {
+                            (*last_used, generation_index, *search_hash)
+                        })
+                })
+                .min();
+            if let Some((_, generation_index, search_hash)) = least_recently_used {
+                generations[generation_index].rankings.remove(&search_hash);
+            }
+        }
+    }
+}
+
+/// Position of a page of hits after the last hit of the previous page: its rank and the
+/// address of its best-matching document, in a generation of the index, for a search.
+///
+/// Cursors are opaque to clients: they are encoded as hexadecimal.
+struct Cursor {
+    doc_address: DocAddress,
+    generation_id: u64,
+    /// `search_hash` of the search the cursor pages through
+    search_hash: u64,
+    /// Number of hits ranked before the page, the last of them the hit the cursor is at
+    start: usize,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        format!(
+            "{:016x}{:016x}{:016x}{:08x}{:08x}",
+            self.generation_id,
+            self.search_hash,
+            self.start,
+            self.doc_address.segment_ord,
+            self.doc_address.doc_id
+        )
+    }
+
+    fn decode(cursor_string: &str) -> Result<Self, String> {
+        let invalid_cursor = || format!("invalid cursor {}", cursor_string);
+        if cursor_string.len() != 64 || !cursor_string.is_ascii() {
+            return Err(invalid_cursor());
+        }
+        let field = |range: std::ops::Range<usize>| {
+            u64::from_str_radix(&cursor_string[range], 16).map_err(|_| invalid_cursor())
+        };
+        Ok(Self {
+            doc_address: DocAddress::new(field(48..56)? as u32, field(56..64)? as u32),
+            generation_id: field(0..16)?,
+            search_hash: field(16..32)?,
+            start: usize::try_from(field(32..48)?).map_err(|_| invalid_cursor())?,
+        })
+    }
+
+    /// The hits of the page after the cursor, in the ranked hits of its search.
+    fn page<'a>(&self, iri_hits: &'a [IriHit], limit: usize) -> Result<&'a [IriHit], HttpError> {
+        // The cursor is at the hit before the page
+        let is_at_hit = self
+            .start
+            .checked_sub(1)
+            .and_then(|index| iri_hits.get(index))
+            .is_some_and(|iri_hit| iri_hit.doc_address == self.doc_address);
+        if !is_at_hit {
+            return Err((Status::BAD_REQUEST, String::from("invalid cursor")));
+        }
+        Ok(&iri_hits[self.start..iri_hits.len().min(self.start.saturating_add(limit))])
+    }
+}
+
+/// Hash of the parameters of a search that select and order its hits, so that a cursor only
+/// pages through the search it was issued for.
+///
+/// Parameters are ordered by name, keeping the order of the values of a parameter (e.g., of
+/// `lang`, in order of preference).
+fn search_hash(url: &Url) -> u64 {
+    let mut pairs = url
+        .query_pairs()
+        .filter(|(key, _)| {
+            !matches!(
+                key.as_ref(),
+                "cursor" | "highlight" | "limit" | "offset" | "timeout"
+            )
+        })
+        .collect::<Vec<_>>();
+    pairs.sort_by(|(left_key, _), (right_key, _)| left_key.cmp(right_key));
+    let mut hasher = Sha256::new();
+    for (key, value) in pairs {
+        hasher.update(key.as_bytes());
+        hasher.update([0]);
+        hasher.update(value.as_bytes());
+        hasher.update([0]);
+    }
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&hasher.finalize()[..8]);
+    u64::from_be_bytes(hash)
+}
+
+/// What `/search` hits can be sorted by.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SortKey {
+    /// The `sortLabel` of the concept in the first requested language it has one in, collated by
+    /// that language
+    Label,
+    /// The first `notation` of the concept
+    Notation,
+    Score,
+}
+
+/// Order of `/search` hits: by `label`, `notation` or `score`, ascending, or descending when
+/// prefixed with `-`.
+///
+/// Concepts without a label or notation come last, and ties are ordered by descending score,
+/// then by IRI.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Sort {
+    descending: bool,
+    key: SortKey,
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Self {
+            descending: true,
+            key: SortKey::Score,
+        }
+    }
+}
+
+impl FromStr for Sort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (descending, key_string) = match s.strip_prefix('-') {
+            Some(key_string) => (true, key_string),
+            None => (false, s),
+        };
+        let key = match key_string {
+            "label" => SortKey::Label,
+            "notation" => SortKey::Notation,
+            "score" => SortKey::Score,
+            _ => {
+                return Err(format!(
+                    "unknown sort {} (expected [-]label, [-]notation or [-]score)",
+                    s
+                ))
+            }
+        };
+        Ok(Self { descending, key })
+    }
+}
+
+/// The label a concept sorts by: its label in the first requested language it has one in, else
+/// its untagged label, else its first label in collation order when no language was requested.
+fn label_sort_key(
+    sort_labels: Vec<String>,
+    languages: &[String],
+    collator: &Collator,
+) -> Option<String> {
+    let sort_labels = sort_labels
+        .iter()
+        .filter_map(|sort_label| sort_label.split_once(SORT_LABEL_LANG_SEPARATOR))
+        .collect::<Vec<_>>();
+    languages
+        .iter()
+        .find_map(|language| {
+            sort_labels.iter().find(|(lang, _)| {
+                !lang.is_empty() && language_tag_matches(lang, std::slice::from_ref(language))
+            })
+        })
+        .or_else(|| sort_labels.iter().find(|(lang, _)| lang.is_empty()))
+        .or_else(|| {
+            if languages.is_empty() {
+                sort_labels
+                    .iter()
+                    .min_by(|(_, left), (_, right)| collator.compare(left, right))
+            } else {
+                None
+            }
+        })
+        .map(|(_, label)| String::from(*label))
+}
+
+/// Sorts hits, sorted by descending score then IRI, in another order.
+///
+/// Like facet values, the sort values of a concept are read from the fast fields of its
+/// best-matching document. Labels are compared with the collator of the first requested
+/// language, or with the root collation when no language was requested; notations are compared
+/// code point by code point.
+fn sort_iri_hits(
+    tantivy_index_searcher: &Searcher,
+    iri_hits: &mut Vec<IriHit>,
+    sort: Sort,
+    languages: &[String],
+) -> tantivy::Result<()> {
+    let field_name = match sort.key {
+        SortKey::Label => "sortLabel",
+        SortKey::Notation => "notation",
+        SortKey::Score => {
+            if !sort.descending {
+                iri_hits.sort_by(|left, right| {
+                    left.score
+                        .total_cmp(&right.score)
+                        .then_with(|| left.iri.cmp(&right.iri))
+                });
+            }
+            return Ok(());
+        }
+    };
+
+    let collator = analysis::collator(languages.first().map(String::as_str));
+    let mut columns_by_segment_ord = HashMap::new();
+    let mut value = String::new();
+    let mut sorted_iri_hits = Vec::with_capacity(iri_hits.len());
+    for iri_hit in iri_hits.drain(..) {
+        let segment_ord = iri_hit.doc_address.segment_ord;
+        let column = match columns_by_segment_ord.entry(segment_ord) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            // A segment without any value of the field has no column for it
+            Entry::Vacant(entry) => entry.insert(
+                tantivy_index_searcher
+                    .segment_reader(segment_ord)
+                    .fast_fields()
+                    .str(field_name)?,
+            ),
+        };
+        let mut values = Vec::new();
+        if let Some(column) = column {
+            for value_ord in column.term_ords(iri_hit.doc_address.doc_id) {
+                value.clear();
+                if column.ord_to_str(value_ord, &mut value)? {
+                    values.push(value.clone());
+                }
+            }
+        }
+        let sort_value = match sort.key {
+            SortKey::Label => label_sort_key(values, languages, &collator),
+            _ => values.into_iter().next(),
+        };
+        sorted_iri_hits.push((sort_value, iri_hit));
     }
 
-    let top_docs = tantivy_index_searcher
-        .search(
-            &query,
-            &TopDocs::with_limit(parsed_url.limit).and_offset(parsed_url.offset),
+    let compare_values = |left_value: &String, right_value: &String| match sort.key {
+        SortKey::Label => collator.compare(left_value, right_value),
+        _ => left_value.cmp(right_value),
+    };
+    sorted_iri_hits.sort_by(|(left_value, left), (right_value, right)| {
+        match (left_value, right_value) {
+            (Some(left_value), Some(right_value)) if sort.descending => {
+                compare_values(right_value, left_value)
+            }
+            (Some(left_value), Some(right_value)) => compare_values(left_value, right_value),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+        .then_with(|| right.score.total_cmp(&left.score))
+        .then_with(|| left.iri.cmp(&right.iri))
+    });
+    iri_hits.extend(sorted_iri_hits.into_iter().map(|(_, iri_hit)| iri_hit));
+    Ok(())
+}
+
+/// Counts the concepts having each value of each facet.
+///
+/// Every document of a concept has all of its facet values, so the values of a concept are read
+/// from the fast fields of its best-matching document.
+fn count_facets(
+    tantivy_index_searcher: &Searcher,
+    iri_hits: &[IriHit],
+    facet_field_names: &[String],
+) -> tantivy::Result<BTreeMap<String, BTreeMap<String, usize>>> {
+    let mut facet_counts = BTreeMap::new();
+    for facet_field_name in facet_field_names {
+        let mut counts_by_value: BTreeMap<String, usize> = BTreeMap::new();
+        let mut facet_value = String::new();
+        for iri_hit in iri_hits {
+            let segment_reader =
+                tantivy_index_searcher.segment_reader(iri_hit.doc_address.segment_ord);
+            // A segment without any value of the facet has no column for it
+            if let Some(facet_column) = segment_reader.fast_fields().str(facet_field_name)? {
+                for facet_value_ord in facet_column.term_ords(iri_hit.doc_address.doc_id) {
+                    facet_value.clear();
+                    if facet_column.ord_to_str(facet_value_ord, &mut facet_value)? {
+                        *counts_by_value.entry(facet_value.clone()).or_default() += 1;
+                    }
+                }
+            }
+        }
+        facet_counts.insert(facet_field_name.clone(), counts_by_value);
+    }
+    Ok(facet_counts)
+}
+
+/// Answers the facet counts of the search in the URL as a JSON object mapping each facet field
+/// name to an array of `{"value", "count"}` objects, most frequent value first.
+pub fn handle_facets_request(
+    request: &mut Request,
+    tantivy_index_reader: &IndexReader,
+    search_query_parser: &SearchQueryParser,
+    facet_field_names: &[String],
+) -> Result<Response, HttpError> {
+    if request.method().as_ref() != "GET" {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    let parsed_url = ParsedUrl::parse(request.url(), facet_field_names)
+        .map_err(|err_string| (Status::BAD_REQUEST, err_string))?;
+
+    let tantivy_index_searcher = tantivy_index_reader.searcher();
+    let (_, iri_hits) = search(&parsed_url, &tantivy_index_searcher, search_query_parser)?;
+
+    let facet_counts = count_facets(&tantivy_index_searcher, &iri_hits, facet_field_names)
+        .map_err(|err| {
+            (
+                Status::INTERNAL_SERVER_ERROR,
+                format!("error counting facets: {}", err),
+            )
+        })?;
+    let mut facets = serde_json::Map::new();
+    for (facet_field_name, counts_by_value) in facet_counts {
+        let mut counts = counts_by_value.into_iter().collect::<Vec<_>>();
+        counts.sort_by(|left, right| right.1.cmp(&left.1).then_with(|| left.0.cmp(&right.0)));
+        facets.insert(
+            facet_field_name,
+            counts
+                .into_iter()
+                .map(|(value, count)| json!({ "value": value, "count": count }))
+                .collect(),
+        );
+    }
+
+    Ok(Response::builder(Status::OK)
+        .with_header(HeaderName::CONTENT_TYPE, "application/json")
+        .unwrap()
+        .with_header("X-Total-Count", iri_hits.len().to_string())
+        .unwrap()
+        .with_body(serde_json::Value::Object(facets).to_string()))
+}
+
+/// Formats `/search` answers in: the RDF graph of the index result query, JSON hits, or SPARQL
+/// JSON results.
+enum SearchResultsFormat {
+    Graph(GraphFormat),
+    Json,
+    SparqlResultsJson,
+}
+
+fn search_results_content_negotiation(request: &Request) -> Result<SearchResultsFormat, HttpError> {
+    content_negotiation(
+        request,
+        &[
+            GraphFormat::NTriples.media_type(),
+            GraphFormat::Turtle.media_type(),
+            GraphFormat::RdfXml.media_type(),
+            "application/json",
+            QueryResultsFormat::Json.media_type(),
+        ],
+        |media_type| {
+            if media_type == "application/json" {
+                Some(SearchResultsFormat::Json)
+            } else if media_type == QueryResultsFormat::Json.media_type() {
+                Some(SearchResultsFormat::SparqlResultsJson)
+            } else {
+                GraphFormat::from_media_type(media_type).map(SearchResultsFormat::Graph)
+            }
+        },
+    )
+}
+
+/// The text of a hit's best-matching document.
+struct MatchedText {
+    lang: Option<String>,
+    /// HTML of the text with the words matching the query highlighted, if any word was
+    snippet: Option<String>,
+    text: String,
+}
+
+/// Reads the matched text of a hit from the stored text fields of its best-matching document.
+///
+/// Snippet generators are created on first use of a field, and only highlight the terms of the
+/// exact part of the query: fuzzy matches have no terms of their own.
+fn matched_text(
+    tantivy_index_searcher: &Searcher,
+    query: &dyn Query,
+    iri_hit: &IriHit,
+    text_fields: &[Field],
+    snippet_generators: &mut HashMap<Field, SnippetGenerator>,
+) -> tantivy::Result<Option<MatchedText>> {
+    let document: TantivyDocument = tantivy_index_searcher.doc(iri_hit.doc_address)?;
+    let lang_field = tantivy_index_searcher.schema().get_field("lang")?;
+    for text_field in text_fields {
+        // Each document holds a single text, in a single field
+        if let Some(text) = document
+            .get_first(*text_field)
+            .and_then(|value| value.as_str())
+        {
+            let snippet_generator = match snippet_generators.entry(*text_field) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(SnippetGenerator::create(
+                    tantivy_index_searcher,
+                    query,
+                    *text_field,
+                )?),
+            };
+            let snippet = snippet_generator.snippet(text);
+            return Ok(Some(MatchedText {
+                // The full language tag comes before its prefixes
+                lang: document
+                    .get_first(lang_field)
+                    .and_then(|value| value.as_str())
+                    .map(String::from),
+                snippet: (!snippet.is_empty()).then(|| snippet.to_html()),
+                text: String::from(text),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Options of the index result query: `kos:inRequestedLanguage` keeps the literals in the
+/// requested languages.
+pub fn index_result_query_options(languages: Vec<String>) -> QueryOptions {
+    QueryOptions::default().with_custom_function(
+        kos::IN_REQUESTED_LANGUAGE.into_owned(),
+        move |args| match args {
+            [term] => Some(Literal::from(is_in_languages(term, &languages)).into()),
+            _ => None,
+        },
+    )
+}
+
+/// Evaluates the index result query for resources, in the requested languages.
+///
+/// Every IRI is bound in a single VALUES clause, so that the results of a page are constructed
+/// by one query evaluation.
+pub(crate) fn index_result_triples<'a>(
+    oxigraph_store: &Store,
+    index_result_sparql: &str,
+    iris: impl Iterator<Item = &'a str>,
+    languages: Vec<String>,
+) -> Result<QueryTripleIter, HttpError> {
+    let index_result_sparql_with_values = with_iri_values(index_result_sparql, iris);
+    match oxigraph_store
+        .query_opt(
+            union_default_graph_query(&index_result_sparql_with_values).map_err(|err| {
+                (
+                    Status::INTERNAL_SERVER_ERROR,
+                    format!(
+                        "error parsing index result query:\nQuery:\n{}\nError:\n{}",
+                        index_result_sparql_with_values, err
+                    ),
+                )
+            })?,
+            index_result_query_options(languages),
         )
         .map_err(|err| {
             (
                 Status::INTERNAL_SERVER_ERROR,
                 format!(
-                    "error searching index:\nQuery: {}\nError: {}",
-                    parsed_url.query, err
+                    "error executing index result query:\nQuery:\n{}\nError:\n{}",
+                    index_result_sparql_with_values, err
                 ),
             )
+        })? {
+        QueryResults::Graph(index_result_triples) => Ok(index_result_triples),
+        _ => Err((
+            Status::INTERNAL_SERVER_ERROR,
+            String::from("index result query did not return a graph (is it a CONSTRUCT query?)"),
+        )),
+    }
+}
+
+/// Variable bound to the position of `?iri` among the resources of the index result query.
+const IRI_POSITION_VARIABLE: &str = "kosIriPosition";
+
+/// Evaluates the index result query for resources, in the requested languages, grouping its
+/// triples by resource: each item is the position of a resource among `iris` and the triples
+/// constructed from one of its solutions, in the order of `iris`.
+///
+/// The CONSTRUCT query is evaluated as a SELECT query of its solutions ordered by the position
+/// of their `?iri` in the VALUES clause, and its template is instantiated for each solution, with
+/// fresh blank nodes.
+pub(crate) fn ordered_index_result_triples<'a>(
+    oxigraph_store: &Store,
+    index_result_sparql: &str,
+    iris: impl Iterator<Item = &'a str>,
+    languages: Vec<String>,
+) -> Result<ResultIter<(usize, Vec<Triple>)>, HttpError> {
+    let mut index_result_sparql_with_values = format!(
+        "{}\nVALUES (?iri ?{}) {{",
+        index_result_sparql, IRI_POSITION_VARIABLE
+    );
+    for (position, iri) in iris.enumerate() {
+        index_result_sparql_with_values.push_str(&format!(" (<{}> {})", iri, position));
+    }
+    index_result_sparql_with_values.push_str(" }");
+    let query_error = |action: &str, err: &dyn fmt::Display| {
+        (
+            Status::INTERNAL_SERVER_ERROR,
+            format!(
+                "error {} index result query:\nQuery:\n{}\nError:\n{}",
+                action, index_result_sparql_with_values, err
+            ),
+        )
+    };
+    let (template, select_query) =
+        match spargebra::Query::parse(&index_result_sparql_with_values, None)
+            .map_err(|err| query_error("parsing", &err))?
+        {
+            spargebra::Query::Construct {
+                template,
+                dataset,
+                pattern,
+                base_iri,
+            } => (
+                template,
+                spargebra::Query::Select {
+                    dataset,
+                    pattern: GraphPattern::OrderBy {
+                        inner: Box::new(pattern),
+                        expression: vec![OrderExpression::Asc(Expression::Variable(
+                            Variable::new_unchecked(IRI_POSITION_VARIABLE),
+                        ))],
+                    },
+                    base_iri,
+                },
+            ),
+            _ => {
+                return Err((
+                    Status::INTERNAL_SERVER_ERROR,
+                    String::from(
+                        "index result query did not return a graph (is it a CONSTRUCT query?)",
+                    ),
+                ))
+            }
+        };
+    let mut select_query = SparqlQuery::from(select_query);
+    select_query.dataset_mut().set_default_graph_as_union();
+    let QueryResults::Solutions(solutions) = oxigraph_store
+        .query_opt(select_query, index_result_query_options(languages))
+        .map_err(|err| query_error("executing", &err))?
+    else {
+        unreachable!("SELECT queries return solutions")
+    };
+    Ok(Box::new(solutions.map(move |solution| {
+        let solution = solution?;
+        let position = match solution.get(IRI_POSITION_VARIABLE) {
+            Some(Term::Literal(position)) => position.value().parse::<usize>().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "index result solution without the position of its ?iri",
+            )
         })?;
+        // Blank nodes of the template are fresh for each solution
+        let mut blank_nodes = HashMap::new();
+        Ok((
+            position,
+            template
+                .iter()
+                .filter_map(|triple_pattern| {
+                    instantiate_triple_pattern(triple_pattern, &solution, &mut blank_nodes)
+                })
+                .collect(),
+        ))
+    })))
+}
 
-    let iri_field = tantivy_index_searcher
-        .schema()
-        .get_field("iri")
-        .map_err(|err| {
+/// The triple of a CONSTRUCT template for a solution, unless one of its variables is unbound or
+/// bound to a term that can't be in its position.
+fn instantiate_triple_pattern(
+    triple_pattern: &TriplePattern,
+    solution: &QuerySolution,
+    blank_nodes: &mut HashMap<BlankNode, BlankNode>,
+) -> Option<Triple> {
+    let subject = match instantiate_term_pattern(&triple_pattern.subject, solution, blank_nodes)? {
+        Term::NamedNode(named_node) => Subject::NamedNode(named_node),
+        Term::BlankNode(blank_node) => Subject::BlankNode(blank_node),
+        Term::Triple(triple) => Subject::Triple(triple),
+        Term::Literal(_) => return None,
+    };
+    let predicate = match &triple_pattern.predicate {
+        NamedNodePattern::NamedNode(named_node) => named_node.clone(),
+        NamedNodePattern::Variable(variable) => match solution.get(variable)? {
+            Term::NamedNode(named_node) => named_node.clone(),
+            _ => return None,
+        },
+    };
+    let object = instantiate_term_pattern(&triple_pattern.object, solution, blank_nodes)?;
+    Some(Triple::new(subject, predicate, object))
+}
+
+fn instantiate_term_pattern(
+    term_pattern: &TermPattern,
+    solution: &QuerySolution,
+    blank_nodes: &mut HashMap<BlankNode, BlankNode>,
+) -> Option<Term> {
+    match term_pattern {
+        TermPattern::NamedNode(named_node) => Some(named_node.clone().into()),
+        TermPattern::BlankNode(blank_node) => Some(
+            blank_nodes
+                .entry(blank_node.clone())
+                .or_default()
+                .clone()
+                .into(),
+        ),
+        TermPattern::Literal(literal) => Some(literal.clone().into()),
+        TermPattern::Triple(triple_pattern) => {
+            instantiate_triple_pattern(triple_pattern, solution, blank_nodes).map(Term::from)
+        }
+        TermPattern::Variable(variable) => solution.get(variable).cloned(),
+    }
+}
+
+pub(crate) const SKOS_PREF_LABEL: NamedNodeRef<'_> =
+    NamedNodeRef::new_unchecked("http://www.w3.org/2004/02/skos/core#prefLabel");
+
+/// The labels and types of a resource, from the index result query.
+#[derive(Default)]
+pub(crate) struct Description {
+    pub(crate) pref_labels: Vec<Literal>,
+    pub(crate) labels: Vec<Literal>,
+    pub(crate) types: Vec<String>,
+}
+
+impl Description {
+    /// The preferred label of the resource, or else its first label.
+    pub(crate) fn label(&self) -> Option<&Literal> {
+        self.pref_labels.first().or_else(|| self.labels.first())
+    }
+
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.label().map(Literal::value)
+    }
+
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.pref_labels
+            .iter()
+            .chain(self.labels.iter())
+            .map(Literal::value)
+    }
+}
+
+/// Describes resources with the `skos:prefLabel`, `rdfs:label` and `rdf:type` triples the index
+/// result query constructs for them.
+pub(crate) fn describe<'a>(
+    oxigraph_store: &Store,
+    index_result_sparql: &str,
+    iris: impl Iterator<Item = &'a str>,
+    languages: Vec<String>,
+) -> Result<HashMap<String, Description>, HttpError> {
+    let mut descriptions: HashMap<String, Description> = HashMap::new();
+    // CONSTRUCT templates can produce the same triple from several solutions
+    let mut seen_triples = HashSet::new();
+    for triple in index_result_triples(oxigraph_store, index_result_sparql, iris, languages)? {
+        let triple = triple.map_err(|err| {
             (
                 Status::INTERNAL_SERVER_ERROR,
-                format!("error getting IRI field from index: {}", err),
+                format!("error executing index result query: {}", err),
             )
         })?;
+        if !seen_triples.insert(triple.clone()) {
+            continue;
+        }
+        let Subject::NamedNode(subject) = triple.subject else {
+            continue;
+        };
+        let description = descriptions
+            .entry(String::from(subject.as_str()))
+            .or_default();
+        match triple.object {
+            Term::Literal(literal) if triple.predicate == SKOS_PREF_LABEL => {
+                description.pref_labels.push(literal)
+            }
+            Term::Literal(literal) if triple.predicate == rdfs::LABEL => {
+                description.labels.push(literal)
+            }
+            Term::NamedNode(type_iri) if triple.predicate == rdf::TYPE => {
+                description.types.push(type_iri.into_string())
+            }
+            _ => {}
+        }
+    }
+    Ok(descriptions)
+}
+
+/// Answers the hits of a search, negotiated as the RDF graph of the index result query, as JSON
+/// hits with their labels and types (`application/json`), or as SPARQL JSON results
+/// (`application/sparql-results+json`).
+///
+/// Hits are ordered by descending score, or by `sort`. Pages are selected with `offset`, or with
+/// the `cursor` of the `Link` to the next page, which stays consistent while the index is
+/// updated: a cursor pages through the index as it was when the search started, as long as the
+/// server keeps that generation of the index. A cursor is rejected by searches with other
+/// parameters than the one it was issued for, except `highlight`, `limit` and `timeout`.
+/// The hits of a search are ranked once, for the first page after a cursor: the following pages
+/// are read from the ranking while the server keeps it.
+///
+/// The timeout is checked between the search, the highlighting and the index result query, and
+/// between the solutions of the index result query: a search past its timeout is answered with a
+/// 503 status once the running step is done, as none of the steps can be interrupted, and graph
+/// hits already being streamed stop at the solution past the timeout.
+#[allow(clippy::too_many_arguments)]
+pub fn handle_request(
+    index_result_sparql: String,
+    oxigraph_store: Store,
+    request: &mut Request,
+    tantivy_index_reader: &IndexReader,
+    search_query_parser: &SearchQueryParser,
+    searcher_cache: &SearcherCache,
+    facet_field_names: &[String],
+    query_timeouts: &QueryTimeouts,
+) -> Result<Response, HttpError> {
+    if request.method().as_ref() != "GET" {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    let deadline = query_timeouts.deadline(&[url_query(request)])?;
+
+    let parsed_url = ParsedUrl::parse(request.url(), facet_field_names)
+        .map_err(|err_string| (Status::BAD_REQUEST, err_string))?;
+
+    let cursor = request
+        .url()
+        .query_pairs()
+        .find(|(key, _)| key == "cursor")
+        .map(|(_, cursor_string)| Cursor::decode(&cursor_string))
+        .transpose()
+        .map_err(|err_string| (Status::BAD_REQUEST, err_string))?;
+    let sort = request
+        .url()
+        .query_pairs()
+        .find(|(key, _)| key == "sort")
+        .map(|(_, sort_string)| sort_string.parse::<Sort>())
+        .transpose()
+        .map_err(|err_string| (Status::BAD_REQUEST, err_string))?
+        .unwrap_or_default();
+    if cursor.is_some() && request.url().query_pairs().any(|(key, _)| key == "offset") {
+        return Err((
+            Status::BAD_REQUEST,
+            String::from("cursor and offset cannot be combined"),
+        ));
+    }
+
+    let format = search_results_content_negotiation(request)?;
+
+    // Later pages search the generation of the index the first page was searched in
+    let tantivy_index_searcher = match &cursor {
+        Some(cursor) => searcher_cache.get(cursor.generation_id).ok_or_else(|| {
+            (
+                Status::GONE,
+                String::from("the index changed since the cursor was issued: search again"),
+            )
+        })?,
+        None => tantivy_index_reader.searcher(),
+    };
+    let search_hash = search_hash(request.url());
+    if cursor
+        .as_ref()
+        .is_some_and(|cursor| cursor.search_hash != search_hash)
+    {
+        return Err((
+            Status::BAD_REQUEST,
+            String::from("the cursor was issued for another search"),
+        ));
+    }
 
-    let index_results_oxigraph_store = Store::new().map_err(|err| {
+    // The hits of the page, the number of hits before it, and the number of hits
+    let (query, iri_hits, start, count) = match &cursor {
+        // Pages after a cursor are slices of the hits of their search, ranked once
+        Some(cursor) => {
+            let ranked_iri_hits = match searcher_cache.ranking(cursor.generation_id, search_hash) {
+                Some(ranked_iri_hits) => ranked_iri_hits,
+                None => {
+                    let (_, iri_hits) = ranked_search(
+                        &parsed_url,
+                        &tantivy_index_searcher,
+                        search_query_parser,
+                        sort,
+                    )?;
+                    let ranked_iri_hits = Arc::new(iri_hits);
+                    searcher_cache.keep_ranking(
+                        cursor.generation_id,
+                        search_hash,
+                        Arc::clone(&ranked_iri_hits),
+                    );
+                    ranked_iri_hits
+                }
+            };
+            let query = search_query(&parsed_url, &tantivy_index_searcher, search_query_parser)?;
+            let iri_hits = cursor.page(&ranked_iri_hits, parsed_url.limit)?.to_vec();
+            (query, iri_hits, cursor.start, ranked_iri_hits.len())
+        }
+        // Only the hits of the page are sorted
+        None if sort == Sort::default() => {
+            let (query, iri_page) = search_with(
+                &parsed_url,
+                &tantivy_index_searcher,
+                search_query_parser,
+                IriPageCollector::new(
+                    iri_collector(&parsed_url),
+                    parsed_url.offset.saturating_add(parsed_url.limit),
+                ),
+            )?;
+            let iri_hits = iri_page
+                .hits
+                .into_iter()
+                .skip(parsed_url.offset)
+                .collect::<Vec<_>>();
+            (query, iri_hits, parsed_url.offset, iri_page.count)
+        }
+        None => {
+            let (query, iri_hits) = ranked_search(
+                &parsed_url,
+                &tantivy_index_searcher,
+                search_query_parser,
+                sort,
+            )?;
+            let count = iri_hits.len();
+            let iri_hits = iri_hits
+                .into_iter()
+                .skip(parsed_url.offset)
+                .take(parsed_url.limit)
+                .collect::<Vec<_>>();
+            (query, iri_hits, parsed_url.offset, count)
+        }
+    };
+    deadline.check()?;
+
+    // The facet counts of the same search
+    let mut links = vec![format!(
+        "</search/facets?{}>; rel=\"{}\"",
+        request.url().query().unwrap_or_default(),
+        kos::FACETS.as_str()
+    )];
+
+    if parsed_url.limit == 0 {
+        return Ok(Response::builder(Status::NO_CONTENT)
+            .with_header("X-Total-Count", count.to_string())
+            .unwrap()
+            .with_header("Link", links.join(", "))
+            .unwrap()
+            .build());
+    }
+
+    // The next page starts after the last hit of this one
+    if start.saturating_add(parsed_url.limit) < count {
+        if let Some(last_iri_hit) = iri_hits.last() {
+            searcher_cache.keep(&tantivy_index_searcher);
+            let next_cursor = Cursor {
+                doc_address: last_iri_hit.doc_address,
+                generation_id: tantivy_index_searcher.generation().generation_id(),
+                search_hash,
+                start: start + iri_hits.len(),
+            };
+            let next_query = form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(
+                    request
+                        .url()
+                        .query_pairs()
+                        .filter(|(key, _)| key != "cursor" && key != "offset"),
+                )
+                .append_pair("cursor", &next_cursor.encode())
+                .finish();
+            links.push(format!("</search?{}>; rel=\"next\"", next_query));
+        }
+    }
+
+    // The hits of the page with their 0-based rank, and their matched text if requested
+    let mut page_hits: Vec<(usize, &IriHit, Option<MatchedText>)> = Vec::new();
+    let text_fields = search_query_parser.text_fields();
+    let mut snippet_generators = HashMap::new();
+    for (rank_index, iri_hit) in (start..).zip(&iri_hits) {
+        let iri_hit_matched_text = if parsed_url.highlight {
+            matched_text(
+                &tantivy_index_searcher,
+                query.as_ref(),
+                iri_hit,
+                &text_fields,
+                &mut snippet_generators,
+            )
+            .map_err(|err| {
+                (
+                    Status::INTERNAL_SERVER_ERROR,
+                    format!("error highlighting matched text: {}", err),
+                )
+            })?
+        } else {
+            None
+        };
+        page_hits.push((rank_index, iri_hit, iri_hit_matched_text));
+    }
+    deadline.check()?;
+
+    let (content_type, body) = match format {
+        SearchResultsFormat::Graph(format) => {
+            return graph_search_results_response(
+                &oxigraph_store,
+                &index_result_sparql,
+                page_hits,
+                parsed_url.languages,
+                format,
+                deadline,
+            )
+            .map(|mut response| {
+                response
+                    .append_header("X-Total-Count", count.to_string())
+                    .unwrap();
+                response.append_header("Link", links.join(", ")).unwrap();
+                response
+            })
+        }
+        SearchResultsFormat::Json => {
+            let descriptions = describe(
+                &oxigraph_store,
+                &index_result_sparql,
+                page_hits.iter().map(|(_, iri_hit, _)| iri_hit.iri.as_str()),
+                parsed_url.languages,
+            )?;
+            deadline.check()?;
+            (
+                "application/json",
+                json_search_results(page_hits, &descriptions)
+                    .to_string()
+                    .into_bytes(),
+            )
+        }
+        SearchResultsFormat::SparqlResultsJson => {
+            let descriptions = describe(
+                &oxigraph_store,
+                &index_result_sparql,
+                page_hits.iter().map(|(_, iri_hit, _)| iri_hit.iri.as_str()),
+                parsed_url.languages,
+            )?;
+            deadline.check()?;
+            (
+                QueryResultsFormat::Json.media_type(),
+                sparql_search_results(page_hits, &descriptions, parsed_url.highlight)?,
+            )
+        }
+    };
+    Ok(Response::builder(Status::OK)
+        .with_header(HeaderName::CONTENT_TYPE, content_type)
+        .unwrap()
+        .with_header("X-Total-Count", count.to_string())
+        .unwrap()
+        .with_header("Link", links.join(", "))
+        .unwrap()
+        .with_body(body))
+}
+
+/// The hits of a page as a JSON array of `{"iri", "rank", "score", "prefLabels", "labels",
+/// "types"}` objects, labels being `{"value", "lang"}` objects, with a `matchedText` object
+/// when highlighting.
+fn json_search_results(
+    page_hits: Vec<(usize, &IriHit, Option<MatchedText>)>,
+    descriptions: &HashMap<String, Description>,
+) -> serde_json::Value {
+    let labels_json = |labels: &[Literal]| {
+        labels
+            .iter()
+            .map(|label| json!({ "value": label.value(), "lang": label.language() }))
+            .collect::<Vec<_>>()
+    };
+    let no_description = Description::default();
+    serde_json::Value::Array(
+        page_hits
+            .into_iter()
+            .map(|(rank_index, iri_hit, iri_hit_matched_text)| {
+                let description = descriptions.get(&iri_hit.iri).unwrap_or(&no_description);
+                let mut json_hit = json!({
+                    "iri": iri_hit.iri,
+                    "rank": rank_index + 1,
+                    "score": iri_hit.score,
+                    "prefLabels": labels_json(&description.pref_labels),
+                    "labels": labels_json(&description.labels),
+                    "types": description.types,
+                });
+                if let Some(iri_hit_matched_text) = iri_hit_matched_text {
+                    json_hit["matchedText"] = json!({
+                        "text": iri_hit_matched_text.text,
+                        "lang": iri_hit_matched_text.lang,
+                        "snippet": iri_hit_matched_text.snippet,
+                    });
+                }
+                json_hit
+            })
+            .collect(),
+    )
+}
+
+/// The hits of a page as SPARQL JSON results, one solution per hit binding `?iri`, `?rank`,
+/// `?score` and `?label`, and `?matchedText` and `?snippet` when highlighting.
+fn sparql_search_results(
+    page_hits: Vec<(usize, &IriHit, Option<MatchedText>)>,
+    descriptions: &HashMap<String, Description>,
+    highlight: bool,
+) -> Result<Vec<u8>, HttpError> {
+    let mut variables = vec![
+        Variable::new_unchecked("iri"),
+        Variable::new_unchecked("rank"),
+        Variable::new_unchecked("score"),
+        Variable::new_unchecked("label"),
+    ];
+    if highlight {
+        variables.push(Variable::new_unchecked("matchedText"));
+        variables.push(Variable::new_unchecked("snippet"));
+    }
+    let solutions = page_hits
+        .into_iter()
+        .map(|(rank_index, iri_hit, iri_hit_matched_text)| {
+            let mut solution: Vec<Option<Term>> = vec![
+                Some(NamedNode::new_unchecked(iri_hit.iri.as_str()).into()),
+                Some(Literal::from(rank_index as u64 + 1).into()),
+                Some(Literal::from(iri_hit.score).into()),
+                descriptions
+                    .get(&iri_hit.iri)
+                    .and_then(Description::label)
+                    .map(|label| label.clone().into()),
+            ];
+            if highlight {
+                let (matched_text, snippet) = match iri_hit_matched_text {
+                    Some(iri_hit_matched_text) => (
+                        Some(matched_text_literal(
+                            iri_hit_matched_text.text,
+                            iri_hit_matched_text.lang,
+                        )),
+                        iri_hit_matched_text
+                            .snippet
+                            .map(|snippet| Literal::new_typed_literal(snippet, rdf::HTML)),
+                    ),
+                    None => (None, None),
+                };
+                solution.push(matched_text.map(Term::from));
+                solution.push(snippet.map(Term::from));
+            }
+            Ok(solution)
+        })
+        .collect::<Vec<_>>();
+    let mut body = Vec::new();
+    QueryResults::Solutions(QuerySolutionIter::new(
+        Rc::new(variables),
+        solutions.into_iter(),
+    ))
+    .write(&mut body, QueryResultsFormat::Json)
+    .map_err(|err| {
         (
             Status::INTERNAL_SERVER_ERROR,
-            format!("error creating search results Oxigraph store: {}", err),
+            format!("error writing search results: {}", err),
         )
     })?;
+    Ok(body)
+}
 
-    for (_score, doc_address) in top_docs {
-        let retrieved_doc = tantivy_index_searcher
-            .doc::<TantivyDocument>(doc_address)
-            .map_err(|err| (Status::INTERNAL_SERVER_ERROR, err.to_string()))?;
-        if let Some(iri_value) = retrieved_doc.get_first(iri_field) {
-            if let Some(iri) = iri_value.as_str() {
-                // Oxigraph doesn't allow out-of-band variable binding like some SPARQL engines do.
-                // oxrdflib just adds a VALUES clause to the end of the query.
-
-                let index_result_sparql_with_values =
-                    format!("{}\nVALUES ?iri {{ {} }}", index_result_sparql, iri);
-
-                let index_result_query_results: QueryResults = oxigraph_store
-                    .query(index_result_sparql_with_values.as_str())
-                    .map_err(|err| {
-                        (
-                            Status::INTERNAL_SERVER_ERROR,
-                            format!(
-                                "error executing index result query:\nQuery:\n{}\nError:\n{}",
-                                index_result_sparql_with_values, err
-                            ),
-                        )
-                    })?;
-
-                if let QueryResults::Graph(query_triple_iter) = index_result_query_results {
-                    for triple in query_triple_iter.filter_map(|t| t.ok()) {
-                        index_results_oxigraph_store
-                            .insert(QuadRef::new(
-                                &triple.subject,
-                                &triple.predicate,
-                                &triple.object,
-                                GraphNameRef::DefaultGraph,
-                            ))
-                            .map_err(|err| {
-                                (
-                                    Status::INTERNAL_SERVER_ERROR,
-                                    format!("error adding index result query results: {}", err),
-                                )
-                            })?;
-                    }
-                } else {
-                    return Err((
-                        Status::INTERNAL_SERVER_ERROR,
-                        String::from(
-                            "index result query did not return a graph (is it a CONSTRUCT query?)",
-                        ),
+fn matched_text_literal(text: String, lang: Option<String>) -> Literal {
+    match lang {
+        // Language tags were indexed from literals, so they are well-formed
+        Some(lang) => Literal::new_language_tagged_literal_unchecked(text, lang),
+        None => Literal::new_simple_literal(text),
+    }
+}
+
+/// The hits of a page in rank order, each hit's rank, score and matched text followed by the
+/// triples of the index result query constructed from its solutions, streamed as they are
+/// evaluated.
+///
+/// The first solution is evaluated before answering, as the index result query is evaluated
+/// while computing it (to order the solutions), so that a timeout is answered with an error
+/// status. The deadline is then checked between the solutions.
+fn graph_search_results_response(
+    oxigraph_store: &Store,
+    index_result_sparql: &str,
+    page_hits: Vec<(usize, &IriHit, Option<MatchedText>)>,
+    languages: Vec<String>,
+    format: GraphFormat,
+    deadline: Deadline,
+) -> Result<Response, HttpError> {
+    let mut solution_triples = ordered_index_result_triples(
+        oxigraph_store,
+        index_result_sparql,
+        page_hits.iter().map(|(_, iri_hit, _)| iri_hit.iri.as_str()),
+        languages,
+    )?;
+    let first_solution_triples = solution_triples.next().transpose().map_err(|err| {
+        (
+            Status::INTERNAL_SERVER_ERROR,
+            format!("error executing index result query: {}", err),
+        )
+    })?;
+    deadline.check()?;
+    let solution_triples = first_solution_triples
+        .into_iter()
+        .map(Ok)
+        .chain(solution_triples);
+
+    let hit_annotations = page_hits
+        .into_iter()
+        .map(|(rank_index, iri_hit, iri_hit_matched_text)| {
+            let iri = NamedNode::new_unchecked(iri_hit.iri.as_str());
+            let mut annotations = vec![
+                Triple::new(iri.clone(), kos::RANK, Literal::from(rank_index as u64 + 1)),
+                Triple::new(iri.clone(), kos::SCORE, Literal::from(iri_hit.score)),
+            ];
+            if let Some(iri_hit_matched_text) = iri_hit_matched_text {
+                annotations.push(Triple::new(
+                    iri.clone(),
+                    kos::MATCHED_TEXT,
+                    matched_text_literal(iri_hit_matched_text.text, iri_hit_matched_text.lang),
+                ));
+                if let Some(snippet) = iri_hit_matched_text.snippet {
+                    annotations.push(Triple::new(
+                        iri,
+                        kos::SNIPPET,
+                        Literal::new_typed_literal(snippet, rdf::HTML),
                     ));
                 }
             }
+            annotations
+        })
+        .collect::<VecDeque<_>>();
+
+    ReadForWrite::build_response(
+        move |w| {
+            Ok(GraphHitsWriter {
+                writer: GraphSerializer::from_format(format).triple_writer(w)?,
+                hit_annotations,
+                written_hit_count: 0,
+                // CONSTRUCT templates can produce the same triple from several solutions, so
+                // duplicates are skipped
+                hit_triples: HashSet::new(),
+                solution_triples,
+            })
+        },
+        |mut graph_hits_writer| {
+            Ok(
+                if let Some(solution_triples) = graph_hits_writer.solution_triples.next() {
+                    let (position, triples) = solution_triples?;
+                    // The solutions of a hit follow the annotations of the hits up to it, some
+                    // of which may have no solutions
+                    while graph_hits_writer.written_hit_count <= position {
+                        if !graph_hits_writer.write_next_hit_annotations()? {
+                            break;
+                        }
+                    }
+                    for triple in triples {
+                        if graph_hits_writer.hit_triples.insert(triple.clone()) {
+                            graph_hits_writer.writer.write(&triple)?;
+                        }
+                    }
+                    Some(graph_hits_writer)
+                } else {
+                    while graph_hits_writer.write_next_hit_annotations()? {}
+                    graph_hits_writer.writer.finish()?;
+                    None
+                },
+            )
+        },
+        format.media_type(),
+        Some(deadline),
+    )
+}
+
+/// The state of the response of a page of hits in a graph format.
+struct GraphHitsWriter<S> {
+    writer: TripleWriter<ReadForWriteWriter>,
+    /// The annotations of the hits that are not written yet
+    hit_annotations: VecDeque<Vec<Triple>>,
+    written_hit_count: usize,
+    /// The triples written since the annotations of the last written hit
+    hit_triples: HashSet<Triple>,
+    solution_triples: S,
+}
+
+impl<S> GraphHitsWriter<S> {
+    /// Writes the annotations of the next hit, if any is left.
+    fn write_next_hit_annotations(&mut self) -> io::Result<bool> {
+        let Some(annotations) = self.hit_annotations.pop_front() else {
+            return Ok(false);
+        };
+        self.hit_triples.clear();
+        for triple in &annotations {
+            self.writer.write(triple)?;
         }
+        self.written_hit_count += 1;
+        Ok(true)
     }
+}
 
-    if let QueryResults::Graph(triples) = index_results_oxigraph_store
-        .query("CONSTRUCT WHERE { ?s ?p ?o }")
-        .map_err(|err| {
-            (
-                Status::INTERNAL_SERVER_ERROR,
-                format!("error serializing triples: {}", err),
+#[cfg(test)]
+mod tests {
+    use oxhttp::model::Method;
+    use oxigraph::io::GraphParser;
+    use oxigraph::model::GraphNameRef;
+
+    use super::*;
+    use crate::search_index::{SearchIndexConfig, SearchIndexHandle};
+    use crate::testing::{self, EX};
+
+    /// Answers a `/search` request on the vocabulary, in the negotiated format.
+    fn get_search(path_and_query: &str, accept: &str) -> Result<Response, HttpError> {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        get_search_in(&search_index_handle, oxigraph_store, path_and_query, accept)
+    }
+
+    /// Answers a `/search` request on an index of a store.
+    fn get_search_in(
+        search_index_handle: &SearchIndexHandle,
+        oxigraph_store: Store,
+        path_and_query: &str,
+        accept: &str,
+    ) -> Result<Response, HttpError> {
+        let search_index = search_index_handle.current();
+        let mut request = testing::request(Method::GET, path_and_query);
+        request.append_header(HeaderName::ACCEPT, accept).unwrap();
+        handle_request(
+            String::from(testing::INDEX_RESULT_SPARQL),
+            oxigraph_store,
+            &mut request,
+            &search_index.reader,
+            &search_index.query_parser,
+            &search_index.searcher_cache,
+            &search_index_handle.config().facet_field_names,
+            &testing::query_timeouts(),
+        )
+    }
+
+    /// The IRIs of JSON hits, in order.
+    fn hit_iris(response: Response) -> Vec<String> {
+        testing::body_json(response)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|json_hit| String::from(json_hit["iri"].as_str().unwrap()))
+            .collect()
+    }
+
+    fn header(response: &Response, name: &str) -> String {
+        String::from(
+            response
+                .header(&HeaderName::from_str(name).unwrap())
+                .unwrap()
+                .to_str()
+                .unwrap(),
+        )
+    }
+
+    /// The triples of an N-Triples response, in order.
+    fn graph_triples(response: Response) -> Vec<Triple> {
+        GraphParser::from_format(GraphFormat::NTriples)
+            .read_triples(testing::body_string(response).as_bytes())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    /// The objects of the triples of a predicate, in order.
+    fn objects(triples: &[Triple], predicate: NamedNodeRef<'_>) -> Vec<(Subject, Term)> {
+        triples
+            .iter()
+            .filter(|triple| triple.predicate == predicate)
+            .map(|triple| (triple.subject.clone(), triple.object.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn answers_one_hit_per_concept() {
+        // ex:cat has two English labels with "cat"
+        let response = get_search("/search?query=cat", "application/json").unwrap();
+        assert_eq!(header(&response, "X-Total-Count"), "1");
+        assert_eq!(hit_iris(response), [format!("{EX}cat")]);
+    }
+
+    #[test]
+    fn streams_graph_hits_in_rank_order() {
+        let response = get_search(
+            "/search?query=cat%20OR%20dog%20OR%20lion&limit=2",
+            GraphFormat::NTriples.media_type(),
+        )
+        .unwrap();
+        assert_eq!(header(&response, "X-Total-Count"), "3");
+        // Streamed bodies have no length until they are written
+        assert_eq!(response.body().len(), None);
+        let triples = graph_triples(response);
+        let ranks = objects(&triples, kos::RANK);
+        assert_eq!(
+            ranks
+                .iter()
+                .map(|(_, rank)| rank.clone())
+                .collect::<Vec<_>>(),
+            [Literal::from(1_u64).into(), Literal::from(2_u64).into()]
+        );
+        let scores = objects(&triples, kos::SCORE)
+            .into_iter()
+            .map(|(_, score)| match score {
+                Term::Literal(score) => score.value().parse::<f32>().unwrap(),
+                _ => panic!("scores are literals"),
+            })
+            .collect::<Vec<_>>();
+        assert!(scores[0] >= scores[1]);
+        // The index result triples of the hits follow their annotations
+        for (iri, _) in ranks {
+            assert!(triples
+                .iter()
+                .any(|triple| triple.subject == iri && triple.predicate == SKOS_PREF_LABEL));
+        }
+    }
+
+    #[test]
+    fn boosts_fields_of_the_index_init_query() {
+        // "Cat" is the preferred label of ex:cat and an alternative label of ex:dog
+        let oxigraph_store = testing::store();
+        oxigraph_store
+            .load_graph(
+                format!("<{EX}dog> <http://www.w3.org/2004/02/skos/core#altLabel> \"Cat\"@en .")
+                    .as_bytes(),
+                GraphFormat::NTriples,
+                oxigraph::model::GraphNameRef::DefaultGraph,
+                None,
             )
-        })?
-    {
-        // Borrow content negotation code from SPARQL
-        let format = graph_content_negotiation(request)?;
-        return ReadForWrite::build_response(
-            move |w| {
-                Ok((
-                    GraphSerializer::from_format(format).triple_writer(w)?,
-                    triples,
-                ))
+            .unwrap();
+        let search_index_config = |field_boosts: Vec<(String, f32)>| SearchIndexConfig {
+            field_boosts,
+            index_init_sparql: String::from(
+                "PREFIX skos: <http://www.w3.org/2004/02/skos/core#>
+                SELECT ?iri ?prefLabel ?altLabel WHERE {
+                    { ?iri skos:prefLabel ?prefLabel } UNION { ?iri skos:altLabel ?altLabel }
+                }",
+            ),
+            ..testing::search_index_config()
+        };
+        for (boosted_field_name, first_iri) in [("prefLabel", "cat"), ("altLabel", "dog")] {
+            let search_index_handle = testing::search_index_handle_with_config(
+                &oxigraph_store,
+                search_index_config(vec![(String::from(boosted_field_name), 10.0)]),
+            );
+            let response = get_search_in(
+                &search_index_handle,
+                oxigraph_store.clone(),
+                "/search?query=cat&lang=en",
+                "application/json",
+            )
+            .unwrap();
+            assert_eq!(hit_iris(response)[0], format!("{EX}{first_iri}"));
+        }
+        assert!(SearchIndexHandle::open(
+            search_index_config(vec![(String::from("hiddenLabel"), 10.0)]),
+            &oxigraph_store,
+            None,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn language_tags_match_their_prefixes() {
+        let languages = [String::from("zh"), String::from("en-gb")];
+        assert!(language_tag_matches("zh-Hant-TW", &languages));
+        assert!(language_tag_matches("EN-GB", &languages));
+        assert!(!language_tag_matches("en", &languages));
+        assert!(!language_tag_matches("zhx", &languages));
+        assert!(language_tag_matches("fr", &[]));
+    }
+
+    #[test]
+    fn filters_hits_and_labels_by_language() {
+        let response = get_search("/search?query=chat&lang=fr", "application/json").unwrap();
+        let json_hits = testing::body_json(response);
+        assert_eq!(json_hits[0]["iri"], format!("{EX}cat"));
+        assert_eq!(
+            json_hits[0]["prefLabels"],
+            serde_json::json!([{ "value": "Chat", "lang": "fr" }])
+        );
+        let response = get_search("/search?query=chat&lang=en", "application/json").unwrap();
+        assert!(hit_iris(response).is_empty());
+    }
+
+    #[test]
+    fn stems_words_in_languages_with_an_analyzer() {
+        let response = get_search("/search?query=cats&lang=en", "application/json").unwrap();
+        assert_eq!(hit_iris(response), [format!("{EX}cat")]);
+    }
+
+    #[test]
+    fn facets_cannot_be_named_after_parameters() {
+        assert!(check_facet_field_names(&[String::from("type"), String::from("scheme")]).is_ok());
+        assert!(check_facet_field_names(&[String::from("type"), String::from("sort")]).is_err());
+        let oxigraph_store = testing::store();
+        assert!(SearchIndexHandle::open(
+            SearchIndexConfig {
+                facet_field_names: vec![String::from("lang")],
+                ..testing::search_index_config()
             },
-            |(mut writer, mut triples)| {
-                Ok(if let Some(t) = triples.next() {
-                    writer.write(&t?)?;
-                    Some((writer, triples))
-                } else {
-                    writer.finish()?;
-                    None
-                })
+            &oxigraph_store,
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn filters_hits_by_facet_values() {
+        let response = get_search(
+            &format!("/search?query=cat%20OR%20catfish&scheme={EX}animals"),
+            "application/json",
+        )
+        .unwrap();
+        assert_eq!(hit_iris(response), [format!("{EX}cat")]);
+    }
+
+    #[test]
+    fn counts_the_facet_values_of_the_hits() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let search_index = search_index_handle.current();
+        let response = handle_facets_request(
+            &mut testing::request(
+                Method::GET,
+                "/search/facets?query=cat%20OR%20catfish%20OR%20dog",
+            ),
+            &search_index.reader,
+            &search_index.query_parser,
+            &search_index_handle.config().facet_field_names,
+        )
+        .unwrap();
+        assert_eq!(header(&response, "X-Total-Count"), "3");
+        let facets = testing::body_json(response);
+        assert_eq!(
+            facets["scheme"],
+            serde_json::json!([{ "value": format!("{EX}animals"), "count": 2 }])
+        );
+        assert_eq!(
+            facets["type"],
+            serde_json::json!([{
+                "value": "http://www.w3.org/2004/02/skos/core#Concept",
+                "count": 3
+            }])
+        );
+    }
+
+    #[test]
+    fn fuzzy_mode_ranks_exact_matches_first() {
+        // ex:dog's alternative label "Bat" is a fuzzy match of "cat" in a heavily boosted field
+        let oxigraph_store = testing::store();
+        oxigraph_store
+            .load_graph(
+                format!("<{EX}dog> <http://www.w3.org/2004/02/skos/core#altLabel> \"Bat\"@en .")
+                    .as_bytes(),
+                GraphFormat::NTriples,
+                oxigraph::model::GraphNameRef::DefaultGraph,
+                None,
+            )
+            .unwrap();
+        let search_index_handle = testing::search_index_handle_with_config(
+            &oxigraph_store,
+            SearchIndexConfig {
+                field_boosts: vec![(String::from("altLabel"), 100.0)],
+                index_init_sparql: String::from(
+                    "PREFIX skos: <http://www.w3.org/2004/02/skos/core#>
+                    SELECT ?iri ?prefLabel ?altLabel WHERE {
+                        { ?iri skos:prefLabel ?prefLabel } UNION { ?iri skos:altLabel ?altLabel }
+                    }",
+                ),
+                ..testing::search_index_config()
             },
-            format.media_type(),
+        );
+        let response = get_search_in(
+            &search_index_handle,
+            oxigraph_store,
+            "/search?query=cat&mode=fuzzy&lang=en",
+            "application/json",
         )
-        .map(|mut response| {
-            response
-                .append_header("X-Total-Count", count.to_string())
-                .unwrap();
-            response
-        });
-    } else {
-        return Err((
-            Status::INTERNAL_SERVER_ERROR,
-            String::from("CONSTRUCT query should always return triples"),
-        ));
+        .unwrap();
+        let json_hits = testing::body_json(response);
+        let iris = json_hits
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|json_hit| json_hit["iri"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(iris, [format!("{EX}cat"), format!("{EX}dog")]);
+        assert!(json_hits[0]["score"].as_f64().unwrap() > 1.0);
+        assert!(json_hits[1]["score"].as_f64().unwrap() <= 1.0);
+    }
+
+    #[test]
+    fn highlights_the_matched_text() {
+        let response = get_search(
+            "/search?query=house&lang=en&highlight=true",
+            "application/json",
+        )
+        .unwrap();
+        assert_eq!(
+            testing::body_json(response)[0]["matchedText"],
+            serde_json::json!({
+                "text": "House cat",
+                "lang": "en",
+                "snippet": "<b>House</b> cat",
+            })
+        );
+        let response = get_search("/search?query=house", "application/json").unwrap();
+        assert!(testing::body_json(response)[0].get("matchedText").is_none());
+        assert_eq!(
+            get_search("/search?query=house&highlight=yes", "application/json")
+                .err()
+                .unwrap()
+                .0,
+            Status::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn groups_graph_triples_by_hit() {
+        let response = get_search(
+            "/search?query=cat%20OR%20dog%20OR%20lion",
+            GraphFormat::NTriples.media_type(),
+        )
+        .unwrap();
+        let triples = graph_triples(response);
+        let mut subjects: Vec<&Subject> = Vec::new();
+        for triple in &triples {
+            if subjects.last() != Some(&&triple.subject) {
+                // Each hit starts with its rank, and its triples are not interleaved with others
+                assert!(!subjects.contains(&&triple.subject));
+                assert_eq!(triple.predicate, kos::RANK);
+                subjects.push(&triple.subject);
+            }
+        }
+        assert_eq!(subjects.len(), 3);
+    }
+
+    #[test]
+    fn orders_index_result_triples_like_their_iris() {
+        // The blank node comes first in the template, and is fresh for each solution
+        let index_result_sparql = "PREFIX skos: <http://www.w3.org/2004/02/skos/core#>
+            CONSTRUCT { _:label skos:notation ?notation . ?iri skos:altLabel _:label }
+            WHERE { ?iri skos:notation ?notation }";
+        let iris = [
+            format!("{EX}dog"),
+            format!("{EX}catfish"),
+            format!("{EX}cat"),
+        ];
+        let solution_triples = ordered_index_result_triples(
+            &testing::store(),
+            index_result_sparql,
+            iris.iter().map(String::as_str),
+            Vec::new(),
+        )
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        assert_eq!(
+            solution_triples
+                .iter()
+                .map(|(position, _)| *position)
+                .collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        let mut label_subjects = HashSet::new();
+        for ((_, triples), iri) in solution_triples.iter().zip(&iris) {
+            let [notation_triple, alt_label_triple] = triples.as_slice() else {
+                panic!("each solution instantiates the template");
+            };
+            assert_eq!(
+                alt_label_triple.subject,
+                Subject::from(NamedNode::new_unchecked(iri.as_str()))
+            );
+            assert_eq!(
+                Term::from(notation_triple.subject.clone()),
+                alt_label_triple.object
+            );
+            assert!(label_subjects.insert(notation_triple.subject.clone()));
+        }
+    }
+
+    #[test]
+    fn describes_json_hits_with_their_labels_and_types() {
+        let response = get_search("/search?query=house&lang=en", "application/json").unwrap();
+        assert_eq!(header(&response, "Content-Type"), "application/json");
+        let json_hits = testing::body_json(response);
+        assert_eq!(
+            json_hits,
+            json!([{
+                "iri": format!("{}cat", EX),
+                "rank": 1,
+                "score": json_hits[0]["score"],
+                "prefLabels": [{ "value": "Cat", "lang": "en" }],
+                "labels": [{ "value": "House cat", "lang": "en" }],
+                "types": ["http://www.w3.org/2004/02/skos/core#Concept"],
+            }])
+        );
+    }
+
+    #[test]
+    fn answers_sparql_json_results_binding_hits() {
+        let response = get_search(
+            "/search?query=chat&highlight=true",
+            "application/sparql-results+json",
+        )
+        .unwrap();
+        assert_eq!(
+            header(&response, "Content-Type"),
+            "application/sparql-results+json"
+        );
+        let results = testing::body_json(response);
+        assert_eq!(
+            results["head"]["vars"],
+            json!(["iri", "rank", "score", "label", "matchedText", "snippet"])
+        );
+        let binding = &results["results"]["bindings"][0];
+        assert_eq!(
+            binding["iri"],
+            json!({ "type": "uri", "value": format!("{}cat", EX) })
+        );
+        assert_eq!(binding["rank"]["value"], "1");
+        assert_eq!(
+            binding["rank"]["datatype"],
+            "http://www.w3.org/2001/XMLSchema#integer"
+        );
+        assert_eq!(binding["matchedText"]["value"], "Chat");
+        assert_eq!(binding["matchedText"]["xml:lang"], "fr");
+        assert_eq!(binding["snippet"]["value"], "<b>Chat</b>");
+        assert_eq!(
+            binding["snippet"]["datatype"],
+            "http://www.w3.org/1999/02/22-rdf-syntax-ns#HTML"
+        );
+    }
+
+    #[test]
+    fn cursors_round_trip_through_their_encoding() {
+        let cursor = Cursor {
+            doc_address: DocAddress::new(3, 1234),
+            generation_id: u64::MAX,
+            search_hash: 0x0123_4567_89ab_cdef,
+            start: 25,
+        };
+        let cursor_string = cursor.encode();
+        assert_eq!(cursor_string.len(), 64);
+        let decoded_cursor = Cursor::decode(&cursor_string).unwrap();
+        assert_eq!(decoded_cursor.doc_address, cursor.doc_address);
+        assert_eq!(decoded_cursor.generation_id, cursor.generation_id);
+        assert_eq!(decoded_cursor.search_hash, cursor.search_hash);
+        assert_eq!(decoded_cursor.start, cursor.start);
+
+        assert!(Cursor::decode(&cursor_string[1..]).is_err());
+        assert!(Cursor::decode(&cursor_string.replace('f', "g")).is_err());
+        assert!(Cursor::decode(&"é".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn keeps_cursor_generations_while_they_are_used() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let tantivy_index_searcher = search_index_handle.current().reader.searcher();
+        let generation_id = tantivy_index_searcher.generation().generation_id();
+        let searcher_cache = SearcherCache::new(Duration::from_millis(200));
+        assert!(searcher_cache.get(generation_id).is_none());
+
+        searcher_cache.keep(&tantivy_index_searcher);
+        for _ in 0..3 {
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(searcher_cache.get(generation_id).is_some());
+        }
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(searcher_cache.get(generation_id).is_none());
+    }
+
+    #[test]
+    fn keeps_the_most_recently_used_rankings() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let tantivy_index_searcher = search_index_handle.current().reader.searcher();
+        let generation_id = tantivy_index_searcher.generation().generation_id();
+        let searcher_cache = SearcherCache::default();
+        // Rankings are only kept with their generation
+        searcher_cache.keep_ranking(generation_id, 0, Arc::new(Vec::new()));
+        assert!(searcher_cache.ranking(generation_id, 0).is_none());
+
+        searcher_cache.keep(&tantivy_index_searcher);
+        for search_hash in 0..MAX_CURSOR_RANKINGS as u64 {
+            searcher_cache.keep_ranking(generation_id, search_hash, Arc::new(Vec::new()));
+        }
+        assert!(searcher_cache.ranking(generation_id, 0).is_some());
+        searcher_cache.keep_ranking(generation_id, u64::MAX, Arc::new(Vec::new()));
+        // The ranking of search 1 was used least recently
+        assert!(searcher_cache.ranking(generation_id, 1).is_none());
+        assert!(searcher_cache.ranking(generation_id, 0).is_some());
+        assert!(searcher_cache.ranking(generation_id, u64::MAX).is_some());
+    }
+
+    #[test]
+    fn search_hashes_ignore_paging_and_parameter_order() {
+        let hash =
+            |path_and_query: &str| search_hash(testing::request(Method::GET, path_and_query).url());
+        assert_eq!(
+            hash("/search?query=cat&lang=fr&lang=en&type=a"),
+            hash("/search?type=a&lang=fr&query=cat&lang=en&limit=5&offset=5&highlight=true")
+        );
+        // Language preferences are ordered
+        assert_ne!(
+            hash("/search?query=cat&lang=fr&lang=en"),
+            hash("/search?query=cat&lang=en&lang=fr")
+        );
+        assert_ne!(hash("/search?query=cat"), hash("/search?query=dog"));
+        assert_ne!(
+            hash("/search?query=cat"),
+            hash("/search?query=cat&sort=label")
+        );
+    }
+
+    /// The `next` link of a response, if any.
+    fn next_link(response: &Response) -> Option<String> {
+        header(response, "Link").split(", ").find_map(|link| {
+            link.strip_suffix(">; rel=\"next\"")
+                .map(|link| String::from(link.trim_start_matches('<')))
+        })
+    }
+
+    #[test]
+    fn pages_through_hits_with_cursors() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let search = "/search?query=cat%20OR%20dog%20OR%20lion%20OR%20catfish%20OR%20chat";
+        for sort in ["", "&sort=label", "&sort=-notation"] {
+            let all_iris = hit_iris(
+                get_search_in(
+                    &search_index_handle,
+                    oxigraph_store.clone(),
+                    &format!("{}{}", search, sort),
+                    "application/json",
+                )
+                .unwrap(),
+            );
+            assert_eq!(all_iris.len(), 4);
+
+            // Pages after the first cursor are read from the ranking of the search
+            for limit in [1, 3] {
+                let mut iris = Vec::new();
+                let mut path_and_query = Some(format!("{}{}&limit={}", search, sort, limit));
+                while let Some(page_path_and_query) = path_and_query {
+                    let response = get_search_in(
+                        &search_index_handle,
+                        oxigraph_store.clone(),
+                        &page_path_and_query,
+                        "application/json",
+                    )
+                    .unwrap();
+                    assert_eq!(header(&response, "X-Total-Count"), "4");
+                    path_and_query = next_link(&response);
+                    let json_hits = testing::body_json(response);
+                    for json_hit in json_hits.as_array().unwrap() {
+                        iris.push(String::from(json_hit["iri"].as_str().unwrap()));
+                        // Ranks go on across pages
+                        assert_eq!(json_hit["rank"], iris.len());
+                    }
+                }
+                assert_eq!(iris, all_iris, "{} {}", sort, limit);
+            }
+        }
+    }
+
+    #[test]
+    fn offsets_select_pages_of_the_same_order() {
+        let search = "/search?query=cat%20OR%20dog%20OR%20lion%20OR%20catfish";
+        let all_iris = hit_iris(get_search(search, "application/json").unwrap());
+        assert_eq!(
+            hit_iris(
+                get_search(&format!("{}&offset=1&limit=2", search), "application/json").unwrap()
+            ),
+            all_iris[1..3]
+        );
+        assert!(hit_iris(
+            get_search(&format!("{}&offset=10", search), "application/json").unwrap()
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn cursors_only_page_through_their_search() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let response = get_search_in(
+            &search_index_handle,
+            oxigraph_store.clone(),
+            "/search?query=cat%20OR%20dog&limit=1",
+            "application/json",
+        )
+        .unwrap();
+        let next_path_and_query = next_link(&response).unwrap();
+
+        // Page sizes may change
+        assert!(get_search_in(
+            &search_index_handle,
+            oxigraph_store.clone(),
+            &format!("{}&limit=5", next_path_and_query),
+            "application/json",
+        )
+        .is_ok());
+        for changed_path_and_query in [
+            next_path_and_query.replace("dog", "lion"),
+            format!("{}&sort=label", next_path_and_query),
+        ] {
+            assert_eq!(
+                get_search_in(
+                    &search_index_handle,
+                    oxigraph_store.clone(),
+                    &changed_path_and_query,
+                    "application/json",
+                )
+                .unwrap_err()
+                .0,
+                Status::BAD_REQUEST
+            );
+        }
+        assert_eq!(
+            get_search_in(
+                &search_index_handle,
+                oxigraph_store,
+                &format!("{}&offset=1", next_path_and_query),
+                "application/json",
+            )
+            .unwrap_err()
+            .0,
+            Status::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn parses_sorts() {
+        assert_eq!(
+            Sort::from_str("label").unwrap(),
+            Sort {
+                descending: false,
+                key: SortKey::Label
+            }
+        );
+        assert_eq!(
+            Sort::from_str("-notation").unwrap(),
+            Sort {
+                descending: true,
+                key: SortKey::Notation
+            }
+        );
+        assert_eq!(Sort::from_str("-score").unwrap(), Sort::default());
+        assert!(Sort::from_str("iri").is_err());
+        assert!(Sort::from_str("--label").is_err());
+    }
+
+    #[test]
+    fn label_sort_keys_prefer_the_requested_languages() {
+        let sort_labels = ["en\u{1f}Cat", "fr\u{1f}Chat", "\u{1f}Felis"]
+            .map(String::from)
+            .to_vec();
+        let collator = analysis::collator(None);
+        let languages = |language_tags: &[&str]| {
+            language_tags
+                .iter()
+                .map(|language_tag| String::from(*language_tag))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            label_sort_key(sort_labels.clone(), &languages(&["fr", "en"]), &collator).as_deref(),
+            Some("Chat")
+        );
+        assert_eq!(
+            label_sort_key(sort_labels.clone(), &languages(&["de", "en"]), &collator).as_deref(),
+            Some("Cat")
+        );
+        // Untagged labels stand in for missing languages
+        assert_eq!(
+            label_sort_key(sort_labels.clone(), &languages(&["de"]), &collator).as_deref(),
+            Some("Felis")
+        );
+        assert_eq!(
+            label_sort_key(sort_labels[..2].to_vec(), &languages(&["de"]), &collator),
+            None
+        );
+        // Else the first label in collation order
+        assert_eq!(
+            label_sort_key(sort_labels[..2].to_vec(), &[], &collator).as_deref(),
+            Some("Cat")
+        );
+    }
+
+    #[test]
+    fn sorts_labels_by_the_collation_of_the_requested_language() {
+        let oxigraph_store = Store::new().unwrap();
+        oxigraph_store
+            .load_graph(
+                r#"
+                @prefix ex: <http://example.com/> .
+                @prefix skos: <http://www.w3.org/2004/02/skos/core#> .
+
+                ex:oel skos:prefLabel "Öl"@de, "Öl"@sv .
+                ex:ost skos:prefLabel "Ost"@de, "Ost"@sv .
+                ex:zebra skos:prefLabel "Zebra"@de, "Zebra"@sv .
+                "#
+                .as_bytes(),
+                GraphFormat::Turtle,
+                GraphNameRef::DefaultGraph,
+                None,
+            )
+            .unwrap();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let sorted_iris = |lang: &str| {
+            hit_iris(
+                get_search_in(
+                    &search_index_handle,
+                    oxigraph_store.clone(),
+                    &format!("/search?query=öl%20OR%20ost%20OR%20zebra&sort=label&lang={lang}"),
+                    "application/json",
+                )
+                .unwrap(),
+            )
+        };
+        // Ö sorts next to O in German but after Z in Swedish
+        assert_eq!(
+            sorted_iris("de"),
+            [
+                format!("{}oel", EX),
+                format!("{}ost", EX),
+                format!("{}zebra", EX)
+            ]
+        );
+        assert_eq!(
+            sorted_iris("sv"),
+            [
+                format!("{}ost", EX),
+                format!("{}zebra", EX),
+                format!("{}oel", EX)
+            ]
+        );
+    }
+
+    #[test]
+    fn searches_notations() {
+        assert_eq!(
+            hit_iris(get_search("/search?query=A1", "application/json").unwrap()),
+            [format!("{}cat", EX)]
+        );
+    }
+
+    #[test]
+    fn answers_searches_past_their_timeout_with_503() {
+        assert!(get_search("/search?query=cat&timeout=60", "application/json").is_ok());
+        assert_eq!(
+            get_search("/search?query=cat&timeout=0", "application/json")
+                .unwrap_err()
+                .0,
+            Status::SERVICE_UNAVAILABLE
+        );
     }
 }
@@ -0,0 +1,703 @@
+use std::fs::{self, File, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use oxigraph::model::Quad;
+use oxigraph::store::Store;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::QueryParser;
+use tantivy::{Index, IndexReader, ReloadPolicy};
+
+use crate::analysis::{language_field_names, register_tokenizers};
+use crate::annotate::AnnotatorCache;
+use crate::fuzzy::FuzzyQueryParser;
+use crate::indexer::Indexer;
+use crate::init::{build_tantivy_index_schema, index_text_field_names};
+use crate::manifest::Manifest;
+use crate::reconcile::SchemaCache;
+use crate::search::{
+    check_facet_field_names, check_field_boosts, SearchMode, SearchQueryParser, SearcherCache,
+};
+use crate::sparql::TripleCountCache;
+
+/// File of the Tantivy data directory naming the subdirectory that holds the current index.
+///
+/// Without it, the index is in the data directory itself.
+const CURRENT_FILE_NAME: &str = "CURRENT";
+
+/// File of the Tantivy data directory locked by the process using the directory.
+const LOCK_FILE_NAME: &str = "LOCK";
+
+/// How the Tantivy index is built and searched.
+#[derive(Clone)]
+pub struct SearchIndexConfig {
+    pub default_search_mode: SearchMode,
+    pub facet_field_names: Vec<String>,
+    pub field_boosts: Vec<(String, f32)>,
+    pub fuzzy_distance: u8,
+    pub fuzzy_prefix_length: usize,
+    pub index_init_sparql: String,
+    /// File the index init query was read from, if any: it is read again when the index is
+    /// rebuilt, so that a changed query applies without a restart
+    pub index_init_sparql_file_path: Option<PathBuf>,
+    pub language_codes: Vec<String>,
+    pub suggest_field_names: Vec<String>,
+}
+
+impl SearchIndexConfig {
+    /// The config with the index init query read again from its file, if it has one.
+    fn with_current_index_init_sparql(&self) -> anyhow::Result<Self> {
+        let mut config = self.clone();
+        if let Some(index_init_sparql_file_path) = &self.index_init_sparql_file_path {
+            config.index_init_sparql = fs::read_to_string(index_init_sparql_file_path)
+                .with_context(|| {
+                    format!(
+                        "unable to read index init SPARQL file {}",
+                        index_init_sparql_file_path.display()
+                    )
+                })?;
+        }
+        Ok(config)
+    }
+}
+
+/// A Tantivy index with its reader, its query parser and its only writer.
+pub struct SearchIndex {
+    pub annotator_cache: AnnotatorCache,
+    pub indexer: Indexer,
+    pub property_cache: SchemaCache,
+    pub query_parser: SearchQueryParser,
+    pub reader: IndexReader,
+    pub searcher_cache: SearcherCache,
+    pub triple_count_cache: TripleCountCache,
+    pub type_cache: SchemaCache,
+    /// Set when a rebuilt index replaces this one. Declared last, so that the directory is
+    /// removed once the writer of the index is dropped and done committing.
+    directory_removal: Mutex<Option<IndexDirectoryRemoval>>,
+}
+
+impl SearchIndex {
+    /// Opens the index in a directory, or creates it in RAM if there is none, and fills it from
+    /// the store if it is empty.
+    pub fn open(
+        config: &SearchIndexConfig,
+        oxigraph_store: &Store,
+        directory_path: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        check_facet_field_names(&config.facet_field_names).map_err(anyhow::Error::msg)?;
+        let text_field_names = index_text_field_names(
+            &config.index_init_sparql,
+            oxigraph_store,
+            &config.facet_field_names,
+        )?;
+        check_field_boosts(&config.field_boosts, &text_field_names).map_err(anyhow::Error::msg)?;
+        let schema = build_tantivy_index_schema(
+            &text_field_names,
+            &config.facet_field_names,
+            &config.language_codes,
+        )?;
+
+        let index = if let Some(directory_path) = directory_path {
+            fs::create_dir_all(directory_path)?;
+            Index::open_or_create(MmapDirectory::open(directory_path)?, schema)?
+        } else {
+            Index::create_in_ram(schema)
+        };
+        register_tokenizers(&index, &config.language_codes)?;
+
+        let reader: IndexReader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommitWithDelay)
+            .try_into()?;
+
+        let indexer = Indexer::new(
+            &index,
+            config.index_init_sparql.clone(),
+            config.facet_field_names.clone(),
+            config.suggest_field_names.clone(),
+        )?;
+        if reader.searcher().num_docs() == 0 {
+            indexer.index_all(oxigraph_store)?;
+            // Searchable right away rather than after the reload delay
+            reader.reload()?;
+        } else {
+            eprintln!("Tantivy index is not empty, skipping init")
+        }
+
+        // Query every language field of every text field, each with the analyzer it was indexed
+        // with
+        let schema = index.schema();
+        let query_fields = text_field_names
+            .iter()
+            .flat_map(|text_field_name| {
+                language_field_names(text_field_name, &config.language_codes)
+            })
+            .map(|field_name| schema.get_field(&field_name))
+            .collect::<tantivy::Result<Vec<_>>>()?;
+        let mut query_parser = QueryParser::for_index(&index, query_fields.clone());
+        let mut fuzzy_query_parser = FuzzyQueryParser::for_index(
+            &index,
+            query_fields,
+            config.fuzzy_distance,
+            config.fuzzy_prefix_length,
+        );
+        for (text_field_name, boost) in &config.field_boosts {
+            for field_name in language_field_names(text_field_name, &config.language_codes) {
+                let field = schema.get_field(&field_name)?;
+                query_parser.set_field_boost(field, *boost);
+                fuzzy_query_parser.set_field_boost(field, *boost);
+            }
+        }
+
+        Ok(Self {
+            annotator_cache: AnnotatorCache::default(),
+            indexer,
+            property_cache: SchemaCache::default(),
+            query_parser: SearchQueryParser::new(
+                query_parser,
+                fuzzy_query_parser,
+                config.default_search_mode,
+            ),
+            reader,
+            searcher_cache: SearcherCache::default(),
+            triple_count_cache: TripleCountCache::default(),
+            type_cache: SchemaCache::default(),
+            directory_removal: Mutex::new(None),
+        })
+    }
+}
+
+/// The search index in use, replaced as a whole when the index is rebuilt so that searches never
+/// see a partially built index.
+pub struct SearchIndexHandle {
+    config: SearchIndexConfig,
+    current: RwLock<Arc<SearchIndex>>,
+    /// Tantivy data directory, or `None` for an index in RAM
+    data_directory_path: Option<PathBuf>,
+    /// Locked while re-indexing changes, so that a rebuilt index is swapped in either before a
+    /// change is re-indexed or after it is queued
+    rebuild_state: Mutex<RebuildState>,
+    /// Increased whenever the store is changed through the server
+    store_generation: AtomicU64,
+    /// Oxigraph data directory, the manifest of which records that the store was changed through
+    /// the server, or `None` for a store in memory
+    oxigraph_data_directory_path: Option<PathBuf>,
+    /// Set once the manifest of the Oxigraph data directory records that the store was changed
+    store_change_recorded: AtomicBool,
+}
+
+/// Whether the index is being rebuilt, and what the rebuilt index may miss.
+#[derive(Default)]
+struct RebuildState {
+    is_running: bool,
+    /// Set when the store changes in ways the running rebuild may miss, to rebuild again once
+    /// it is done
+    runs_again: bool,
+    /// Quads changed while the rebuild runs, re-indexed in the rebuilt index before it is swapped
+    /// in since the rebuild may have read the store before they changed
+    changed_quads: Vec<Quad>,
+}
+
+impl SearchIndexHandle {
+    pub fn open(
+        config: SearchIndexConfig,
+        oxigraph_store: &Store,
+        data_directory_path: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let directory_path = match &data_directory_path {
+            Some(data_directory_path) => Some(current_index_directory_path(data_directory_path)?),
+            None => None,
+        };
+        let search_index = SearchIndex::open(&config, oxigraph_store, directory_path.as_deref())?;
+        Ok(Self {
+            config,
+            current: RwLock::new(Arc::new(search_index)),
+            data_directory_path,
+            rebuild_state: Mutex::new(RebuildState::default()),
+            store_generation: AtomicU64::new(0),
+            oxigraph_data_directory_path: None,
+            store_change_recorded: AtomicBool::new(false),
+        })
+    }
+
+    /// Records changes of the store through the server in the manifest of its data directory,
+    /// so that the store is not rebuilt over them.
+    pub fn with_oxigraph_data_directory_path(
+        mut self,
+        oxigraph_data_directory_path: Option<PathBuf>,
+    ) -> Self {
+        self.oxigraph_data_directory_path = oxigraph_data_directory_path;
+        self
+    }
+
+    /// The config the index was opened with. Rebuilt indexes read the index init query again
+    /// from its file.
+    pub fn config(&self) -> &SearchIndexConfig {
+        &self.config
+    }
+
+    /// The index to use for a request. It stays usable until the request drops it, even if the
+    /// index is swapped meanwhile.
+    pub fn current(&self) -> Arc<SearchIndex> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// A number increased whenever the store is changed through the server, for the caches of
+    /// what is computed from the store.
+    ///
+    /// Read it before computing from the store, so that a change made meanwhile outdates the
+    /// result.
+    pub fn store_generation(&self) -> u64 {
+        self.store_generation.load(Ordering::SeqCst)
+    }
+
+    /// Re-indexes the concepts changed quads may affect in the current index, and makes them
+    /// searchable right away rather than after the reload delay.
+    ///
+    /// During a rebuild, the changed quads are also re-indexed in the rebuilt index once built.
+    pub fn reindex(&self, oxigraph_store: &Store, changed_quads: &[Quad]) -> anyhow::Result<()> {
+        self.record_store_change()?;
+        let mut rebuild_state = self.lock_rebuild_state();
+        self.store_generation.fetch_add(1, Ordering::SeqCst);
+        if rebuild_state.is_running {
+            rebuild_state.changed_quads.extend_from_slice(changed_quads);
+        }
+        let search_index = self.current();
+        search_index
+            .indexer
+            .reindex(oxigraph_store, changed_quads)?;
+        search_index.reader.reload()?;
+        Ok(())
+    }
+
+    /// Rebuilds the index from the store in a background thread, then swaps it in.
+    ///
+    /// Returns `false` without doing anything if a rebuild is already running.
+    pub fn spawn_rebuild(self: &Arc<Self>, oxigraph_store: Store) -> bool {
+        let mut rebuild_state = self.lock_rebuild_state();
+        if rebuild_state.is_running {
+            return false;
+        }
+        rebuild_state.is_running = true;
+        let search_index_handle = Arc::clone(self);
+        thread::spawn(move || loop {
+            match search_index_handle.rebuild(&oxigraph_store) {
+                Ok(()) => eprintln!("rebuilt Tantivy index"),
+                Err(err) => eprintln!("error rebuilding Tantivy index: {}", err),
+            }
+            let mut rebuild_state = search_index_handle.lock_rebuild_state();
+            rebuild_state.changed_quads.clear();
+            if !std::mem::take(&mut rebuild_state.runs_again) {
+                rebuild_state.is_running = false;
+                break;
+            }
+        });
+        true
+    }
+
+    /// Rebuilds the index in the background after changes of the store the changed quads of
+    /// which are unknown, such as loaded files or a replaced dataset.
+    ///
+    /// If a rebuild is already running, it runs again once done, since it may have read the
+    /// store before the changes.
+    pub fn rebuild_after_change(self: &Arc<Self>, oxigraph_store: Store) {
+        if let Err(err) = self.record_store_change() {
+            eprintln!("{}", err);
+        }
+        self.store_generation.fetch_add(1, Ordering::SeqCst);
+        let mut rebuild_state = self.lock_rebuild_state();
+        if rebuild_state.is_running {
+            rebuild_state.runs_again = true;
+        } else {
+            drop(rebuild_state);
+            self.spawn_rebuild(oxigraph_store);
+        }
+    }
+
+    /// Records in the manifest of the Oxigraph data directory, once, that the store was changed.
+    fn record_store_change(&self) -> anyhow::Result<()> {
+        let Some(oxigraph_data_directory_path) = &self.oxigraph_data_directory_path else {
+            return Ok(());
+        };
+        if self.store_change_recorded.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        Manifest::record_modified(oxigraph_data_directory_path).map_err(|err| {
+            // Recorded by the next change instead
+            self.store_change_recorded.store(false, Ordering::SeqCst);
+            anyhow::anyhow!(
+                "error recording that the Oxigraph store was changed: {}",
+                err
+            )
+        })
+    }
+
+    fn lock_rebuild_state(&self) -> std::sync::MutexGuard<'_, RebuildState> {
+        self.rebuild_state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn rebuild(&self, oxigraph_store: &Store) -> anyhow::Result<()> {
+        let config = self.config.with_current_index_init_sparql()?;
+        match &self.data_directory_path {
+            Some(data_directory_path) => {
+                let (search_index, previous_directory_path) =
+                    rebuild_index_directory(&config, oxigraph_store, data_directory_path)?;
+                // The next startup finds the index up to date with the query it was rebuilt with
+                if let Some(recorded_manifest) = Manifest::read(data_directory_path)? {
+                    Manifest::for_tantivy_index(&recorded_manifest, &config)
+                        .write(data_directory_path)?;
+                }
+                // Requests still using the previous index keep it until they are done with it
+                *self
+                    .swap_rebuilt(search_index, oxigraph_store)?
+                    .directory_removal
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(IndexDirectoryRemoval {
+                    data_directory_path: data_directory_path.clone(),
+                    directory_path: previous_directory_path,
+                });
+            }
+            None => {
+                self.swap_rebuilt(
+                    SearchIndex::open(&config, oxigraph_store, None)?,
+                    oxigraph_store,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-indexes the quads changed during a rebuild in the rebuilt index, then makes it the
+    /// current one, returning the previous one.
+    fn swap_rebuilt(
+        &self,
+        search_index: SearchIndex,
+        oxigraph_store: &Store,
+    ) -> anyhow::Result<Arc<SearchIndex>> {
+        let mut rebuild_state = self.lock_rebuild_state();
+        let changed_quads = std::mem::take(&mut rebuild_state.changed_quads);
+        search_index
+            .indexer
+            .reindex(oxigraph_store, &changed_quads)?;
+        search_index.reader.reload()?;
+        Ok(self.swap(search_index))
+    }
+
+    /// Makes an index the current one, returning the previous one.
+    fn swap(&self, search_index: SearchIndex) -> Arc<SearchIndex> {
+        std::mem::replace(
+            &mut *self.current.write().unwrap_or_else(PoisonError::into_inner),
+            Arc::new(search_index),
+        )
+    }
+}
+
+/// The directory of an index replaced by a rebuilt one, removed when dropped.
+struct IndexDirectoryRemoval {
+    data_directory_path: PathBuf,
+    directory_path: PathBuf,
+}
+
+impl Drop for IndexDirectoryRemoval {
+    fn drop(&mut self) {
+        remove_index_directory(&self.data_directory_path, &self.directory_path);
+    }
+}
+
+/// Locks a Tantivy data directory until the returned file is dropped, so that a single process
+/// at a time builds and swaps the indexes of the directory.
+pub fn lock_data_directory(data_directory_path: &Path) -> anyhow::Result<File> {
+    fs::create_dir_all(data_directory_path)?;
+    let lock_file = File::create(data_directory_path.join(LOCK_FILE_NAME))?;
+    match lock_file.try_lock() {
+        Ok(()) => Ok(lock_file),
+        Err(TryLockError::WouldBlock) => bail!(
+            "Tantivy index data directory {} is used by another process",
+            data_directory_path.display()
+        ),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+/// Directory of the current index in a Tantivy data directory.
+fn current_index_directory_path(data_directory_path: &Path) -> anyhow::Result<PathBuf> {
+    let current_file_path = data_directory_path.join(CURRENT_FILE_NAME);
+    if current_file_path.exists() {
+        Ok(data_directory_path.join(fs::read_to_string(current_file_path)?.trim()))
+    } else {
+        Ok(data_directory_path.to_path_buf())
+    }
+}
+
+/// Whether a Tantivy data directory has an index already, rather than one to create.
+pub fn has_index(data_directory_path: &Path) -> anyhow::Result<bool> {
+    let directory_path = current_index_directory_path(data_directory_path)?;
+    Ok(directory_path.exists() && Index::exists(&MmapDirectory::open(directory_path)?)?)
+}
+
+/// Builds a new index in a fresh subdirectory of a Tantivy data directory and makes it the
+/// current index of the directory.
+///
+/// Returns the new index and the directory of the previous one, which is left in place.
+pub fn rebuild_index_directory(
+    config: &SearchIndexConfig,
+    oxigraph_store: &Store,
+    data_directory_path: &Path,
+) -> anyhow::Result<(SearchIndex, PathBuf)> {
+    let previous_directory_path = current_index_directory_path(data_directory_path)?;
+
+    let directory_name = format!(
+        "index-{}",
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis()
+    );
+    let directory_path = data_directory_path.join(&directory_name);
+    if directory_path.exists() {
+        bail!(
+            "index directory {} already exists",
+            directory_path.display()
+        );
+    }
+    eprintln!("rebuilding Tantivy index in {}", directory_path.display());
+    let search_index = SearchIndex::open(config, oxigraph_store, Some(&directory_path))?;
+
+    // Renaming is atomic, so a crash leaves either the previous or the new index current
+    let current_file_path = data_directory_path.join(CURRENT_FILE_NAME);
+    let new_current_file_path = current_file_path.with_extension("new");
+    fs::write(&new_current_file_path, directory_name)?;
+    fs::rename(new_current_file_path, current_file_path)?;
+
+    Ok((search_index, previous_directory_path))
+}
+
+/// Removes the directory of a previous index, unless it is the data directory itself: the
+/// index was there before the first rebuild, and its files are left in place.
+pub fn remove_index_directory(data_directory_path: &Path, previous_directory_path: &Path) {
+    if previous_directory_path != data_directory_path {
+        if let Err(err) = fs::remove_dir_all(previous_directory_path) {
+            eprintln!(
+                "error removing previous Tantivy index {}: {}",
+                previous_directory_path.display(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use oxigraph::model::{GraphName, Literal, NamedNode};
+
+    use super::*;
+    use crate::testing;
+
+    /// A fresh directory under the temporary directory.
+    fn temp_directory_path(name: &str) -> PathBuf {
+        let directory_path = std::env::temp_dir().join(format!(
+            "kos-kit-server-{}-{}",
+            name,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir_all(&directory_path).unwrap();
+        directory_path
+    }
+
+    #[test]
+    fn removes_replaced_index_directories_once_unused() {
+        let data_directory_path = temp_directory_path("rebuild");
+        let oxigraph_store = testing::store();
+        let search_index_handle = SearchIndexHandle::open(
+            testing::search_index_config(),
+            &oxigraph_store,
+            Some(data_directory_path.clone()),
+        )
+        .unwrap();
+
+        // The index first in the data directory itself is left in place
+        search_index_handle.rebuild(&oxigraph_store).unwrap();
+        let first_directory_path = current_index_directory_path(&data_directory_path).unwrap();
+        assert_ne!(first_directory_path, data_directory_path);
+
+        let first_search_index = search_index_handle.current();
+        // Rebuilt directories are named after the time
+        thread::sleep(Duration::from_millis(2));
+        search_index_handle.rebuild(&oxigraph_store).unwrap();
+        assert_ne!(
+            current_index_directory_path(&data_directory_path).unwrap(),
+            first_directory_path
+        );
+        assert!(first_directory_path.exists());
+        // Still searchable by the requests using it
+        assert_eq!(
+            first_search_index.reader.searcher().num_docs(),
+            search_index_handle.current().reader.searcher().num_docs()
+        );
+
+        drop(first_search_index);
+        assert!(!first_directory_path.exists());
+
+        drop(search_index_handle);
+        fs::remove_dir_all(data_directory_path).unwrap();
+    }
+
+    #[test]
+    fn rebuilds_with_the_index_init_query_read_again() {
+        let data_directory_path = temp_directory_path("reread");
+        let tantivy_index_data_directory_path = data_directory_path.join("tantivy");
+        let index_init_sparql_file_path = data_directory_path.join("index_init.sparql");
+        fs::write(&index_init_sparql_file_path, testing::INDEX_INIT_SPARQL).unwrap();
+        let config = SearchIndexConfig {
+            index_init_sparql_file_path: Some(index_init_sparql_file_path.clone()),
+            ..testing::search_index_config()
+        };
+        Manifest::for_tantivy_index(&Manifest::default(), &config)
+            .write(&tantivy_index_data_directory_path)
+            .unwrap();
+        let oxigraph_store = testing::store();
+        let search_index_handle = SearchIndexHandle::open(
+            config,
+            &oxigraph_store,
+            Some(tantivy_index_data_directory_path.clone()),
+        )
+        .unwrap();
+        let cat_iri = format!("{}cat", testing::EX);
+        assert!(testing::count_documents(&search_index_handle, &cat_iri) > 1);
+
+        // Only the preferred labels are indexed
+        let index_init_sparql = "PREFIX skos: <http://www.w3.org/2004/02/skos/core#>
+            SELECT ?iri ?prefLabel WHERE { ?iri skos:prefLabel ?prefLabel FILTER(LANG(?prefLabel) = \"en\") }";
+        fs::write(&index_init_sparql_file_path, index_init_sparql).unwrap();
+        search_index_handle.rebuild(&oxigraph_store).unwrap();
+        assert_eq!(testing::count_documents(&search_index_handle, &cat_iri), 1);
+        assert_eq!(
+            Manifest::read(&tantivy_index_data_directory_path).unwrap(),
+            Some(Manifest::for_tantivy_index(
+                &Manifest::default(),
+                &SearchIndexConfig {
+                    index_init_sparql: String::from(index_init_sparql),
+                    ..testing::search_index_config()
+                }
+            ))
+        );
+
+        drop(search_index_handle);
+        fs::remove_dir_all(data_directory_path).unwrap();
+    }
+
+    #[test]
+    fn data_directories_are_locked_by_a_single_process() {
+        let data_directory_path = temp_directory_path("lock");
+        let lock_file = lock_data_directory(&data_directory_path).unwrap();
+        assert!(lock_data_directory(&data_directory_path).is_err());
+        drop(lock_file);
+        assert!(lock_data_directory(&data_directory_path).is_ok());
+        fs::remove_dir_all(data_directory_path).unwrap();
+    }
+
+    fn gato() -> Quad {
+        Quad::new(
+            NamedNode::new_unchecked(format!("{}cat", testing::EX)),
+            NamedNode::new_unchecked("http://www.w3.org/2004/02/skos/core#prefLabel"),
+            Literal::new_language_tagged_literal_unchecked("Gato", "es"),
+            GraphName::DefaultGraph,
+        )
+    }
+
+    #[test]
+    fn reindexes_changes_made_during_a_rebuild_in_the_rebuilt_index() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let cat_iri = format!("{}cat", testing::EX);
+        search_index_handle.lock_rebuild_state().is_running = true;
+        // Built from the store before the change
+        let rebuilt_search_index =
+            SearchIndex::open(search_index_handle.config(), &oxigraph_store, None).unwrap();
+
+        oxigraph_store.insert(&gato()).unwrap();
+        search_index_handle
+            .reindex(&oxigraph_store, &[gato()])
+            .unwrap();
+        assert_eq!(testing::count_documents(&search_index_handle, &cat_iri), 5);
+
+        search_index_handle
+            .swap_rebuilt(rebuilt_search_index, &oxigraph_store)
+            .unwrap();
+        assert_eq!(testing::count_documents(&search_index_handle, &cat_iri), 5);
+        assert!(search_index_handle
+            .lock_rebuild_state()
+            .changed_quads
+            .is_empty());
+    }
+
+    #[test]
+    fn records_changes_of_the_store_in_its_manifest() {
+        let oxigraph_data_directory_path = temp_directory_path("modified");
+        Manifest::default()
+            .write(&oxigraph_data_directory_path)
+            .unwrap();
+        let oxigraph_store = testing::store();
+        let search_index_handle =
+            SearchIndexHandle::open(testing::search_index_config(), &oxigraph_store, None)
+                .unwrap()
+                .with_oxigraph_data_directory_path(Some(oxigraph_data_directory_path.clone()));
+        let recorded_manifest = || {
+            Manifest::read(&oxigraph_data_directory_path)
+                .unwrap()
+                .unwrap()
+        };
+        assert!(!recorded_manifest().modified);
+
+        oxigraph_store.insert(&gato()).unwrap();
+        search_index_handle
+            .reindex(&oxigraph_store, &[gato()])
+            .unwrap();
+        assert!(recorded_manifest().modified);
+
+        fs::remove_file(Manifest::path(&oxigraph_data_directory_path)).unwrap();
+        fs::remove_dir_all(&oxigraph_data_directory_path).unwrap();
+    }
+
+    #[test]
+    fn rebuilds_again_after_changes_a_running_rebuild_may_miss() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let search_index = search_index_handle.current();
+        let store_generation = search_index_handle.store_generation();
+
+        search_index_handle.lock_rebuild_state().is_running = true;
+        search_index_handle.rebuild_after_change(oxigraph_store.clone());
+        assert!(search_index_handle.lock_rebuild_state().runs_again);
+        assert!(search_index_handle.store_generation() > store_generation);
+        // Left to the running rebuild
+        assert!(Arc::ptr_eq(&search_index, &search_index_handle.current()));
+
+        search_index_handle.lock_rebuild_state().is_running = false;
+        search_index_handle.lock_rebuild_state().runs_again = false;
+        oxigraph_store.insert(&gato()).unwrap();
+        search_index_handle.rebuild_after_change(oxigraph_store);
+        for _ in 0..500 {
+            if !search_index_handle.lock_rebuild_state().is_running {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!search_index_handle.lock_rebuild_state().is_running);
+        assert_eq!(
+            testing::count_documents(&search_index_handle, &format!("{}cat", testing::EX)),
+            5
+        );
+    }
+}
@@ -0,0 +1,395 @@
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use oxigraph::model::{Literal, NamedNode, NamedNodeRef, Term};
+use oxigraph::sparql::{
+    Query, QueryOptions, QueryResults, QuerySolutionIter, ServiceHandler, Variable,
+};
+use spargebra::algebra::GraphPattern;
+use spargebra::term::{NamedNodePattern, TermPattern};
+
+use crate::collector::IriPageCollector;
+use crate::search::{iri_collector, search, search_with, ParsedUrl, SearchMode};
+use crate::search_index::SearchIndex;
+use crate::vocab::kos;
+
+#[derive(Debug)]
+pub struct SearchServiceError(String);
+
+impl fmt::Display for SearchServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for SearchServiceError {}
+
+/// A `SERVICE kos:search { ... }` pattern: a single subject variable, bound to the IRIs of the
+/// hits, with `kos:query` and optional `kos:lang`, `kos:limit` and `kos:mode` parameters, and
+/// optional `kos:score` and `kos:rank` variables.
+struct SearchServiceCall {
+    iri_variable: Variable,
+    languages: Vec<String>,
+    limit: Option<usize>,
+    mode: Option<SearchMode>,
+    query: String,
+    rank_variable: Option<Variable>,
+    score_variable: Option<Variable>,
+}
+
+impl SearchServiceCall {
+    fn parse(pattern: &GraphPattern) -> Result<Self, SearchServiceError> {
+        let triple_patterns = match pattern {
+            GraphPattern::Bgp { patterns } => patterns,
+            // Oxigraph hands the service pattern over as a SELECT * query
+            GraphPattern::Project { inner, .. }
+            | GraphPattern::Distinct { inner }
+            | GraphPattern::Reduced { inner } => return Self::parse(inner),
+            _ => {
+                return Err(SearchServiceError(format!(
+                    "{} patterns should only contain triple patterns",
+                    kos::SEARCH
+                )))
+            }
+        };
+
+        let mut iri_variable = None;
+        let mut languages = Vec::new();
+        let mut limit = None;
+        let mut mode = None;
+        let mut query = None;
+        let mut rank_variable = None;
+        let mut score_variable = None;
+        for triple_pattern in triple_patterns {
+            let TermPattern::Variable(subject_variable) = &triple_pattern.subject else {
+                return Err(SearchServiceError(format!(
+                    "the subject of {} patterns should be a variable",
+                    kos::SEARCH
+                )));
+            };
+            if iri_variable.get_or_insert_with(|| subject_variable.clone()) != subject_variable {
+                return Err(SearchServiceError(format!(
+                    "{} patterns should have a single subject",
+                    kos::SEARCH
+                )));
+            }
+            let NamedNodePattern::NamedNode(predicate) = &triple_pattern.predicate else {
+                return Err(SearchServiceError(format!(
+                    "the predicates of {} patterns should be IRIs",
+                    kos::SEARCH
+                )));
+            };
+            let predicate = predicate.as_ref();
+            if predicate == kos::LANG {
+                languages
+                    .push(literal_value(predicate, &triple_pattern.object)?.to_ascii_lowercase());
+            } else if predicate == kos::LIMIT {
+                limit = Some(
+                    literal_value(predicate, &triple_pattern.object)?
+                        .parse::<usize>()
+                        .map_err(|err| {
+                            SearchServiceError(format!("error parsing {}: {}", predicate, err))
+                        })?,
+                );
+            } else if predicate == kos::MODE {
+                mode = Some(
+                    literal_value(predicate, &triple_pattern.object)?
+                        .parse::<SearchMode>()
+                        .map_err(SearchServiceError)?,
+                );
+            } else if predicate == kos::QUERY {
+                query = Some(String::from(literal_value(
+                    predicate,
+                    &triple_pattern.object,
+                )?));
+            } else if predicate == kos::RANK {
+                rank_variable = Some(variable(predicate, &triple_pattern.object)?);
+            } else if predicate == kos::SCORE {
+                score_variable = Some(variable(predicate, &triple_pattern.object)?);
+            } else {
+                return Err(SearchServiceError(format!(
+                    "{} is not supported in {} patterns",
+                    predicate,
+                    kos::SEARCH
+                )));
+            }
+        }
+
+        Ok(Self {
+            iri_variable: iri_variable.ok_or_else(|| {
+                SearchServiceError(format!("{} patterns should not be empty", kos::SEARCH))
+            })?,
+            languages,
+            limit,
+            mode,
+            query: query.ok_or_else(|| {
+                SearchServiceError(format!(
+                    "{} patterns should have a {}",
+                    kos::SEARCH,
+                    kos::QUERY
+                ))
+            })?,
+            rank_variable,
+            score_variable,
+        })
+    }
+}
+
+fn literal_value<'a>(
+    predicate: NamedNodeRef<'_>,
+    object: &'a TermPattern,
+) -> Result<&'a str, SearchServiceError> {
+    match object {
+        TermPattern::Literal(literal) => Ok(literal.value()),
+        _ => Err(SearchServiceError(format!(
+            "the object of {} should be a literal",
+            predicate
+        ))),
+    }
+}
+
+fn variable(
+    predicate: NamedNodeRef<'_>,
+    object: &TermPattern,
+) -> Result<Variable, SearchServiceError> {
+    match object {
+        TermPattern::Variable(variable) => Ok(variable.clone()),
+        _ => Err(SearchServiceError(format!(
+            "the object of {} should be a variable",
+            predicate
+        ))),
+    }
+}
+
+/// Evaluates `SERVICE kos:search` patterns against the search index, so that full-text hits can
+/// be joined with graph patterns in a single SPARQL query, e.g.
+///
+/// ```sparql
+/// PREFIX kos: <urn:kos-kit:>
+/// PREFIX skos: <http://www.w3.org/2004/02/skos/core#>
+/// SELECT ?iri ?score WHERE {
+///   SERVICE kos:search { ?iri kos:query "water" ; kos:score ?score }
+///   ?iri skos:broader <http://example.com/hydrology>
+/// }
+/// ```
+///
+/// Oxigraph evaluates the service pattern on its own, so its parameters must be constants.
+pub struct SearchServiceHandler {
+    search_index: Arc<SearchIndex>,
+}
+
+impl ServiceHandler for SearchServiceHandler {
+    type Error = SearchServiceError;
+
+    fn handle(
+        &self,
+        service_name: NamedNode,
+        query: Query,
+    ) -> Result<QueryResults, SearchServiceError> {
+        if service_name.as_ref() != kos::SEARCH {
+            return Err(SearchServiceError(format!(
+                "SERVICE {} is not supported by this server",
+                service_name
+            )));
+        }
+
+        // Oxigraph doesn't expose the algebra of its queries
+        let pattern = match spargebra::Query::parse(&query.to_string(), None)
+            .map_err(|err| SearchServiceError(err.to_string()))?
+        {
+            spargebra::Query::Select { pattern, .. } => pattern,
+            _ => {
+                return Err(SearchServiceError(format!(
+                    "{} should be called with a graph pattern",
+                    kos::SEARCH
+                )))
+            }
+        };
+        let search_service_call = SearchServiceCall::parse(&pattern)?;
+
+        let tantivy_index_searcher = self.search_index.reader.searcher();
+        let parsed_url = ParsedUrl {
+            facet_filters: Vec::new(),
+            highlight: false,
+            languages: search_service_call.languages,
+            limit: search_service_call.limit.unwrap_or(usize::MAX),
+            mode: search_service_call.mode,
+            offset: 0,
+            query: search_service_call.query,
+        };
+        // Only the hits within the limit are sorted and read
+        let iri_hits = match search_service_call.limit {
+            Some(limit) => search_with(
+                &parsed_url,
+                &tantivy_index_searcher,
+                &self.search_index.query_parser,
+                IriPageCollector::new(iri_collector(&parsed_url), limit),
+            )
+            .map(|(_, iri_page)| iri_page.hits),
+            None => search(
+                &parsed_url,
+                &tantivy_index_searcher,
+                &self.search_index.query_parser,
+            )
+            .map(|(_, iri_hits)| iri_hits),
+        }
+        .map_err(|(_, message)| SearchServiceError(message))?;
+
+        let mut variables = vec![search_service_call.iri_variable];
+        variables.extend(search_service_call.score_variable.clone());
+        variables.extend(search_service_call.rank_variable.clone());
+        let solutions = iri_hits
+            .into_iter()
+            .enumerate()
+            .map(|(rank_index, iri_hit)| {
+                let mut solution: Vec<Option<Term>> =
+                    vec![Some(NamedNode::new_unchecked(iri_hit.iri).into())];
+                if search_service_call.score_variable.is_some() {
+                    solution.push(Some(Literal::from(iri_hit.score).into()));
+                }
+                if search_service_call.rank_variable.is_some() {
+                    solution.push(Some(Literal::from(rank_index as u64 + 1).into()));
+                }
+                Ok(solution)
+            })
+            .collect::<Vec<_>>();
+        Ok(QueryResults::Solutions(QuerySolutionIter::new(
+            Rc::new(variables),
+            solutions.into_iter(),
+        )))
+    }
+}
+
+/// Options of `/sparql` queries: `SERVICE kos:search` searches the index.
+pub fn search_service_query_options(search_index: Arc<SearchIndex>) -> QueryOptions {
+    QueryOptions::default().with_service_handler(SearchServiceHandler { search_index })
+}
+
+#[cfg(test)]
+mod tests {
+    use oxhttp::model::{HeaderName, Method};
+    use url::form_urlencoded;
+
+    use super::*;
+    use crate::sparql::{self, SparqlGuardrails};
+    use crate::testing;
+
+    fn parse(service_pattern: &str) -> Result<SearchServiceCall, SearchServiceError> {
+        let spargebra::Query::Select { pattern, .. } = spargebra::Query::parse(
+            &format!(
+                "PREFIX kos: <urn:kos-kit:> SELECT * WHERE {{ {} }}",
+                service_pattern
+            ),
+            None,
+        )
+        .unwrap() else {
+            unreachable!()
+        };
+        SearchServiceCall::parse(&pattern)
+    }
+
+    #[test]
+    fn parses_the_parameters_and_variables_of_search_patterns() {
+        let search_service_call = parse(
+            r#"?iri kos:query "cat" ; kos:lang "EN", "fr" ; kos:limit "2" ; kos:mode "fuzzy" ;
+                kos:score ?score ; kos:rank ?rank"#,
+        )
+        .unwrap();
+        assert_eq!(search_service_call.iri_variable.as_str(), "iri");
+        assert_eq!(search_service_call.languages, ["en", "fr"]);
+        assert_eq!(search_service_call.limit, Some(2));
+        assert_eq!(search_service_call.mode, Some(SearchMode::Fuzzy));
+        assert_eq!(search_service_call.query, "cat");
+        assert_eq!(search_service_call.rank_variable.unwrap().as_str(), "rank");
+        assert_eq!(
+            search_service_call.score_variable.unwrap().as_str(),
+            "score"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_search_patterns() {
+        for service_pattern in [
+            // No query
+            "?iri kos:score ?score",
+            // Several subjects
+            r#"?iri kos:query "cat" . ?other kos:score ?score"#,
+            // Variable parameters
+            "?iri kos:query ?query",
+            // Constant outputs
+            r#"?iri kos:query "cat" ; kos:score "1""#,
+            r#"?iri kos:query "cat" ; kos:limit "many""#,
+            r#"?iri kos:query "cat" ; kos:unknown "1""#,
+        ] {
+            assert!(parse(service_pattern).is_err(), "{}", service_pattern);
+        }
+    }
+
+    /// The JSON bindings of a `/sparql` query of the vocabulary.
+    fn select(query: &str) -> serde_json::Value {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let mut request = testing::request(
+            Method::GET,
+            &format!(
+                "/sparql?{}",
+                form_urlencoded::Serializer::new(String::new())
+                    .append_pair("query", query)
+                    .finish()
+            ),
+        );
+        request
+            .append_header(HeaderName::ACCEPT, "application/sparql-results+json")
+            .unwrap();
+        let response = sparql::handle_request(
+            &mut request,
+            oxigraph_store,
+            search_service_query_options(search_index_handle.current()),
+            &[kos::SEARCH],
+            |_| unreachable!("queries don't describe the service"),
+            &testing::query_timeouts(),
+            &SparqlGuardrails::default(),
+        )
+        .unwrap();
+        testing::body_json(response)["results"]["bindings"].take()
+    }
+
+    #[test]
+    fn joins_search_hits_with_graph_patterns_in_sparql_queries() {
+        let bindings = select(
+            r#"
+            PREFIX kos: <urn:kos-kit:>
+            PREFIX skos: <http://www.w3.org/2004/02/skos/core#>
+            SELECT ?iri ?rank WHERE {
+                SERVICE kos:search { ?iri kos:query "cat OR catfish" ; kos:rank ?rank }
+                # ex:catfish is in no scheme
+                ?iri skos:inScheme <http://example.com/animals>
+            }
+        "#,
+        );
+        assert_eq!(bindings.as_array().unwrap().len(), 1);
+        assert_eq!(bindings[0]["iri"]["value"], format!("{}cat", testing::EX));
+        // Ranked among every hit, before the join
+        assert_eq!(bindings[0]["rank"]["value"], "2");
+    }
+
+    #[test]
+    fn limits_search_hits_to_the_best_ones() {
+        let search = |service_pattern: &str| {
+            select(&format!(
+                "PREFIX kos: <urn:kos-kit:> SELECT ?iri ?rank WHERE {{ SERVICE kos:search {{ {} }} }} ORDER BY ?rank",
+                service_pattern
+            ))
+        };
+        let all_bindings = search(r#"?iri kos:query "cat OR catfish" ; kos:rank ?rank"#);
+        assert_eq!(all_bindings.as_array().unwrap().len(), 2);
+        let bindings =
+            search(r#"?iri kos:query "cat OR catfish" ; kos:limit "1" ; kos:rank ?rank"#);
+        assert_eq!(
+            bindings.as_array().unwrap(),
+            &all_bindings.as_array().unwrap()[..1]
+        );
+    }
+}
@@ -0,0 +1,348 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use oxhttp::model::{HeaderName, Request, Response, Status};
+use oxigraph::{
+    model::{NamedNode, Term},
+    sparql::{EvaluationError, QueryResults},
+    store::Store,
+};
+use serde_json::json;
+use tantivy::{
+    collector::DocSetCollector,
+    query::{BooleanQuery, MoreLikeThisQuery, Occur, Query, TermQuery},
+    schema::IndexRecordOption,
+    IndexReader, TantivyDocument,
+};
+use url::Url;
+
+use crate::collector::IriCollector;
+use crate::search::{parse_languages, parse_limit, parse_offset, SearchQueryParser};
+use crate::sparql::union_default_graph_query;
+
+type HttpError = (Status, String);
+
+struct ParsedUrl {
+    /// Weight of each broader concept shared with the concept, or relation between them, added
+    /// to the normalized text similarity
+    graph_boost: f32,
+    iri: NamedNode,
+    /// Requested language tags, lowercased, in order of preference
+    languages: Vec<String>,
+    limit: usize,
+    offset: usize,
+}
+
+impl ParsedUrl {
+    fn parse(url: &Url) -> Result<Self, String> {
+        let url_query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        Ok(Self {
+            graph_boost: match url_query.get("graphBoost") {
+                Some(graph_boost_string) => {
+                    let graph_boost = graph_boost_string
+                        .parse::<f32>()
+                        .map_err(|err| format!("error parsing graphBoost: {}", err))?;
+                    // Scores must stay comparable for ranking
+                    if !graph_boost.is_finite() || graph_boost < 0.0 {
+                        return Err(format!(
+                            "graphBoost should be a non-negative finite number, not {}",
+                            graph_boost_string
+                        ));
+                    }
+                    graph_boost
+                }
+                None => 0.0,
+            },
+            iri: NamedNode::new(url_query.get("iri").ok_or("missing iri")?)
+                .map_err(|err| format!("error parsing iri: {}", err))?,
+            languages: parse_languages(url),
+            limit: parse_limit(&url_query)?,
+            offset: parse_offset(&url_query)?,
+        })
+    }
+}
+
+/// Counts, for each concept, the broader concepts it shares with a concept, plus one if either
+/// is related to the other.
+fn graph_proximities(
+    oxigraph_store: &Store,
+    iri: &NamedNode,
+) -> Result<HashMap<String, u64>, HttpError> {
+    let query = format!(
+        "PREFIX skos: <http://www.w3.org/2004/02/skos/core#>
+SELECT ?similar (COUNT(DISTINCT ?via) AS ?proximity) WHERE {{
+    {{ {iri} skos:broader ?via . ?similar skos:broader ?via }}
+    UNION
+    {{ {iri} skos:related ?similar BIND({iri} AS ?via) }}
+    UNION
+    {{ ?similar skos:related {iri} BIND({iri} AS ?via) }}
+    FILTER(isIRI(?similar) && ?similar != {iri})
+}} GROUP BY ?similar"
+    );
+    let solutions = match union_default_graph_query(&query)
+        .map_err(EvaluationError::from)
+        .and_then(|query| oxigraph_store.query(query))
+        .map_err(|err| {
+            (
+                Status::INTERNAL_SERVER_ERROR,
+                format!("error executing graph proximity query: {}", err),
+            )
+        })? {
+        QueryResults::Solutions(solutions) => solutions,
+        _ => unreachable!("graph proximity query is a SELECT query"),
+    };
+    let mut proximities = HashMap::new();
+    for solution in solutions {
+        let solution = solution.map_err(|err| {
+            (
+                Status::INTERNAL_SERVER_ERROR,
+                format!("error executing graph proximity query: {}", err),
+            )
+        })?;
+        if let (Some(Term::NamedNode(similar)), Some(Term::Literal(proximity))) =
+            (solution.get("similar"), solution.get("proximity"))
+        {
+            proximities.insert(
+                String::from(similar.as_str()),
+                proximity.value().parse::<u64>().unwrap_or_default(),
+            );
+        }
+    }
+    Ok(proximities)
+}
+
+/// Finds the concepts whose indexed texts are similar to those of the concept in the `iri`
+/// parameter, e.g. duplicates or overlapping concepts across vocabularies.
+///
+/// Similarity is a more-like-this query built from the terms of every text of the concept. With
+/// a `graphBoost`, concepts also score that much per broader concept they share with the concept
+/// and for a `skos:related` link to it, on top of their text similarity normalized to 1.
+///
+/// Answers a JSON array of `{"iri", "rank", "score", "textScore", "graphProximity"}` objects.
+pub fn handle_request(
+    request: &mut Request,
+    oxigraph_store: &Store,
+    tantivy_index_reader: &IndexReader,
+    search_query_parser: &SearchQueryParser,
+) -> Result<Response, HttpError> {
+    if request.method().as_ref() != "GET" {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    let parsed_url =
+        ParsedUrl::parse(request.url()).map_err(|err_string| (Status::BAD_REQUEST, err_string))?;
+
+    let tantivy_index_searcher = tantivy_index_reader.searcher();
+    let internal_server_error = |err: tantivy::TantivyError| {
+        (
+            Status::INTERNAL_SERVER_ERROR,
+            format!("error reading index: {}", err),
+        )
+    };
+    let iri_field = tantivy_index_searcher
+        .schema()
+        .get_field("iri")
+        .map_err(internal_server_error)?;
+    let iri_query = TermQuery::new(
+        tantivy::Term::from_field_text(iri_field, parsed_url.iri.as_str()),
+        IndexRecordOption::Basic,
+    );
+
+    // The texts of the concept, from all of its documents
+    let mut texts_by_field = BTreeMap::new();
+    for doc_address in tantivy_index_searcher
+        .search(&iri_query, &DocSetCollector)
+        .map_err(internal_server_error)?
+    {
+        let document: TantivyDocument = tantivy_index_searcher
+            .doc(doc_address)
+            .map_err(internal_server_error)?;
+        for text_field in search_query_parser.text_fields() {
+            for text in document.get_all(text_field) {
+                texts_by_field
+                    .entry(text_field)
+                    .or_insert_with(Vec::new)
+                    .push(text.clone());
+            }
+        }
+    }
+    if texts_by_field.is_empty() {
+        return Err((
+            Status::NOT_FOUND,
+            format!("{} is not in the index", parsed_url.iri),
+        ));
+    }
+
+    // Labels are short, so every term of the concept counts
+    let more_like_this_query = MoreLikeThisQuery::builder()
+        .with_min_doc_frequency(1)
+        .with_min_term_frequency(1)
+        .with_min_word_length(2)
+        .with_boost_factor(1.0)
+        .with_document_fields(texts_by_field.into_iter().collect());
+    let query = BooleanQuery::new(vec![
+        (
+            Occur::Must,
+            Box::new(more_like_this_query) as Box<dyn Query>,
+        ),
+        (Occur::MustNot, Box::new(iri_query)),
+    ]);
+    let iri_hits = tantivy_index_searcher
+        .search(
+            &query,
+            &IriCollector::new("iri").with_language_preference("lang", parsed_url.languages),
+        )
+        .map_err(internal_server_error)?;
+
+    // Text scores and graph proximities of the similar concepts
+    let max_text_score = iri_hits
+        .iter()
+        .map(|iri_hit| iri_hit.score)
+        .fold(0.0_f32, f32::max);
+    let mut similar_concepts: HashMap<String, (Option<f32>, u64)> = iri_hits
+        .into_iter()
+        .map(|iri_hit| (iri_hit.iri, (Some(iri_hit.score), 0)))
+        .collect();
+    if parsed_url.graph_boost != 0.0 {
+        for (similar_iri, proximity) in graph_proximities(oxigraph_store, &parsed_url.iri)? {
+            similar_concepts.entry(similar_iri).or_insert((None, 0)).1 = proximity;
+        }
+    }
+    let mut scored_similar_concepts = similar_concepts
+        .into_iter()
+        .map(|(similar_iri, (text_score, proximity))| {
+            let normalized_text_score = match text_score {
+                Some(text_score) if max_text_score > 0.0 => text_score / max_text_score,
+                _ => 0.0,
+            };
+            let score = normalized_text_score + parsed_url.graph_boost * proximity as f32;
+            (similar_iri, score, text_score, proximity)
+        })
+        .collect::<Vec<_>>();
+    scored_similar_concepts.sort_by(|left, right| {
+        right
+            .1
+            .partial_cmp(&left.1)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| left.0.cmp(&right.0))
+    });
+
+    let count = scored_similar_concepts.len();
+    let json_hits = scored_similar_concepts
+        .into_iter()
+        .enumerate()
+        .skip(parsed_url.offset)
+        .take(parsed_url.limit)
+        .map(
+            |(rank_index, (similar_iri, score, text_score, proximity))| {
+                json!({
+                    "iri": similar_iri,
+                    "rank": rank_index + 1,
+                    "score": score,
+                    "textScore": text_score,
+                    "graphProximity": proximity,
+                })
+            },
+        )
+        .collect::<Vec<_>>();
+
+    Ok(Response::builder(Status::OK)
+        .with_header(HeaderName::CONTENT_TYPE, "application/json")
+        .unwrap()
+        .with_header("X-Total-Count", count.to_string())
+        .unwrap()
+        .with_body(serde_json::Value::Array(json_hits).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use oxhttp::model::Method;
+
+    use super::*;
+    use crate::testing::{self, EX};
+
+    fn parse(path_and_query: &str) -> Result<ParsedUrl, String> {
+        ParsedUrl::parse(testing::request(Method::GET, path_and_query).url())
+    }
+
+    #[test]
+    fn parses_the_graph_boost_and_the_search_parameters() {
+        let parsed_url =
+            parse("/search/similar?iri=http://example.com/cat&graphBoost=0.5&lang=EN&limit=2")
+                .unwrap();
+        assert_eq!(parsed_url.graph_boost, 0.5);
+        assert_eq!(parsed_url.iri.as_str(), "http://example.com/cat");
+        assert_eq!(parsed_url.languages, ["en"]);
+        assert_eq!(parsed_url.limit, 2);
+        assert_eq!(parsed_url.offset, 0);
+        assert_eq!(
+            parse("/search/similar?iri=http://example.com/cat")
+                .unwrap()
+                .graph_boost,
+            0.0
+        );
+    }
+
+    #[test]
+    fn rejects_graph_boosts_that_are_not_finite_non_negative_numbers() {
+        for graph_boost in ["NaN", "inf", "-inf", "-1", "high"] {
+            assert!(
+                parse(&format!(
+                    "/search/similar?iri=http://example.com/cat&graphBoost={}",
+                    graph_boost
+                ))
+                .is_err(),
+                "{}",
+                graph_boost
+            );
+        }
+    }
+
+    #[test]
+    fn ranks_similar_texts_and_graph_neighbours() {
+        let oxigraph_store = testing::store();
+        oxigraph_store
+            .update(&format!(
+                "PREFIX skos: <http://www.w3.org/2004/02/skos/core#>
+                INSERT DATA {{
+                    <{EX}kitten> skos:prefLabel \"Kitten cat\"@en .
+                    <{EX}dog> skos:related <{EX}cat> .
+                }}"
+            ))
+            .unwrap();
+        let search_index = testing::search_index_handle(&oxigraph_store).current();
+        let similar = |path_and_query: &str| {
+            handle_request(
+                &mut testing::request(Method::GET, path_and_query),
+                &oxigraph_store,
+                &search_index.reader,
+                &search_index.query_parser,
+            )
+        };
+
+        let json_hits = testing::body_json(
+            similar(&format!("/search/similar?iri={EX}cat&graphBoost=2")).unwrap(),
+        );
+        assert_eq!(json_hits[0]["iri"], format!("{EX}dog"));
+        assert_eq!(json_hits[0]["graphProximity"], 1);
+        assert_eq!(json_hits[0]["textScore"], serde_json::Value::Null);
+        assert_eq!(json_hits[1]["iri"], format!("{EX}kitten"));
+        assert_eq!(json_hits[1]["score"], 1.0);
+
+        assert_eq!(
+            similar(&format!("/search/similar?iri={EX}cat&graphBoost=NaN"))
+                .unwrap_err()
+                .0,
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            similar("/search/similar?iri=http://example.com/unknown")
+                .unwrap_err()
+                .0,
+            Status::NOT_FOUND
+        );
+    }
+}
@@ -2,26 +2,339 @@
 
 #![allow(clippy::print_stderr, clippy::cast_precision_loss, clippy::use_debug)]
 use oxhttp::model::{Body, HeaderName, HeaderValue, Request, Response, Status};
-use oxigraph::io::{GraphFormat, GraphSerializer};
-use oxigraph::model::{GraphName, IriParseError, NamedNode, NamedOrBlankNode};
-use oxigraph::sparql::{Query, QueryResults};
+use oxigraph::io::{DatasetFormat, GraphFormat, GraphSerializer};
+use oxigraph::model::vocab::rdf;
+use oxigraph::model::{
+    BlankNode, GraphName, IriParseError, Literal, NamedNode, NamedNodeRef, NamedOrBlankNode, Triple,
+};
+use oxigraph::sparql::{EvaluationError, ParseError, Query, QueryOptions, QueryResults};
 use oxigraph::store::Store;
 use sparesults::{QueryResultsFormat, QueryResultsSerializer};
+use spargebra::algebra::{AggregateExpression, Expression, GraphPattern, OrderExpression};
+use spargebra::term::NamedNodePattern;
 use std::cell::RefCell;
 use std::cmp::min;
+use std::collections::HashMap;
 use std::fmt;
 use std::io::{self, Read, Write};
 use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
 use url::form_urlencoded;
 
-const MAX_SPARQL_BODY_SIZE: u64 = 0x0010_0000;
+use crate::timeout::{Deadline, QueryTimeouts};
+
+pub(crate) const MAX_SPARQL_BODY_SIZE: u64 = 0x0010_0000;
+
+/// Formats of the results of graph queries, the first one being the default
+const GRAPH_FORMATS: [GraphFormat; 3] = [
+    GraphFormat::NTriples,
+    GraphFormat::Turtle,
+    GraphFormat::RdfXml,
+];
+
+/// Formats of the results of SELECT and ASK queries, the first one being the default
+const QUERY_RESULTS_FORMATS: [QueryResultsFormat; 4] = [
+    QueryResultsFormat::Json,
+    QueryResultsFormat::Xml,
+    QueryResultsFormat::Csv,
+    QueryResultsFormat::Tsv,
+];
+
+const SD_NAMESPACE: &str = "http://www.w3.org/ns/sparql-service-description#";
+
+const VOID_NAMESPACE: &str = "http://rdfs.org/ns/void#";
 
 type HttpError = (Status, String);
 
-pub fn handle_request(request: &mut Request, store: Store) -> Result<Response, HttpError> {
+/// What `/sparql` answers for queries with more solutions or triples than a response may have.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OnTooManyResults {
+    /// The first results, with a `Warning` header stating the maximum
+    #[default]
+    Truncate,
+    /// An error
+    Reject,
+}
+
+impl FromStr for OnTooManyResults {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(Self::Truncate),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!(
+                "unknown policy {} for too many results (expected truncate or reject)",
+                s
+            )),
+        }
+    }
+}
+
+/// Features of SPARQL queries that `/sparql` can deny.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SparqlFeature {
+    /// Property paths other than single predicates, e.g. `skos:broader+`
+    PropertyPath,
+    /// `SERVICE` patterns
+    Service,
+}
+
+impl FromStr for SparqlFeature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "property-path" => Ok(Self::PropertyPath),
+            "service" => Ok(Self::Service),
+            _ => Err(format!(
+                "unknown SPARQL feature {} (expected property-path or service)",
+                s
+            )),
+        }
+    }
+}
+
+/// Limits of `/sparql` queries, for public access. There are none by default.
+#[derive(Clone, Debug, Default)]
+pub struct SparqlGuardrails {
+    /// Maximum number of solutions or triples of a response
+    pub max_results: Option<usize>,
+    pub on_too_many_results: OnTooManyResults,
+    /// Maximum number of triple patterns and operators in the algebra of a query
+    pub max_query_complexity: Option<usize>,
+    pub denied_features: Vec<SparqlFeature>,
+    /// IRIs of the services `SERVICE` patterns may call even if the service feature is denied
+    /// (e.g., `urn:kos-kit:search`)
+    pub allowed_services: Vec<String>,
+}
+
+impl SparqlGuardrails {
+    /// Checks the algebra of a query before it is evaluated.
+    fn check_query(&self, query: &spargebra::Query) -> Result<(), HttpError> {
+        let (spargebra::Query::Select { pattern, .. }
+        | spargebra::Query::Construct { pattern, .. }
+        | spargebra::Query::Describe { pattern, .. }
+        | spargebra::Query::Ask { pattern, .. }) = query;
+        let mut query_algebra_summary = QueryAlgebraSummary::default();
+        query_algebra_summary.add_graph_pattern(pattern);
+
+        if let Some(max_query_complexity) = self.max_query_complexity {
+            if query_algebra_summary.complexity > max_query_complexity {
+                return Err(forbidden(format!(
+                    "The query is too complex: it has {} triple patterns and operators, and at \
+                     most {} are allowed",
+                    query_algebra_summary.complexity, max_query_complexity
+                )));
+            }
+        }
+        if query_algebra_summary.has_property_paths
+            && self.denied_features.contains(&SparqlFeature::PropertyPath)
+        {
+            return Err(forbidden("Property paths are not allowed by this server"));
+        }
+        if self.denied_features.contains(&SparqlFeature::Service) {
+            for service_name in &query_algebra_summary.service_names {
+                match service_name {
+                    NamedNodePattern::NamedNode(service_name)
+                        if self
+                            .allowed_services
+                            .iter()
+                            .any(|allowed_service| allowed_service == service_name.as_str()) => {}
+                    _ => {
+                        return Err(forbidden(format!(
+                            "SERVICE {} is not allowed by this server",
+                            service_name
+                        )))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What guardrails check in the algebra of a query.
+#[derive(Default)]
+struct QueryAlgebraSummary {
+    /// Number of triple patterns, property paths and other operators
+    complexity: usize,
+    has_property_paths: bool,
+    /// Names of the services of the `SERVICE` patterns
+    service_names: Vec<NamedNodePattern>,
+}
+
+impl QueryAlgebraSummary {
+    fn add_graph_pattern(&mut self, pattern: &GraphPattern) {
+        match pattern {
+            GraphPattern::Bgp { patterns } => self.complexity += patterns.len(),
+            GraphPattern::Path { .. } => {
+                self.complexity += 1;
+                self.has_property_paths = true;
+            }
+            GraphPattern::Join { left, right }
+            | GraphPattern::Lateral { left, right }
+            | GraphPattern::Union { left, right }
+            | GraphPattern::Minus { left, right } => {
+                self.complexity += 1;
+                self.add_graph_pattern(left);
+                self.add_graph_pattern(right);
+            }
+            GraphPattern::LeftJoin {
+                left,
+                right,
+                expression,
+            } => {
+                self.complexity += 1;
+                self.add_graph_pattern(left);
+                self.add_graph_pattern(right);
+                if let Some(expression) = expression {
+                    self.add_expression(expression);
+                }
+            }
+            GraphPattern::Filter { expr, inner } => {
+                self.complexity += 1;
+                self.add_expression(expr);
+                self.add_graph_pattern(inner);
+            }
+            GraphPattern::Extend {
+                inner, expression, ..
+            } => {
+                self.complexity += 1;
+                self.add_expression(expression);
+                self.add_graph_pattern(inner);
+            }
+            GraphPattern::OrderBy { inner, expression } => {
+                self.complexity += 1;
+                for order_expression in expression {
+                    match order_expression {
+                        OrderExpression::Asc(expression) | OrderExpression::Desc(expression) => {
+                            self.add_expression(expression)
+                        }
+                    }
+                }
+                self.add_graph_pattern(inner);
+            }
+            GraphPattern::Group {
+                inner, aggregates, ..
+            } => {
+                self.complexity += 1;
+                for (_, aggregate) in aggregates {
+                    match aggregate {
+                        AggregateExpression::Count { expr: None, .. } => {}
+                        AggregateExpression::Count {
+                            expr: Some(expr), ..
+                        }
+                        | AggregateExpression::Sum { expr, .. }
+                        | AggregateExpression::Avg { expr, .. }
+                        | AggregateExpression::Min { expr, .. }
+                        | AggregateExpression::Max { expr, .. }
+                        | AggregateExpression::GroupConcat { expr, .. }
+                        | AggregateExpression::Sample { expr, .. }
+                        | AggregateExpression::Custom { expr, .. } => self.add_expression(expr),
+                    }
+                }
+                self.add_graph_pattern(inner);
+            }
+            GraphPattern::Service { name, inner, .. } => {
+                self.complexity += 1;
+                self.service_names.push(name.clone());
+                self.add_graph_pattern(inner);
+            }
+            GraphPattern::Graph { inner, .. }
+            | GraphPattern::Project { inner, .. }
+            | GraphPattern::Distinct { inner }
+            | GraphPattern::Reduced { inner }
+            | GraphPattern::Slice { inner, .. } => {
+                self.complexity += 1;
+                self.add_graph_pattern(inner);
+            }
+            GraphPattern::Values { .. } => self.complexity += 1,
+        }
+    }
+
+    /// Only `EXISTS` patterns count in expressions.
+    fn add_expression(&mut self, expression: &Expression) {
+        match expression {
+            Expression::Exists(pattern) => self.add_graph_pattern(pattern),
+            Expression::Or(left, right)
+            | Expression::And(left, right)
+            | Expression::Equal(left, right)
+            | Expression::SameTerm(left, right)
+            | Expression::Greater(left, right)
+            | Expression::GreaterOrEqual(left, right)
+            | Expression::Less(left, right)
+            | Expression::LessOrEqual(left, right)
+            | Expression::Add(left, right)
+            | Expression::Subtract(left, right)
+            | Expression::Multiply(left, right)
+            | Expression::Divide(left, right) => {
+                self.add_expression(left);
+                self.add_expression(right);
+            }
+            Expression::UnaryPlus(inner)
+            | Expression::UnaryMinus(inner)
+            | Expression::Not(inner) => self.add_expression(inner),
+            Expression::In(inner, expressions) => {
+                self.add_expression(inner);
+                for expression in expressions {
+                    self.add_expression(expression);
+                }
+            }
+            Expression::If(condition, then, otherwise) => {
+                self.add_expression(condition);
+                self.add_expression(then);
+                self.add_expression(otherwise);
+            }
+            Expression::Coalesce(expressions) | Expression::FunctionCall(_, expressions) => {
+                for expression in expressions {
+                    self.add_expression(expression);
+                }
+            }
+            Expression::NamedNode(_)
+            | Expression::Literal(_)
+            | Expression::Variable(_)
+            | Expression::Bound(_) => {}
+        }
+    }
+}
+
+/// Answers SPARQL queries, evaluated with `query_options` (e.g., a service handler).
+///
+/// A `GET` without a query is answered with the service description of the endpoint, which
+/// lists `extension_features` (e.g., the services of `query_options`) among its features and the
+/// `triple_counts` of the store.
+///
+/// The `timeout` parameter is read from the URL query or from a form-encoded body. The timeout
+/// is checked between solutions: a query past it is answered with a 503 status, or has the
+/// streaming of its results cut off once its status is sent, but the computation of a solution
+/// runs to its end. Queries breaking the `guardrails` are refused with a 403 status.
+pub fn handle_request(
+    request: &mut Request,
+    store: Store,
+    query_options: QueryOptions,
+    extension_features: &[NamedNodeRef<'_>],
+    triple_counts: impl FnOnce(&Store) -> Result<Arc<TripleCounts>, HttpError>,
+    query_timeouts: &QueryTimeouts,
+    guardrails: &SparqlGuardrails,
+) -> Result<Response, HttpError> {
     match request.method().as_ref() {
-        "GET" => configure_and_evaluate_sparql_query(&store, &[url_query(request)], None, request),
+        "GET" => {
+            if form_urlencoded::parse(url_query(request)).any(|(k, _)| k == "query") {
+                configure_and_evaluate_sparql_query(
+                    &store,
+                    &[url_query(request)],
+                    None,
+                    request,
+                    query_options,
+                    query_timeouts.deadline(&[url_query(request)])?,
+                    guardrails,
+                )
+            } else {
+                service_description(&store, request, extension_features, triple_counts)
+            }
+        }
         "POST" => {
             let content_type =
                 content_type(request).ok_or_else(|| bad_request("No Content-Type given"))?;
@@ -37,6 +350,9 @@ pub fn handle_request(request: &mut Request, store: Store) -> Result<Response, H
                     &[url_query(request)],
                     Some(buffer),
                     request,
+                    query_options,
+                    query_timeouts.deadline(&[url_query(request)])?,
+                    guardrails,
                 )
             } else if content_type == "application/x-www-form-urlencoded" {
                 let mut buffer = Vec::new();
@@ -50,6 +366,9 @@ pub fn handle_request(request: &mut Request, store: Store) -> Result<Response, H
                     &[url_query(request), &buffer],
                     None,
                     request,
+                    query_options,
+                    query_timeouts.deadline(&[url_query(request), &buffer])?,
+                    guardrails,
                 )
             } else {
                 Err(unsupported_media_type(&content_type))
@@ -62,14 +381,14 @@ pub fn handle_request(request: &mut Request, store: Store) -> Result<Response, H
     }
 }
 
-fn base_url(request: &Request) -> String {
+pub(crate) fn base_url(request: &Request) -> String {
     let mut url = request.url().clone();
     url.set_query(None);
     url.set_fragment(None);
     url.into()
 }
 
-fn url_query(request: &Request) -> &[u8] {
+pub(crate) fn url_query(request: &Request) -> &[u8] {
     request.url().query().unwrap_or("").as_bytes()
 }
 
@@ -78,6 +397,9 @@ fn configure_and_evaluate_sparql_query(
     encoded: &[&[u8]],
     mut query: Option<String>,
     request: &Request,
+    query_options: QueryOptions,
+    deadline: Deadline,
+    guardrails: &SparqlGuardrails,
 ) -> Result<Response, HttpError> {
     let mut default_graph_uris = Vec::new();
     let mut named_graph_uris = Vec::new();
@@ -106,9 +428,13 @@ fn configure_and_evaluate_sparql_query(
         default_graph_uris,
         named_graph_uris,
         request,
+        query_options,
+        deadline,
+        guardrails,
     )
 }
 
+#[allow(clippy::too_many_arguments)]
 fn evaluate_sparql_query(
     store: &Store,
     query: &str,
@@ -116,8 +442,14 @@ fn evaluate_sparql_query(
     default_graph_uris: Vec<String>,
     named_graph_uris: Vec<String>,
     request: &Request,
+    query_options: QueryOptions,
+    deadline: Deadline,
+    guardrails: &SparqlGuardrails,
 ) -> Result<Response, HttpError> {
-    let mut query = Query::parse(query, Some(&base_url(request))).map_err(bad_request)?;
+    // Parsed as Oxigraph does, but keeping the algebra to check it
+    let query = spargebra::Query::parse(query, Some(&base_url(request))).map_err(bad_request)?;
+    guardrails.check_query(&query)?;
+    let mut query = Query::from(query);
 
     if use_default_graph_as_union {
         if !default_graph_uris.is_empty() || !named_graph_uris.is_empty() {
@@ -143,15 +475,19 @@ fn evaluate_sparql_query(
         );
     }
 
-    let results = store.query(query).map_err(internal_server_error)?;
+    let results = store
+        .query_opt(query, query_options)
+        .map_err(internal_server_error)?;
     match results {
         QueryResults::Solutions(solutions) => {
             let format = query_results_content_negotiation(request)?;
-            ReadForWrite::build_response(
+            let variables = solutions.variables().to_vec();
+            let (solutions, truncated_to) = read_ahead(solutions, guardrails, Some(deadline))?;
+            let response = ReadForWrite::build_response(
                 move |w| {
                     Ok((
                         QueryResultsSerializer::from_format(format)
-                            .solutions_writer(w, solutions.variables().to_vec())?,
+                            .solutions_writer(w, variables)?,
                         solutions,
                     ))
                 },
@@ -165,9 +501,12 @@ fn evaluate_sparql_query(
                     })
                 },
                 format.media_type(),
-            )
+                Some(deadline),
+            )?;
+            Ok(with_truncation_warning(response, truncated_to, "solutions"))
         }
         QueryResults::Boolean(result) => {
+            deadline.check()?;
             let format = query_results_content_negotiation(request)?;
             let mut body = Vec::new();
             QueryResultsSerializer::from_format(format)
@@ -180,7 +519,8 @@ fn evaluate_sparql_query(
         }
         QueryResults::Graph(triples) => {
             let format = graph_content_negotiation(request)?;
-            ReadForWrite::build_response(
+            let (triples, truncated_to) = read_ahead(triples, guardrails, Some(deadline))?;
+            let response = ReadForWrite::build_response(
                 move |w| {
                     Ok((
                         GraphSerializer::from_format(format).triple_writer(w)?,
@@ -197,37 +537,342 @@ fn evaluate_sparql_query(
                     })
                 },
                 format.media_type(),
+                Some(deadline),
+            )?;
+            Ok(with_truncation_warning(response, truncated_to, "triples"))
+        }
+    }
+}
+
+/// Results of a query iterated as they are streamed
+pub(crate) type ResultIter<T> = Box<dyn Iterator<Item = Result<T, EvaluationError>>>;
+
+/// Computes results of a query before answering it: the first one, since evaluation often
+/// happens while computing it (e.g., to sort the solutions), so that a timeout is answered with
+/// an error status, and when there is a maximum number of results per response, one more than
+/// the maximum, to tell whether there are too many.
+///
+/// Also returns the maximum the results are truncated to, when there are too many of them and
+/// they are truncated.
+pub(crate) fn read_ahead<T: 'static>(
+    mut results: impl Iterator<Item = Result<T, EvaluationError>> + 'static,
+    guardrails: &SparqlGuardrails,
+    deadline: Option<Deadline>,
+) -> Result<(ResultIter<T>, Option<usize>), HttpError> {
+    let read_ahead_count = match guardrails.max_results {
+        Some(max_results) => max_results.saturating_add(1),
+        None => 1,
+    };
+    let mut read_ahead_results = Vec::new();
+    for result in results.by_ref().take(read_ahead_count) {
+        if let Some(deadline) = deadline {
+            deadline.check()?;
+        }
+        read_ahead_results.push(result.map_err(internal_server_error)?);
+    }
+    if let Some(deadline) = deadline {
+        deadline.check()?;
+    }
+
+    match (guardrails.max_results, guardrails.on_too_many_results) {
+        (Some(max_results), OnTooManyResults::Reject) if read_ahead_results.len() > max_results => {
+            Err(forbidden(format!(
+                "The query has more than {} results, the maximum allowed by this server: add a \
+                 LIMIT",
+                max_results
+            )))
+        }
+        (Some(max_results), OnTooManyResults::Truncate)
+            if read_ahead_results.len() > max_results =>
+        {
+            // The results past the maximum are never evaluated
+            read_ahead_results.truncate(max_results);
+            Ok((
+                Box::new(read_ahead_results.into_iter().map(Ok)),
+                Some(max_results),
+            ))
+        }
+        _ => Ok((
+            Box::new(read_ahead_results.into_iter().map(Ok).chain(results)),
+            None,
+        )),
+    }
+}
+
+/// Tells clients that a response stops at the maximum number of results, when its results were
+/// truncated.
+pub(crate) fn with_truncation_warning(
+    mut response: Response,
+    truncated_to: Option<usize>,
+    result_kind: &str,
+) -> Response {
+    if let Some(max_results) = truncated_to {
+        response
+            .append_header(
+                "Warning",
+                format!(
+                    "199 - \"results are truncated to the first {} {}\"",
+                    max_results, result_kind
+                ),
             )
+            .unwrap();
+    }
+    response
+}
+
+/// The number of triples of the dataset and of each of its graphs.
+pub struct TripleCounts {
+    dataset: u64,
+    default_graph: u64,
+    /// Graphs named by blank nodes are left out, as they can't be queried by name
+    named_graphs: Vec<(NamedNode, u64)>,
+}
+
+impl TripleCounts {
+    /// Counts the triples of each graph in a single scan of the store.
+    fn count(store: &Store) -> Result<Self, HttpError> {
+        let mut counts_by_graph_name: HashMap<GraphName, u64> = HashMap::new();
+        let mut dataset = 0;
+        for quad in store.iter() {
+            *counts_by_graph_name
+                .entry(quad.map_err(internal_server_error)?.graph_name)
+                .or_default() += 1;
+            dataset += 1;
         }
+        let mut named_graphs = Vec::new();
+        for named_graph in store.named_graphs() {
+            if let NamedOrBlankNode::NamedNode(named_graph) =
+                named_graph.map_err(internal_server_error)?
+            {
+                let count = counts_by_graph_name
+                    .get(&GraphName::from(named_graph.clone()))
+                    .copied()
+                    .unwrap_or_default();
+                named_graphs.push((named_graph, count));
+            }
+        }
+        Ok(Self {
+            dataset,
+            default_graph: counts_by_graph_name
+                .get(&GraphName::DefaultGraph)
+                .copied()
+                .unwrap_or_default(),
+            named_graphs,
+        })
     }
 }
 
+/// The triple counts of the service description, counted again after the store changes rather
+/// than on every request, since counting scans the whole store.
+#[derive(Default)]
+pub struct TripleCountCache {
+    counts: Mutex<Option<(u64, Arc<TripleCounts>)>>,
+}
+
+impl TripleCountCache {
+    /// The triple counts as of a generation of the store (see
+    /// `SearchIndexHandle::store_generation`).
+    pub fn get(
+        &self,
+        store: &Store,
+        store_generation: u64,
+    ) -> Result<Arc<TripleCounts>, HttpError> {
+        if let Some((counts_store_generation, counts)) = self
+            .counts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            if *counts_store_generation == store_generation {
+                return Ok(Arc::clone(counts));
+            }
+        }
+
+        // Counted without holding the lock, so that concurrent requests don't queue up behind it
+        let counts = Arc::new(TripleCounts::count(store)?);
+        *self.counts.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some((store_generation, Arc::clone(&counts)));
+        Ok(counts)
+    }
+}
+
+/// Describes the endpoint with the SPARQL 1.1 Service Description vocabulary: the query
+/// languages and result formats it supports, its features, and its dataset, with the triple
+/// counts of each graph as a VoID description.
+fn service_description(
+    store: &Store,
+    request: &Request,
+    extension_features: &[NamedNodeRef<'_>],
+    triple_counts: impl FnOnce(&Store) -> Result<Arc<TripleCounts>, HttpError>,
+) -> Result<Response, HttpError> {
+    let format = graph_content_negotiation(request)?;
+    let triple_counts = triple_counts(store)?;
+    let sd = |local_name: &str| NamedNode::new_unchecked(format!("{SD_NAMESPACE}{local_name}"));
+    let void = |local_name: &str| NamedNode::new_unchecked(format!("{VOID_NAMESPACE}{local_name}"));
+    let endpoint = NamedNode::new(base_url(request)).map_err(internal_server_error)?;
+
+    let mut triples = Vec::new();
+    let service = BlankNode::default();
+    triples.push(Triple::new(service.clone(), rdf::TYPE, sd("Service")));
+    triples.push(Triple::new(
+        service.clone(),
+        sd("endpoint"),
+        endpoint.clone(),
+    ));
+    for language in ["SPARQL10Query", "SPARQL11Query"] {
+        triples.push(Triple::new(
+            service.clone(),
+            sd("supportedLanguage"),
+            sd(language),
+        ));
+    }
+    for format_iri in QUERY_RESULTS_FORMATS
+        .map(QueryResultsFormat::iri)
+        .into_iter()
+        .chain(GRAPH_FORMATS.map(GraphFormat::iri))
+    {
+        triples.push(Triple::new(
+            service.clone(),
+            sd("resultFormat"),
+            NamedNode::new_unchecked(format_iri),
+        ));
+    }
+    for extension_feature in extension_features {
+        triples.push(Triple::new(
+            service.clone(),
+            sd("feature"),
+            *extension_feature,
+        ));
+        triples.push(Triple::new(*extension_feature, rdf::TYPE, sd("Feature")));
+    }
+
+    let dataset = BlankNode::default();
+    triples.push(Triple::new(
+        service.clone(),
+        sd("defaultDataset"),
+        dataset.clone(),
+    ));
+    triples.push(Triple::new(dataset.clone(), rdf::TYPE, sd("Dataset")));
+    triples.push(Triple::new(dataset.clone(), rdf::TYPE, void("Dataset")));
+    triples.push(Triple::new(
+        dataset.clone(),
+        void("sparqlEndpoint"),
+        endpoint,
+    ));
+    triples.push(Triple::new(
+        dataset.clone(),
+        void("triples"),
+        Literal::from(triple_counts.dataset),
+    ));
+    let default_graph = BlankNode::default();
+    triples.push(Triple::new(
+        dataset.clone(),
+        sd("defaultGraph"),
+        default_graph.clone(),
+    ));
+    triples.push(Triple::new(default_graph.clone(), rdf::TYPE, sd("Graph")));
+    triples.push(Triple::new(
+        default_graph,
+        void("triples"),
+        Literal::from(triple_counts.default_graph),
+    ));
+    for (named_graph, named_graph_triple_count) in &triple_counts.named_graphs {
+        let named_graph_description = BlankNode::default();
+        let graph = BlankNode::default();
+        triples.push(Triple::new(
+            dataset.clone(),
+            sd("namedGraph"),
+            named_graph_description.clone(),
+        ));
+        triples.push(Triple::new(
+            named_graph_description.clone(),
+            rdf::TYPE,
+            sd("NamedGraph"),
+        ));
+        triples.push(Triple::new(
+            named_graph_description.clone(),
+            sd("name"),
+            named_graph.clone(),
+        ));
+        triples.push(Triple::new(
+            named_graph_description,
+            sd("graph"),
+            graph.clone(),
+        ));
+        triples.push(Triple::new(graph.clone(), rdf::TYPE, sd("Graph")));
+        triples.push(Triple::new(
+            graph,
+            void("triples"),
+            Literal::from(*named_graph_triple_count),
+        ));
+    }
+
+    let mut body = Vec::new();
+    let mut writer = GraphSerializer::from_format(format)
+        .triple_writer(&mut body)
+        .map_err(internal_server_error)?;
+    for triple in &triples {
+        writer.write(triple).map_err(internal_server_error)?;
+    }
+    writer.finish().map_err(internal_server_error)?;
+    Ok(Response::builder(Status::OK)
+        .with_header(HeaderName::CONTENT_TYPE, format.media_type())
+        .unwrap()
+        .with_body(body))
+}
+
+/// Parses a query of the server over the union of the graphs of the store, so that concepts
+/// stored in named graphs (e.g., through `/store`) are indexed and described like those of the
+/// default graph.
+pub fn union_default_graph_query(query: &str) -> Result<Query, ParseError> {
+    let mut query = Query::parse(query, None)?;
+    query.dataset_mut().set_default_graph_as_union();
+    Ok(query)
+}
+
+/// Binds `?iri` to each of `iris` with a VALUES clause appended to a SPARQL query.
+///
+/// Oxigraph doesn't allow out-of-band variable binding like some SPARQL engines do.
+/// oxrdflib just adds a VALUES clause to the end of the query.
+pub fn with_iri_values<'a>(query: &str, iris: impl Iterator<Item = &'a str>) -> String {
+    let mut query_with_values = format!("{}\nVALUES ?iri {{", query);
+    for iri in iris {
+        query_with_values.push_str(" <");
+        query_with_values.push_str(iri);
+        query_with_values.push('>');
+    }
+    query_with_values.push_str(" }");
+    query_with_values
+}
+
 pub fn graph_content_negotiation(request: &Request) -> Result<GraphFormat, HttpError> {
     content_negotiation(
         request,
-        &[
-            GraphFormat::NTriples.media_type(),
-            GraphFormat::Turtle.media_type(),
-            GraphFormat::RdfXml.media_type(),
-        ],
+        &GRAPH_FORMATS.map(GraphFormat::media_type),
         GraphFormat::from_media_type,
     )
 }
 
-fn query_results_content_negotiation(request: &Request) -> Result<QueryResultsFormat, HttpError> {
+pub fn dataset_content_negotiation(request: &Request) -> Result<DatasetFormat, HttpError> {
     content_negotiation(
         request,
         &[
-            QueryResultsFormat::Json.media_type(),
-            QueryResultsFormat::Xml.media_type(),
-            QueryResultsFormat::Csv.media_type(),
-            QueryResultsFormat::Tsv.media_type(),
+            DatasetFormat::NQuads.media_type(),
+            DatasetFormat::TriG.media_type(),
         ],
+        DatasetFormat::from_media_type,
+    )
+}
+
+fn query_results_content_negotiation(request: &Request) -> Result<QueryResultsFormat, HttpError> {
+    content_negotiation(
+        request,
+        &QUERY_RESULTS_FORMATS.map(QueryResultsFormat::media_type),
         QueryResultsFormat::from_media_type,
     )
 }
 
-fn content_negotiation<F>(
+pub fn content_negotiation<F>(
     request: &Request,
     supported: &[&str],
     parse: impl Fn(&str) -> Option<F>,
@@ -293,7 +938,7 @@ fn content_negotiation<F>(
     parse(result).ok_or_else(|| internal_server_error("Unknown media type"))
 }
 
-fn content_type(request: &Request) -> Option<String> {
+pub(crate) fn content_type(request: &Request) -> Option<String> {
     let value = request.header(&HeaderName::CONTENT_TYPE)?.to_str().ok()?;
     Some(
         value
@@ -304,28 +949,35 @@ fn content_type(request: &Request) -> Option<String> {
     )
 }
 
-fn bad_request(message: impl fmt::Display) -> HttpError {
+pub(crate) fn bad_request(message: impl fmt::Display) -> HttpError {
     (Status::BAD_REQUEST, message.to_string())
 }
 
-fn unsupported_media_type(content_type: &str) -> HttpError {
+pub(crate) fn unsupported_media_type(content_type: &str) -> HttpError {
     (
         Status::UNSUPPORTED_MEDIA_TYPE,
         format!("No supported content Content-Type given: {content_type}"),
     )
 }
 
-fn internal_server_error(message: impl fmt::Display) -> HttpError {
+fn forbidden(message: impl fmt::Display) -> HttpError {
+    (Status::FORBIDDEN, message.to_string())
+}
+
+pub(crate) fn internal_server_error(message: impl fmt::Display) -> HttpError {
     eprintln!("Internal server error: {message}");
     (Status::INTERNAL_SERVER_ERROR, message.to_string())
 }
 
 /// Hacky tool to allow implementing read on top of a write loop
+///
+/// The loop stops at the `deadline`, dropping its state (e.g., a query's iterator).
 pub struct ReadForWrite<O, U: (Fn(O) -> io::Result<Option<O>>)> {
     buffer: Rc<RefCell<Vec<u8>>>,
     position: usize,
     add_more_data: U,
     state: Option<O>,
+    deadline: Option<Deadline>,
 }
 
 impl<O: 'static, U: (Fn(O) -> io::Result<Option<O>>) + 'static> ReadForWrite<O, U> {
@@ -333,6 +985,7 @@ impl<O: 'static, U: (Fn(O) -> io::Result<Option<O>>) + 'static> ReadForWrite<O,
         initial_state_builder: impl FnOnce(ReadForWriteWriter) -> io::Result<O>,
         add_more_data: U,
         content_type: &'static str,
+        deadline: Option<Deadline>,
     ) -> Result<Response, HttpError> {
         let buffer = Rc::new(RefCell::new(Vec::new()));
         let state = initial_state_builder(ReadForWriteWriter {
@@ -347,6 +1000,7 @@ impl<O: 'static, U: (Fn(O) -> io::Result<Option<O>>) + 'static> ReadForWrite<O,
                 position: 0,
                 add_more_data,
                 state: Some(state),
+                deadline,
             })))
     }
 }
@@ -358,7 +1012,12 @@ impl<O, U: (Fn(O) -> io::Result<Option<O>>)> Read for ReadForWrite<O, U> {
             if let Some(state) = self.state.take() {
                 self.buffer.borrow_mut().clear();
                 self.position = 0;
-                self.state = match (self.add_more_data)(state) {
+                let more_data = match self.deadline {
+                    Some(deadline) => deadline.check_io(),
+                    None => Ok(()),
+                }
+                .and_then(|()| (self.add_more_data)(state));
+                self.state = match more_data {
                     Ok(state) => state,
                     Err(e) => {
                         eprintln!("Internal server error while streaming results: {e}");
@@ -397,3 +1056,376 @@ impl Write for ReadForWriteWriter {
         self.buffer.borrow_mut().write_all(buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use oxhttp::model::Method;
+    use oxigraph::model::{GraphNameRef, Quad, Term};
+
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{self, EX};
+    use crate::vocab::kos;
+
+    /// The service description of an endpoint over a store, loaded in a store of its own.
+    fn describe_service(
+        store: &Store,
+        triple_count_cache: &TripleCountCache,
+        store_generation: u64,
+    ) -> Store {
+        let mut request = testing::request(Method::GET, "/sparql");
+        request
+            .append_header(HeaderName::ACCEPT, "application/n-triples")
+            .unwrap();
+        let response = handle_request(
+            &mut request,
+            store.clone(),
+            QueryOptions::default(),
+            &[kos::SEARCH],
+            |store| triple_count_cache.get(store, store_generation),
+            &testing::query_timeouts(),
+            &SparqlGuardrails::default(),
+        )
+        .unwrap();
+        let description = Store::new().unwrap();
+        description
+            .load_graph(
+                testing::body_string(response).as_bytes(),
+                GraphFormat::NTriples,
+                GraphNameRef::DefaultGraph,
+                None,
+            )
+            .unwrap();
+        description
+    }
+
+    /// The `void:triples` of the dataset, of its default graph and of its named graph.
+    fn described_triple_counts(description: &Store) -> (String, String, String) {
+        let QueryResults::Solutions(mut solutions) = description
+            .query(
+                "PREFIX sd: <http://www.w3.org/ns/sparql-service-description#>
+                PREFIX void: <http://rdfs.org/ns/void#>
+                SELECT ?dataset ?defaultGraph ?namedGraph WHERE {
+                    ?service sd:defaultDataset ?d .
+                    ?d void:triples ?dataset ;
+                        sd:defaultGraph/void:triples ?defaultGraph ;
+                        sd:namedGraph/sd:graph/void:triples ?namedGraph .
+                }",
+            )
+            .unwrap()
+        else {
+            panic!("the query is a SELECT query")
+        };
+        let solution = solutions.next().unwrap().unwrap();
+        assert!(solutions.next().is_none());
+        let count = |variable: &str| match solution.get(variable) {
+            Some(Term::Literal(count)) => String::from(count.value()),
+            term => panic!("{variable} is not a count: {term:?}"),
+        };
+        (count("dataset"), count("defaultGraph"), count("namedGraph"))
+    }
+
+    #[test]
+    fn describes_the_service_and_its_dataset() {
+        let store = testing::store();
+        let default_graph_triple_count = store.len().unwrap();
+        let named_graph_quad = |label: &str| {
+            Quad::new(
+                NamedNode::new_unchecked(format!("{EX}horse")),
+                NamedNode::new_unchecked("http://www.w3.org/2004/02/skos/core#prefLabel"),
+                Literal::from(label),
+                NamedNode::new_unchecked(format!("{EX}horses")),
+            )
+        };
+        store.insert(&named_graph_quad("Horse")).unwrap();
+        let triple_count_cache = TripleCountCache::default();
+
+        let description = describe_service(&store, &triple_count_cache, 0);
+        assert!(description
+            .contains(&Quad::new(
+                kos::SEARCH,
+                rdf::TYPE,
+                NamedNode::new_unchecked(format!("{SD_NAMESPACE}Feature")),
+                GraphName::DefaultGraph,
+            ))
+            .unwrap());
+        assert_eq!(
+            described_triple_counts(&description),
+            (
+                (default_graph_triple_count + 1).to_string(),
+                default_graph_triple_count.to_string(),
+                String::from("1")
+            )
+        );
+
+        // Counted again once the store changes
+        store.insert(&named_graph_quad("Pony")).unwrap();
+        assert_eq!(
+            described_triple_counts(&describe_service(&store, &triple_count_cache, 0)).2,
+            "1"
+        );
+        assert_eq!(
+            described_triple_counts(&describe_service(&store, &triple_count_cache, 1)).2,
+            "2"
+        );
+    }
+
+    #[test]
+    fn reads_the_timeout_of_form_encoded_queries() {
+        let store = testing::store();
+        let post_query = |body: &str| {
+            let mut request = testing::request_with_body(
+                Method::POST,
+                "/sparql",
+                "application/x-www-form-urlencoded",
+                body,
+            );
+            handle_request(
+                &mut request,
+                store.clone(),
+                QueryOptions::default(),
+                &[],
+                |_| unreachable!("queries don't describe the service"),
+                &testing::query_timeouts(),
+                &SparqlGuardrails::default(),
+            )
+        };
+        assert_eq!(
+            post_query("query=SELECT%20*%20WHERE%20%7B%20%3Fs%20%3Fp%20%3Fo%20%7D")
+                .unwrap()
+                .status(),
+            Status::OK
+        );
+        assert_eq!(
+            post_query("query=SELECT%20*%20WHERE%20%7B%20%3Fs%20%3Fp%20%3Fo%20%7D&timeout=0")
+                .unwrap_err()
+                .0,
+            Status::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            post_query("query=SELECT%20*%20WHERE%20%7B%20%3Fs%20%3Fp%20%3Fo%20%7D&timeout=x")
+                .unwrap_err()
+                .0,
+            Status::BAD_REQUEST
+        );
+    }
+
+    fn summarize(query: &str) -> QueryAlgebraSummary {
+        let spargebra::Query::Select { pattern, .. } =
+            spargebra::Query::parse(query, None).unwrap()
+        else {
+            panic!("{query} is not a SELECT query")
+        };
+        let mut query_algebra_summary = QueryAlgebraSummary::default();
+        query_algebra_summary.add_graph_pattern(&pattern);
+        query_algebra_summary
+    }
+
+    #[test]
+    fn summarizes_query_algebras() {
+        // The projection, the filter and the triple patterns inside and outside of EXISTS
+        let query_algebra_summary =
+            summarize("SELECT * WHERE { ?s ?p ?o FILTER EXISTS { ?o ?q ?r . ?r ?q ?s } }");
+        assert_eq!(query_algebra_summary.complexity, 5);
+        assert!(!query_algebra_summary.has_property_paths);
+        assert!(query_algebra_summary.service_names.is_empty());
+
+        let query_algebra_summary = summarize("SELECT ?s WHERE { ?s <http://example.com/p>+ ?o }");
+        assert_eq!(query_algebra_summary.complexity, 2);
+        assert!(query_algebra_summary.has_property_paths);
+
+        let query_algebra_summary = summarize(
+            "SELECT * WHERE { SERVICE <urn:kos-kit:search> { ?s ?p ?o } SERVICE ?service {} }",
+        );
+        assert_eq!(
+            query_algebra_summary
+                .service_names
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["<urn:kos-kit:search>", "?service"]
+        );
+    }
+
+    #[test]
+    fn guardrails_refuse_denied_queries() {
+        let guardrails = SparqlGuardrails {
+            max_query_complexity: Some(3),
+            denied_features: vec![SparqlFeature::PropertyPath, SparqlFeature::Service],
+            allowed_services: vec![String::from("urn:kos-kit:search")],
+            ..SparqlGuardrails::default()
+        };
+        let check = |query: &str| {
+            guardrails
+                .check_query(&spargebra::Query::parse(query, None).unwrap())
+                .map_err(|(status, _)| status)
+        };
+        assert_eq!(check("SELECT * WHERE { ?s ?p ?o }"), Ok(()));
+        assert_eq!(
+            check("SELECT * WHERE { SERVICE <urn:kos-kit:search> { ?s ?p ?o } }"),
+            Ok(())
+        );
+        for denied_query in [
+            "SELECT * WHERE { ?s ?p ?o . ?o ?p ?s . ?s ?q ?r . ?r ?q ?s }",
+            "SELECT ?s WHERE { ?s <http://example.com/p>* ?o }",
+            "SELECT * WHERE { SERVICE <http://example.com/sparql> { ?s ?p ?o } }",
+        ] {
+            assert_eq!(
+                check(denied_query),
+                Err(Status::FORBIDDEN),
+                "{denied_query}"
+            );
+        }
+    }
+
+    /// Numbers evaluated one at a time, counting the evaluations.
+    fn counted_results(
+        count: usize,
+        evaluation_count: &Rc<RefCell<usize>>,
+    ) -> impl Iterator<Item = Result<usize, EvaluationError>> + 'static {
+        let evaluation_count = Rc::clone(evaluation_count);
+        (0..count).map(move |result| {
+            *evaluation_count.borrow_mut() += 1;
+            Ok(result)
+        })
+    }
+
+    fn guardrails(max_results: usize, on_too_many_results: OnTooManyResults) -> SparqlGuardrails {
+        SparqlGuardrails {
+            max_results: Some(max_results),
+            on_too_many_results,
+            ..SparqlGuardrails::default()
+        }
+    }
+
+    #[test]
+    fn reads_ahead_one_more_result_than_the_maximum() {
+        let evaluation_count = Rc::new(RefCell::new(0));
+        let (results, truncated_to) = read_ahead(
+            counted_results(5, &evaluation_count),
+            &SparqlGuardrails::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(*evaluation_count.borrow(), 1);
+        assert_eq!(results.count(), 5);
+        assert_eq!(truncated_to, None);
+
+        // Truncated results past the one after the maximum are never evaluated
+        let evaluation_count = Rc::new(RefCell::new(0));
+        let (results, truncated_to) = read_ahead(
+            counted_results(5, &evaluation_count),
+            &guardrails(2, OnTooManyResults::Truncate),
+            None,
+        )
+        .unwrap();
+        assert_eq!(*evaluation_count.borrow(), 3);
+        assert_eq!(results.map(Result::unwrap).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(*evaluation_count.borrow(), 3);
+        assert_eq!(truncated_to, Some(2));
+        let (results, truncated_to) = read_ahead(
+            counted_results(2, &evaluation_count),
+            &guardrails(2, OnTooManyResults::Truncate),
+            None,
+        )
+        .unwrap();
+        assert_eq!(results.count(), 2);
+        assert_eq!(truncated_to, None);
+
+        let evaluation_count = Rc::new(RefCell::new(0));
+        assert_eq!(
+            read_ahead(
+                counted_results(5, &evaluation_count),
+                &guardrails(2, OnTooManyResults::Reject),
+                None,
+            )
+            .err()
+            .unwrap()
+            .0,
+            Status::FORBIDDEN
+        );
+        assert_eq!(*evaluation_count.borrow(), 3);
+        let (results, truncated_to) = read_ahead(
+            counted_results(2, &evaluation_count),
+            &guardrails(2, OnTooManyResults::Reject),
+            None,
+        )
+        .unwrap();
+        assert_eq!(results.count(), 2);
+        assert_eq!(truncated_to, None);
+    }
+
+    #[test]
+    fn reads_ahead_until_the_deadline() {
+        let deadline = QueryTimeouts {
+            default: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+        .deadline(&[])
+        .unwrap();
+        assert_eq!(
+            read_ahead(
+                counted_results(5, &Rc::new(RefCell::new(0))),
+                &SparqlGuardrails::default(),
+                Some(deadline),
+            )
+            .err()
+            .unwrap()
+            .0,
+            Status::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[test]
+    fn truncates_responses_to_the_maximum_number_of_results() {
+        let store = testing::store();
+        let query = |guardrails: &SparqlGuardrails| {
+            let mut request = testing::request(
+                Method::GET,
+                "/sparql?query=SELECT%20*%20WHERE%20%7B%20%3Fs%20%3Fp%20%3Fo%20%7D",
+            );
+            handle_request(
+                &mut request,
+                store.clone(),
+                QueryOptions::default(),
+                &[],
+                |_| unreachable!("queries don't describe the service"),
+                &testing::query_timeouts(),
+                guardrails,
+            )
+        };
+
+        let response = query(&guardrails(2, OnTooManyResults::Truncate)).unwrap();
+        assert_eq!(
+            response
+                .header(&HeaderName::from_str("Warning").unwrap())
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "199 - \"results are truncated to the first 2 solutions\""
+        );
+        assert_eq!(
+            testing::body_json(response)["results"]["bindings"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            query(&guardrails(2, OnTooManyResults::Reject))
+                .unwrap_err()
+                .0,
+            Status::FORBIDDEN
+        );
+        let response = query(&SparqlGuardrails::default()).unwrap();
+        assert!(response
+            .header(&HeaderName::from_str("Warning").unwrap())
+            .is_none());
+        // Responses with up to the maximum number of results are not truncated
+        let response = query(&guardrails(1000, OnTooManyResults::Truncate)).unwrap();
+        assert!(response
+            .header(&HeaderName::from_str("Warning").unwrap())
+            .is_none());
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+use oxhttp::model::{HeaderName, Request, Response, Status};
+use serde_json::json;
+use tantivy::{
+    query::{BooleanQuery, Occur, Query, TermQuery},
+    schema::{IndexRecordOption, Value},
+    IndexReader, Searcher, TantivyDocument, Term,
+};
+use url::Url;
+
+use crate::analysis::{DEFAULT_TOKENIZER_NAME, SUGGEST_MAX_GRAM};
+use crate::collector::{IriCollector, IriPageCollector};
+use crate::search::{parse_languages, parse_limit};
+
+type HttpError = (Status, String);
+
+struct ParsedUrl {
+    /// Requested language tags, lowercased, in order of preference
+    languages: Vec<String>,
+    limit: usize,
+    q: String,
+}
+
+impl ParsedUrl {
+    fn parse(url: &Url) -> Result<Self, String> {
+        let url_query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        Ok(Self {
+            languages: parse_languages(url),
+            limit: parse_limit(&url_query)?,
+            q: url_query.get("q").ok_or("missing q string")?.clone(),
+        })
+    }
+}
+
+/// A concept with a text having words starting with every word of a search.
+pub(crate) struct Suggestion {
+    pub(crate) iri: String,
+    /// The matching text
+    pub(crate) label: Option<String>,
+    pub(crate) lang: Option<String>,
+    pub(crate) score: f32,
+    pub(crate) types: Vec<String>,
+}
+
+/// Suggests concepts whose texts have words starting with every word of `q`, from the stored
+/// fields of the index alone.
+///
+/// Only the `limit` best concepts are sorted and have their documents read, as short prefixes
+/// match most of the index.
+pub(crate) fn suggest(
+    tantivy_index_searcher: &Searcher,
+    q: &str,
+    languages: Vec<String>,
+    limit: usize,
+) -> Result<Vec<Suggestion>, HttpError> {
+    let schema = tantivy_index_searcher.schema();
+    let internal_server_error = |err: tantivy::TantivyError| {
+        (
+            Status::INTERNAL_SERVER_ERROR,
+            format!("error reading index: {}", err),
+        )
+    };
+    let suggest_field = schema.get_field("suggest").map_err(internal_server_error)?;
+    let lang_field = schema.get_field("lang").map_err(internal_server_error)?;
+    // The types are a facet, which may not have been configured
+    let type_field = schema.get_field("type").ok();
+
+    // Analyze q like the indexed texts before their edge n-grams were taken, so that each word
+    // of q matches the n-gram of the same length
+    let mut text_analyzer = tantivy_index_searcher
+        .index()
+        .tokenizers()
+        .get(DEFAULT_TOKENIZER_NAME)
+        .ok_or_else(|| {
+            (
+                Status::INTERNAL_SERVER_ERROR,
+                format!("tokenizer {} is not registered", DEFAULT_TOKENIZER_NAME),
+            )
+        })?;
+    let mut subqueries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+    let mut token_stream = text_analyzer.token_stream(q);
+    while token_stream.advance() {
+        let word = &token_stream.token().text;
+        let gram = match word.char_indices().nth(SUGGEST_MAX_GRAM) {
+            Some((gram_end, _)) => &word[..gram_end],
+            None => word.as_str(),
+        };
+        subqueries.push((
+            Occur::Must,
+            Box::new(TermQuery::new(
+                Term::from_field_text(suggest_field, gram),
+                IndexRecordOption::WithFreqs,
+            )),
+        ));
+    }
+
+    let mut suggestions = Vec::new();
+    if !subqueries.is_empty() && limit > 0 {
+        let iri_page = tantivy_index_searcher
+            .search(
+                &BooleanQuery::new(subqueries),
+                &IriPageCollector::new(
+                    IriCollector::new("iri").with_language_preference("lang", languages),
+                    limit,
+                ),
+            )
+            .map_err(|err| {
+                (
+                    Status::INTERNAL_SERVER_ERROR,
+                    format!("error searching index:\nQuery: {}\nError: {}", q, err),
+                )
+            })?;
+
+        for iri_hit in iri_page.hits {
+            let document: TantivyDocument = tantivy_index_searcher
+                .doc(iri_hit.doc_address)
+                .map_err(internal_server_error)?;
+            suggestions.push(Suggestion {
+                iri: iri_hit.iri,
+                label: document
+                    .get_first(suggest_field)
+                    .and_then(|value| value.as_str())
+                    .map(String::from),
+                // The full language tag comes before its prefixes
+                lang: document
+                    .get_first(lang_field)
+                    .and_then(|value| value.as_str())
+                    .map(String::from),
+                score: iri_hit.score,
+                types: type_field
+                    .map(|type_field| {
+                        document
+                            .get_all(type_field)
+                            .filter_map(|value| value.as_str())
+                            .map(String::from)
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default(),
+            });
+        }
+    }
+    Ok(suggestions)
+}
+
+/// Suggests concepts whose texts have words starting with every word of `q`.
+///
+/// Suggestions are answered from the stored fields of the index alone, without querying
+/// Oxigraph, as a JSON array of `{"iri", "label", "lang", "types", "score"}` objects.
+pub fn handle_request(
+    request: &mut Request,
+    tantivy_index_reader: &IndexReader,
+) -> Result<Response, HttpError> {
+    if request.method().as_ref() != "GET" {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    let parsed_url =
+        ParsedUrl::parse(request.url()).map_err(|err_string| (Status::BAD_REQUEST, err_string))?;
+
+    let suggestions = suggest(
+        &tantivy_index_reader.searcher(),
+        &parsed_url.q,
+        parsed_url.languages,
+        parsed_url.limit,
+    )?
+    .into_iter()
+    .map(|suggestion| {
+        json!({
+            "iri": suggestion.iri,
+            "label": suggestion.label,
+            "lang": suggestion.lang,
+            "types": suggestion.types,
+            "score": suggestion.score,
+        })
+    })
+    .collect::<Vec<_>>();
+
+    Ok(Response::builder(Status::OK)
+        .with_header(HeaderName::CONTENT_TYPE, "application/json")
+        .unwrap()
+        .with_body(serde_json::Value::Array(suggestions).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use oxhttp::model::Method;
+
+    use super::*;
+    use crate::testing::{self, EX};
+
+    fn get_suggest(path_and_query: &str) -> Vec<String> {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let response = handle_request(
+            &mut testing::request(Method::GET, path_and_query),
+            &search_index_handle.current().reader,
+        )
+        .unwrap();
+        testing::body_json(response)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|suggestion| String::from(suggestion["iri"].as_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn suggests_concepts_with_words_starting_with_every_word() {
+        let mut iris = get_suggest("/suggest?q=CA");
+        iris.sort();
+        assert_eq!(iris, [format!("{EX}cat"), format!("{EX}catfish")]);
+        assert_eq!(get_suggest("/suggest?q=ho%20ca"), [format!("{EX}cat")]);
+        assert!(get_suggest("/suggest?q=cx").is_empty());
+        assert_eq!(get_suggest("/suggest?q=ca&limit=1").len(), 1);
+    }
+}
@@ -0,0 +1,155 @@
+//! Fixtures of the unit tests: a small vocabulary in an in-memory store, indexed in RAM with the
+//! default index init and result queries.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use oxhttp::model::{HeaderName, Method, Request, Response};
+use oxigraph::io::GraphFormat;
+use oxigraph::model::GraphNameRef;
+use oxigraph::store::Store;
+use tantivy::collector::Count;
+use tantivy::query::TermQuery;
+use tantivy::schema::IndexRecordOption;
+use tantivy::Term;
+use url::Url;
+
+use crate::search::SearchMode;
+use crate::search_index::{SearchIndexConfig, SearchIndexHandle};
+use crate::timeout::QueryTimeouts;
+
+pub(crate) const INDEX_INIT_SPARQL: &str = include_str!("./index_init.sparql");
+pub(crate) const INDEX_RESULT_SPARQL: &str = include_str!("./index_result.sparql");
+
+pub(crate) const VOCABULARY_TURTLE: &str = r#"
+@prefix ex: <http://example.com/> .
+@prefix rdfs: <http://www.w3.org/2000/01/rdf-schema#> .
+@prefix skos: <http://www.w3.org/2004/02/skos/core#> .
+@prefix skosxl: <http://www.w3.org/2008/05/skos-xl#> .
+
+ex:animals a skos:ConceptScheme ;
+    skos:prefLabel "Animals"@en .
+
+ex:cat a skos:Concept ;
+    skos:inScheme ex:animals ;
+    skos:prefLabel "Cat"@en, "Chat"@fr ;
+    rdfs:label "House cat"@en ;
+    skos:notation "A1" .
+
+ex:dog a skos:Concept ;
+    skos:inScheme ex:animals ;
+    skos:prefLabel "Dog"@en, "Chien"@fr ;
+    skos:notation "A2" .
+
+ex:catfish a skos:Concept ;
+    skos:prefLabel "Catfish"@en ;
+    skos:notation "B1" .
+
+ex:lion a skos:Concept ;
+    skos:inScheme ex:animals ;
+    skosxl:prefLabel [ skosxl:literalForm "Lion"@en ] .
+"#;
+
+pub(crate) const EX: &str = "http://example.com/";
+
+/// The vocabulary in an in-memory store.
+pub(crate) fn store() -> Store {
+    let store = Store::new().unwrap();
+    store
+        .load_graph(
+            VOCABULARY_TURTLE.as_bytes(),
+            GraphFormat::Turtle,
+            GraphNameRef::DefaultGraph,
+            None,
+        )
+        .unwrap();
+    store
+}
+
+/// The defaults of the command line arguments.
+pub(crate) fn search_index_config() -> SearchIndexConfig {
+    SearchIndexConfig {
+        default_search_mode: SearchMode::Exact,
+        facet_field_names: vec![String::from("type"), String::from("scheme")],
+        field_boosts: Vec::new(),
+        fuzzy_distance: 1,
+        fuzzy_prefix_length: 0,
+        index_init_sparql: String::from(INDEX_INIT_SPARQL),
+        index_init_sparql_file_path: None,
+        language_codes: vec![String::from("en")],
+        suggest_field_names: Vec::new(),
+    }
+}
+
+/// The store indexed in RAM.
+pub(crate) fn search_index_handle(store: &Store) -> Arc<SearchIndexHandle> {
+    search_index_handle_with_config(store, search_index_config())
+}
+
+pub(crate) fn search_index_handle_with_config(
+    store: &Store,
+    search_index_config: SearchIndexConfig,
+) -> Arc<SearchIndexHandle> {
+    Arc::new(SearchIndexHandle::open(search_index_config, store, None).unwrap())
+}
+
+/// The number of documents of a concept in the current index.
+pub(crate) fn count_documents(search_index_handle: &SearchIndexHandle, iri: &str) -> usize {
+    let searcher = search_index_handle.current().reader.searcher();
+    let iri_field = searcher.schema().get_field("iri").unwrap();
+    searcher
+        .search(
+            &TermQuery::new(
+                Term::from_field_text(iri_field, iri),
+                IndexRecordOption::Basic,
+            ),
+            &Count,
+        )
+        .unwrap()
+}
+
+pub(crate) fn query_timeouts() -> QueryTimeouts {
+    QueryTimeouts {
+        default: Duration::from_secs(30),
+        max: Duration::from_secs(60),
+    }
+}
+
+/// A request to a path and query of the server, e.g. `/search?query=cat`.
+pub(crate) fn request(method: Method, path_and_query: &str) -> Request {
+    Request::builder(
+        method,
+        Url::parse("http://localhost")
+            .unwrap()
+            .join(path_and_query)
+            .unwrap(),
+    )
+    .build()
+}
+
+/// A request with a body, e.g. a `POST` of a SPARQL update.
+pub(crate) fn request_with_body(
+    method: Method,
+    path_and_query: &str,
+    content_type: &str,
+    body: &str,
+) -> Request {
+    Request::builder(
+        method,
+        Url::parse("http://localhost")
+            .unwrap()
+            .join(path_and_query)
+            .unwrap(),
+    )
+    .with_header(HeaderName::CONTENT_TYPE, content_type)
+    .unwrap()
+    .with_body(String::from(body))
+}
+
+pub(crate) fn body_string(response: Response) -> String {
+    response.into_body().to_string().unwrap()
+}
+
+pub(crate) fn body_json(response: Response) -> serde_json::Value {
+    serde_json::from_str(&body_string(response)).unwrap()
+}
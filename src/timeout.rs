@@ -0,0 +1,134 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use oxhttp::model::Status;
+use url::form_urlencoded;
+
+type HttpError = (Status, String);
+
+/// Time limits of `/sparql` and `/search` queries: a default, which the `timeout` parameter of a
+/// request (in seconds) can lower or raise up to a maximum.
+#[derive(Clone, Copy)]
+pub struct QueryTimeouts {
+    pub default: Duration,
+    pub max: Duration,
+}
+
+impl QueryTimeouts {
+    /// The deadline of the query of a request, starting now, from the `timeout` parameter of its
+    /// form-encoded parameters: its URL query, and its body for form-encoded `POST`s.
+    pub fn deadline(&self, encoded: &[&[u8]]) -> Result<Deadline, HttpError> {
+        let timeout = match encoded
+            .iter()
+            .flat_map(|encoded| form_urlencoded::parse(encoded))
+            .find(|(key, _)| key == "timeout")
+        {
+            Some((_, timeout_string)) => timeout_string
+                .parse::<f64>()
+                .ok()
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .ok_or_else(|| {
+                    (
+                        Status::BAD_REQUEST,
+                        format!(
+                            "error parsing timeout: {timeout_string} is not a number of seconds"
+                        ),
+                    )
+                })?,
+            None => self.default,
+        }
+        .min(self.max);
+        Ok(Deadline {
+            instant: Instant::now() + timeout,
+            timeout,
+        })
+    }
+}
+
+/// When a query must be evaluated by.
+///
+/// Neither Oxigraph nor Tantivy can interrupt an evaluation, so the deadline is only checked
+/// between its steps (e.g., between solutions): a step running past the deadline, such as a
+/// search or the computation of a solution, runs to its end before the query is stopped.
+#[derive(Clone, Copy)]
+pub struct Deadline {
+    instant: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    pub fn check(&self) -> Result<(), HttpError> {
+        if Instant::now() < self.instant {
+            Ok(())
+        } else {
+            Err((Status::SERVICE_UNAVAILABLE, self.message()))
+        }
+    }
+
+    /// Checks the deadline while streaming a response, once its status is sent.
+    pub fn check_io(&self) -> io::Result<()> {
+        if Instant::now() < self.instant {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::TimedOut, self.message()))
+        }
+    }
+
+    fn message(&self) -> String {
+        format!(
+            "the query was stopped because it ran past its timeout of {}s",
+            self.timeout.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeouts() -> QueryTimeouts {
+        QueryTimeouts {
+            default: Duration::from_secs(30),
+            max: Duration::from_secs(60),
+        }
+    }
+
+    fn timeout(encoded: &[&[u8]]) -> Result<Duration, Status> {
+        timeouts()
+            .deadline(encoded)
+            .map(|deadline| deadline.timeout)
+            .map_err(|(status, _)| status)
+    }
+
+    #[test]
+    fn reads_timeouts_from_the_url_query_or_the_body() {
+        assert_eq!(timeout(&[b"query=q"]), Ok(Duration::from_secs(30)));
+        assert_eq!(
+            timeout(&[b"query=q&timeout=1.5"]),
+            Ok(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            timeout(&[b"", b"query=q&timeout=5"]),
+            Ok(Duration::from_secs(5))
+        );
+        // Capped by the maximum
+        assert_eq!(timeout(&[b"timeout=600"]), Ok(Duration::from_secs(60)));
+        for invalid_timeout in [&b"timeout=soon"[..], b"timeout=-1", b"timeout=NaN"] {
+            assert_eq!(timeout(&[invalid_timeout]), Err(Status::BAD_REQUEST));
+        }
+    }
+
+    #[test]
+    fn deadlines_pass_once_the_timeout_is_over() {
+        let deadline = timeouts().deadline(&[b"timeout=60"]).unwrap();
+        assert!(deadline.check().is_ok());
+        assert!(deadline.check_io().is_ok());
+
+        let deadline = timeouts().deadline(&[b"timeout=0"]).unwrap();
+        assert_eq!(deadline.check().unwrap_err().0, Status::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            deadline.check_io().unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
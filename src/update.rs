@@ -0,0 +1,534 @@
+use std::io::Read;
+use std::sync::Arc;
+
+use oxhttp::model::{Request, Response, Status};
+use oxigraph::model::{BlankNode, GraphName, GraphNameRef, NamedNode, Quad, Subject, Term};
+use oxigraph::sparql::{EvaluationError, QueryResults, QuerySolution, Update};
+use oxigraph::store::{Store, Transaction};
+use spargebra::algebra::{GraphTarget, QueryDataset};
+use spargebra::term::{
+    GraphNamePattern, GroundQuadPattern, GroundSubject, GroundTerm, GroundTermPattern,
+    NamedNodePattern, QuadPattern, TermPattern,
+};
+use spargebra::GraphUpdateOperation;
+use url::form_urlencoded;
+
+use crate::auth::check_bearer_token;
+use crate::search_index::SearchIndexHandle;
+use crate::sparql::{
+    bad_request, base_url, content_type, internal_server_error, unsupported_media_type, url_query,
+    MAX_SPARQL_BODY_SIZE,
+};
+
+type HttpError = (Status, String);
+
+/// What an update changed in the store, for re-indexing.
+#[derive(Default)]
+struct UpdateChanges {
+    /// Quads inserted or deleted by the update, or that it may have inserted or deleted
+    quads: Vec<Quad>,
+    /// Whether the update loaded files, whose quads are only known once parsed by Oxigraph
+    loaded: bool,
+}
+
+fn graph_name(graph_name: spargebra::term::GraphName) -> GraphName {
+    match graph_name {
+        spargebra::term::GraphName::NamedNode(graph_name) => graph_name.into(),
+        spargebra::term::GraphName::DefaultGraph => GraphName::DefaultGraph,
+    }
+}
+
+fn ground_subject(subject: GroundSubject) -> Option<Subject> {
+    match subject {
+        GroundSubject::NamedNode(subject) => Some(subject.into()),
+        // Quoted triples are not concepts
+        _ => None,
+    }
+}
+
+fn ground_term(term: GroundTerm) -> Option<Term> {
+    match term {
+        GroundTerm::NamedNode(term) => Some(term.into()),
+        GroundTerm::Literal(term) => Some(term.into()),
+        _ => None,
+    }
+}
+
+fn instantiate_term(pattern: &TermPattern, solution: &QuerySolution) -> Option<Term> {
+    match pattern {
+        TermPattern::NamedNode(term) => Some(term.clone().into()),
+        // Blank nodes of templates are fresh for each solution
+        TermPattern::BlankNode(_) => Some(BlankNode::default().into()),
+        TermPattern::Literal(term) => Some(term.clone().into()),
+        TermPattern::Variable(variable) => solution.get(variable).cloned(),
+        _ => None,
+    }
+}
+
+fn instantiate_ground_term(pattern: &GroundTermPattern, solution: &QuerySolution) -> Option<Term> {
+    match pattern {
+        GroundTermPattern::NamedNode(term) => Some(term.clone().into()),
+        GroundTermPattern::Literal(term) => Some(term.clone().into()),
+        GroundTermPattern::Variable(variable) => solution.get(variable).cloned(),
+        _ => None,
+    }
+}
+
+fn instantiate_named_node(
+    pattern: &NamedNodePattern,
+    solution: &QuerySolution,
+) -> Option<NamedNode> {
+    match pattern {
+        NamedNodePattern::NamedNode(named_node) => Some(named_node.clone()),
+        NamedNodePattern::Variable(variable) => match solution.get(variable) {
+            Some(Term::NamedNode(named_node)) => Some(named_node.clone()),
+            _ => None,
+        },
+    }
+}
+
+fn instantiate_graph_name(
+    pattern: &GraphNamePattern,
+    solution: &QuerySolution,
+) -> Option<GraphName> {
+    match pattern {
+        GraphNamePattern::NamedNode(graph_name) => Some(graph_name.clone().into()),
+        GraphNamePattern::DefaultGraph => Some(GraphName::DefaultGraph),
+        GraphNamePattern::Variable(variable) => match solution.get(variable) {
+            Some(Term::NamedNode(graph_name)) => Some(graph_name.clone().into()),
+            Some(Term::BlankNode(graph_name)) => Some(graph_name.clone().into()),
+            _ => None,
+        },
+    }
+}
+
+/// A quad of a template instantiated with a solution of the `WHERE` clause, if its subject is
+/// bound to a resource and all its other positions are bound.
+fn instantiate_quad(
+    subject: Option<Term>,
+    predicate: Option<NamedNode>,
+    object: Option<Term>,
+    graph_name: Option<GraphName>,
+) -> Option<Quad> {
+    let subject = match subject? {
+        Term::NamedNode(subject) => Subject::from(subject),
+        Term::BlankNode(subject) => Subject::from(subject),
+        Term::Triple(subject) => Subject::from(subject),
+        Term::Literal(_) => return None,
+    };
+    Some(Quad::new(subject, predicate?, object?, graph_name?))
+}
+
+/// The quads a `DELETE`/`INSERT` operation may change: its templates, instantiated with the
+/// solutions of its `WHERE` clause before it is applied.
+fn delete_insert_quads(
+    transaction: &Transaction<'_>,
+    delete: &[GroundQuadPattern],
+    insert: &[QuadPattern],
+    using: &Option<QueryDataset>,
+    pattern: &spargebra::algebra::GraphPattern,
+) -> Result<Vec<Quad>, EvaluationError> {
+    let QueryResults::Solutions(solutions) = transaction.query(spargebra::Query::Select {
+        dataset: using.clone(),
+        pattern: pattern.clone(),
+        base_iri: None,
+    })?
+    else {
+        return Ok(Vec::new());
+    };
+    let mut quads = Vec::new();
+    for solution in solutions {
+        let solution = solution?;
+        for quad_pattern in delete {
+            quads.extend(instantiate_quad(
+                instantiate_ground_term(&quad_pattern.subject, &solution),
+                instantiate_named_node(&quad_pattern.predicate, &solution),
+                instantiate_ground_term(&quad_pattern.object, &solution),
+                instantiate_graph_name(&quad_pattern.graph_name, &solution),
+            ));
+        }
+        for quad_pattern in insert {
+            quads.extend(instantiate_quad(
+                instantiate_term(&quad_pattern.subject, &solution),
+                instantiate_named_node(&quad_pattern.predicate, &solution),
+                instantiate_term(&quad_pattern.object, &solution),
+                instantiate_graph_name(&quad_pattern.graph_name, &solution),
+            ));
+        }
+    }
+    Ok(quads)
+}
+
+/// The quads of the graphs a `CLEAR` or `DROP` operation empties, before it is applied.
+fn graph_target_quads(
+    transaction: &Transaction<'_>,
+    graph_target: &GraphTarget,
+) -> Result<Vec<Quad>, EvaluationError> {
+    let quads = match graph_target {
+        GraphTarget::NamedNode(graph_name) => {
+            transaction.quads_for_pattern(None, None, None, Some(graph_name.as_ref().into()))
+        }
+        GraphTarget::DefaultGraph => {
+            transaction.quads_for_pattern(None, None, None, Some(GraphNameRef::DefaultGraph))
+        }
+        GraphTarget::NamedGraphs | GraphTarget::AllGraphs => {
+            transaction.quads_for_pattern(None, None, None, None)
+        }
+    };
+    let mut graph_target_quads = Vec::new();
+    for quad in quads {
+        let quad = quad?;
+        if *graph_target != GraphTarget::NamedGraphs || !quad.graph_name.is_default_graph() {
+            graph_target_quads.push(quad);
+        }
+    }
+    Ok(graph_target_quads)
+}
+
+/// Applies the operations of an update one after the other in a single transaction, gathering
+/// the quads each may change in the state the previous ones left the store in.
+fn apply_update(
+    oxigraph_store: &Store,
+    update: &spargebra::Update,
+) -> Result<UpdateChanges, EvaluationError> {
+    oxigraph_store.transaction(|mut transaction| {
+        let mut update_changes = UpdateChanges::default();
+        for operation in &update.operations {
+            match operation {
+                GraphUpdateOperation::InsertData { data } => {
+                    update_changes.quads.extend(data.iter().map(|quad| {
+                        Quad::new(
+                            quad.subject.clone(),
+                            quad.predicate.clone(),
+                            quad.object.clone(),
+                            graph_name(quad.graph_name.clone()),
+                        )
+                    }))
+                }
+                GraphUpdateOperation::DeleteData { data } => {
+                    update_changes.quads.extend(data.iter().filter_map(|quad| {
+                        Some(Quad::new(
+                            ground_subject(quad.subject.clone())?,
+                            quad.predicate.clone(),
+                            ground_term(quad.object.clone())?,
+                            graph_name(quad.graph_name.clone()),
+                        ))
+                    }))
+                }
+                GraphUpdateOperation::DeleteInsert {
+                    delete,
+                    insert,
+                    using,
+                    pattern,
+                } => update_changes.quads.extend(delete_insert_quads(
+                    &transaction,
+                    delete,
+                    insert,
+                    using,
+                    pattern,
+                )?),
+                GraphUpdateOperation::Load { .. } => update_changes.loaded = true,
+                GraphUpdateOperation::Clear { graph, .. }
+                | GraphUpdateOperation::Drop { graph, .. } => update_changes
+                    .quads
+                    .extend(graph_target_quads(&transaction, graph)?),
+                GraphUpdateOperation::Create { .. } => {}
+            }
+            // Relative IRIs were resolved when parsing the update
+            transaction.update(Update::parse(
+                &spargebra::Update {
+                    base_iri: None,
+                    operations: vec![operation.clone()],
+                }
+                .to_string(),
+                None,
+            )?)?;
+        }
+        Ok(update_changes)
+    })
+}
+
+/// Applies SPARQL 1.1 updates to the store, per the SPARQL 1.1 Protocol, then re-indexes the
+/// resources they changed.
+///
+/// Updates are `POST`ed as `application/sparql-update` bodies or as form-encoded `update`
+/// parameters, with the admin token. Updates loading files rebuild the whole index in the
+/// background instead.
+pub fn handle_request(
+    request: &mut Request,
+    oxigraph_store: Store,
+    search_index_handle: &Arc<SearchIndexHandle>,
+    admin_token: Option<&str>,
+) -> Result<Response, HttpError> {
+    if request.method().as_ref() != "POST" {
+        return Err((
+            Status::METHOD_NOT_ALLOWED,
+            format!("{} is not supported by this server", request.method()),
+        ));
+    }
+
+    check_bearer_token(request, admin_token)?;
+
+    let content_type = content_type(request).ok_or_else(|| bad_request("No Content-Type given"))?;
+    let mut update_string = None;
+    let mut body = Vec::new();
+    if content_type == "application/sparql-update" {
+        let mut buffer = String::new();
+        request
+            .body_mut()
+            .take(MAX_SPARQL_BODY_SIZE)
+            .read_to_string(&mut buffer)
+            .map_err(bad_request)?;
+        update_string = Some(buffer);
+    } else if content_type == "application/x-www-form-urlencoded" {
+        request
+            .body_mut()
+            .take(MAX_SPARQL_BODY_SIZE)
+            .read_to_end(&mut body)
+            .map_err(bad_request)?;
+    } else {
+        return Err(unsupported_media_type(&content_type));
+    }
+
+    let mut using_graph_uris = Vec::new();
+    let mut using_named_graph_uris = Vec::new();
+    for encoded in [url_query(request), body.as_slice()] {
+        for (k, v) in form_urlencoded::parse(encoded) {
+            match k.as_ref() {
+                "update" => {
+                    if update_string.is_some() {
+                        return Err(bad_request("Multiple update parameters provided"));
+                    }
+                    update_string = Some(v.into_owned())
+                }
+                "using-graph-uri" => {
+                    using_graph_uris.push(NamedNode::new(v.into_owned()).map_err(bad_request)?)
+                }
+                "using-named-graph-uri" => using_named_graph_uris
+                    .push(NamedNode::new(v.into_owned()).map_err(bad_request)?),
+                _ => (),
+            }
+        }
+    }
+    let update_string =
+        update_string.ok_or_else(|| bad_request("You should set the 'update' parameter"))?;
+    let mut update =
+        spargebra::Update::parse(&update_string, Some(&base_url(request))).map_err(bad_request)?;
+
+    if !using_graph_uris.is_empty() || !using_named_graph_uris.is_empty() {
+        for operation in &mut update.operations {
+            if let GraphUpdateOperation::DeleteInsert { using, .. } = operation {
+                if using.is_some() {
+                    return Err(bad_request(
+                        "using-graph-uri and using-named-graph-uri cannot be combined with USING",
+                    ));
+                }
+                *using = Some(QueryDataset {
+                    default: using_graph_uris.clone(),
+                    named: Some(using_named_graph_uris.clone()),
+                });
+            }
+        }
+    }
+
+    let update_changes = apply_update(&oxigraph_store, &update).map_err(internal_server_error)?;
+
+    if update_changes.loaded {
+        search_index_handle.rebuild_after_change(oxigraph_store);
+    } else {
+        search_index_handle
+            .reindex(&oxigraph_store, &update_changes.quads)
+            .map_err(|err| {
+                internal_server_error(format!("update applied, but re-indexing failed: {}", err))
+            })?;
+    }
+
+    Ok(Response::builder(Status::NO_CONTENT).build())
+}
+
+#[cfg(test)]
+mod tests {
+    use oxhttp::model::{HeaderName, Method};
+    use oxigraph::model::Literal;
+
+    use super::*;
+    use crate::testing::{self, EX};
+
+    const PREFIXES: &str = "PREFIX ex: <http://example.com/>\n\
+                            PREFIX skos: <http://www.w3.org/2004/02/skos/core#>\n";
+
+    /// The quads an update changes in the vocabulary, sorted.
+    fn changed_quads(update: &str) -> Vec<String> {
+        let update = spargebra::Update::parse(&format!("{PREFIXES}{update}"), None).unwrap();
+        let update_changes = apply_update(&testing::store(), &update).unwrap();
+        assert!(!update_changes.loaded);
+        let mut changed_quads = update_changes
+            .quads
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        changed_quads.sort();
+        changed_quads
+    }
+
+    fn pref_label(concept: &str, label: &str, language: &str) -> String {
+        Quad::new(
+            NamedNode::new_unchecked(format!("{EX}{concept}")),
+            NamedNode::new_unchecked("http://www.w3.org/2004/02/skos/core#prefLabel"),
+            Literal::new_language_tagged_literal_unchecked(label, language),
+            GraphName::DefaultGraph,
+        )
+        .to_string()
+    }
+
+    #[test]
+    fn gathers_the_quads_of_data_operations() {
+        assert_eq!(
+            changed_quads(
+                "INSERT DATA { ex:cat skos:prefLabel \"Gato\"@es } ;\
+                 DELETE DATA { ex:dog skos:prefLabel \"Dog\"@en }"
+            ),
+            [
+                pref_label("cat", "Gato", "es"),
+                pref_label("dog", "Dog", "en")
+            ]
+        );
+    }
+
+    #[test]
+    fn gathers_the_instantiated_templates_of_delete_insert_operations() {
+        let changed_quads = changed_quads(
+            "DELETE { ?concept skos:prefLabel ?label } \
+             INSERT { ?concept skos:altLabel ?label } \
+             WHERE { ?concept skos:prefLabel ?label FILTER(?concept = ex:cat) }",
+        );
+        assert_eq!(changed_quads.len(), 4);
+        assert!(changed_quads.contains(&pref_label("cat", "Chat", "fr")));
+        assert!(changed_quads
+            .contains(&pref_label("cat", "Cat", "en").replace("prefLabel", "altLabel")));
+    }
+
+    #[test]
+    fn gathers_the_quads_of_cleared_graphs_after_previous_operations() {
+        let changed_quads = changed_quads(
+            "INSERT DATA { GRAPH ex:g { ex:cat skos:prefLabel \"Gato\"@es } } ; CLEAR GRAPH ex:g",
+        );
+        let inserted_quad = Quad::new(
+            NamedNode::new_unchecked(format!("{EX}cat")),
+            NamedNode::new_unchecked("http://www.w3.org/2004/02/skos/core#prefLabel"),
+            Literal::new_language_tagged_literal_unchecked("Gato", "es"),
+            NamedNode::new_unchecked(format!("{EX}g")),
+        )
+        .to_string();
+        // Once inserted, then cleared
+        assert_eq!(changed_quads, [inserted_quad.clone(), inserted_quad]);
+    }
+
+    fn post_update(
+        oxigraph_store: &Store,
+        search_index_handle: &Arc<SearchIndexHandle>,
+        content_type: &str,
+        body: &str,
+        authorization: Option<&str>,
+    ) -> Result<Response, HttpError> {
+        let mut request = testing::request_with_body(Method::POST, "/update", content_type, body);
+        if let Some(authorization) = authorization {
+            request
+                .append_header(HeaderName::AUTHORIZATION, authorization)
+                .unwrap();
+        }
+        handle_request(
+            &mut request,
+            oxigraph_store.clone(),
+            search_index_handle,
+            Some("secret"),
+        )
+    }
+
+    #[test]
+    fn applies_updates_and_reindexes_their_concepts() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        let cat_iri = format!("{EX}cat");
+        assert_eq!(testing::count_documents(&search_index_handle, &cat_iri), 4);
+
+        let update = format!("{PREFIXES}INSERT DATA {{ ex:cat skos:prefLabel \"Gato\"@es }}");
+        assert_eq!(
+            post_update(
+                &oxigraph_store,
+                &search_index_handle,
+                "application/sparql-update",
+                &update,
+                None
+            )
+            .unwrap_err()
+            .0,
+            Status::UNAUTHORIZED
+        );
+        assert_eq!(
+            post_update(
+                &oxigraph_store,
+                &search_index_handle,
+                "application/sparql-update",
+                &update,
+                Some("Bearer secret")
+            )
+            .unwrap()
+            .status(),
+            Status::NO_CONTENT
+        );
+        assert_eq!(testing::count_documents(&search_index_handle, &cat_iri), 5);
+
+        let update = format!("{PREFIXES}DELETE WHERE {{ ex:cat skos:prefLabel ?label }}");
+        assert_eq!(
+            post_update(
+                &oxigraph_store,
+                &search_index_handle,
+                "application/x-www-form-urlencoded",
+                &form_urlencoded::Serializer::new(String::new())
+                    .append_pair("update", &update)
+                    .finish(),
+                Some("Bearer secret")
+            )
+            .unwrap()
+            .status(),
+            Status::NO_CONTENT
+        );
+        // House cat and the notation A1 are left
+        assert_eq!(testing::count_documents(&search_index_handle, &cat_iri), 2);
+    }
+
+    #[test]
+    fn rejects_invalid_updates() {
+        let oxigraph_store = testing::store();
+        let search_index_handle = testing::search_index_handle(&oxigraph_store);
+        assert_eq!(
+            post_update(
+                &oxigraph_store,
+                &search_index_handle,
+                "application/sparql-update",
+                "INSERT DATA {",
+                Some("Bearer secret")
+            )
+            .unwrap_err()
+            .0,
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            post_update(
+                &oxigraph_store,
+                &search_index_handle,
+                "text/plain",
+                "CLEAR ALL",
+                Some("Bearer secret")
+            )
+            .unwrap_err()
+            .0,
+            Status::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            oxigraph_store.len().unwrap(),
+            testing::store().len().unwrap()
+        );
+    }
+}
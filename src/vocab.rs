@@ -0,0 +1,42 @@
+//! Terms the server uses to annotate its responses.
+//!
+//! All terms are in the `urn:kos-kit:` namespace, conventionally abbreviated `kos:`.
+
+pub mod kos {
+    use oxigraph::model::NamedNodeRef;
+
+    /// `kos:facets`: relation type of the `Link` from a search's results to the JSON document of
+    /// its facet counts.
+    pub const FACETS: NamedNodeRef<'_> = NamedNodeRef::new_unchecked("urn:kos-kit:facets");
+    /// `kos:inRequestedLanguage(?term)`: SPARQL function available to the index result query,
+    /// true unless `?term` is a literal whose language tag matches none of the languages
+    /// requested with the search's `lang` parameters.
+    pub const IN_REQUESTED_LANGUAGE: NamedNodeRef<'_> =
+        NamedNodeRef::new_unchecked("urn:kos-kit:inRequestedLanguage");
+    /// `kos:lang`: in a `kos:search` service pattern, a language tag the texts of the hits should
+    /// preferably be in, like the `lang` parameters of `/search`.
+    pub const LANG: NamedNodeRef<'_> = NamedNodeRef::new_unchecked("urn:kos-kit:lang");
+    /// `kos:limit`: in a `kos:search` service pattern, the maximum number of hits.
+    pub const LIMIT: NamedNodeRef<'_> = NamedNodeRef::new_unchecked("urn:kos-kit:limit");
+    /// `kos:matchedText`: the text of a search result that best matched the search, when the
+    /// search asked for highlighting.
+    pub const MATCHED_TEXT: NamedNodeRef<'_> =
+        NamedNodeRef::new_unchecked("urn:kos-kit:matchedText");
+    /// `kos:mode`: in a `kos:search` service pattern, how words are matched, `exact` or `fuzzy`.
+    pub const MODE: NamedNodeRef<'_> = NamedNodeRef::new_unchecked("urn:kos-kit:mode");
+    /// `kos:query`: in a `kos:search` service pattern, the text searched for, with the syntax of
+    /// the `query` parameter of `/search`.
+    pub const QUERY: NamedNodeRef<'_> = NamedNodeRef::new_unchecked("urn:kos-kit:query");
+    /// `kos:rank`: the 1-based position of a resource in the ranked results of a search,
+    /// counted from the first result of the first page.
+    pub const RANK: NamedNodeRef<'_> = NamedNodeRef::new_unchecked("urn:kos-kit:rank");
+    /// `kos:score`: the relevance score (an `xsd:float`) the search index assigned to a resource.
+    /// Scores are only comparable within the results of the same search.
+    pub const SCORE: NamedNodeRef<'_> = NamedNodeRef::new_unchecked("urn:kos-kit:score");
+    /// `kos:search`: SPARQL service searching the index from `/sparql` queries, e.g.
+    /// `SERVICE kos:search { ?iri kos:query "water" ; kos:score ?score }`.
+    pub const SEARCH: NamedNodeRef<'_> = NamedNodeRef::new_unchecked("urn:kos-kit:search");
+    /// `kos:snippet`: the matched text as an `rdf:HTML` literal, the words matching the search
+    /// wrapped in `<b>` elements.
+    pub const SNIPPET: NamedNodeRef<'_> = NamedNodeRef::new_unchecked("urn:kos-kit:snippet");
+}